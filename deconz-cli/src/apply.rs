@@ -0,0 +1,525 @@
+//! `deconz-cli apply`: converges the device to the network parameters in a TOML file. Every key
+//! is optional, parameters that aren't in the file are left alone:
+//!
+//! ```toml
+//! coordinator = true
+//! pan_id = "1a62"
+//! extended_pan_id = "00212effff000001"
+//! channels = [11, 15, 20, 25]
+//! trust_center_address = "00212effff000001"
+//! security_mode = "NoMasterButTrustCenterLinkKey"
+//! predefined_pan_id = true
+//! network_key = "01030507090b0d0f00020406080a0c0d"
+//! network_update_id = 0
+//! ```
+
+use std::path::Path;
+
+use deconz::{
+    protocol::{
+        device::ReadDeviceState,
+        network_parameters::{
+            self,
+            parameters::{
+                APSDesignatedCoordinator, ChannelMask, NetworkKey, PredefinedNetworkPanId,
+                SecurityMode, ZIGBEE_CHANNELS,
+            },
+        },
+        NetworkState,
+    },
+    DeconzClientHandle, LEAVE_NETWORK_TIMEOUT,
+};
+use serde::{de::Error, Deserialize, Deserializer};
+use tracing::{info, warn};
+
+use crate::util::hex_string::HexString;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkConfig {
+    coordinator: Option<bool>,
+    pan_id: Option<HexString<u16>>,
+    /// Written to the APS extended PAN ID, which the firmware forms the network with.
+    extended_pan_id: Option<HexString<u64>>,
+    #[serde(default, deserialize_with = "channels")]
+    channels: Option<Vec<u8>>,
+    trust_center_address: Option<HexString<u64>>,
+    #[serde(default, deserialize_with = "from_str")]
+    security_mode: Option<SecurityMode>,
+    predefined_pan_id: Option<bool>,
+    network_key: Option<HexString<[u8; 16]>>,
+    network_update_id: Option<u8>,
+}
+
+/// A parameter that differs from the configuration, holding the value to write.
+enum ParameterChange {
+    ApsDesignatedCoordinator(APSDesignatedCoordinator),
+    NetworkPanId(u16),
+    ApsExtendedPanId(u64),
+    ChannelMask(ChannelMask),
+    TrustCenterAddress(u64),
+    SecurityMode(SecurityMode),
+    PredefinedNetworkPanId(PredefinedNetworkPanId),
+    NetworkKey([u8; 16]),
+    NetworkUpdateId(u8),
+}
+
+impl ParameterChange {
+    /// Whether the firmware only picks the new value up when it forms the network.
+    fn needs_restart(&self) -> bool {
+        !matches!(self, ParameterChange::NetworkUpdateId(_))
+    }
+
+    async fn write(self, deconz: &mut DeconzClientHandle) -> Result<(), anyhow::Error> {
+        match self {
+            ParameterChange::ApsDesignatedCoordinator(value) => {
+                deconz
+                    .send_command(network_parameters::WriteAPSDesignatedCoordinator::new(
+                        value,
+                    ))
+                    .await?;
+            }
+            ParameterChange::NetworkPanId(value) => {
+                deconz
+                    .send_command(network_parameters::WriteNetworkPanId::new(value))
+                    .await?;
+            }
+            ParameterChange::ApsExtendedPanId(value) => {
+                deconz
+                    .send_command(network_parameters::WriteAPSExtendedPanId::new(value))
+                    .await?;
+            }
+            ParameterChange::ChannelMask(value) => {
+                deconz
+                    .send_command(network_parameters::WriteChannelMask::new(value))
+                    .await?;
+            }
+            ParameterChange::TrustCenterAddress(value) => {
+                deconz
+                    .send_command(network_parameters::WriteTrustCenterAddress::new(value))
+                    .await?;
+            }
+            ParameterChange::SecurityMode(value) => {
+                deconz
+                    .send_command(network_parameters::WriteSecurityMode::new(value))
+                    .await?;
+            }
+            ParameterChange::PredefinedNetworkPanId(value) => {
+                deconz
+                    .send_command(network_parameters::WritePredefinedNetworkPanId::new(value))
+                    .await?;
+            }
+            ParameterChange::NetworkKey(value) => {
+                deconz
+                    .send_command(network_parameters::WriteNetworkKey::new(NetworkKey::Set(
+                        value,
+                    )))
+                    .await?;
+            }
+            ParameterChange::NetworkUpdateId(value) => {
+                deconz
+                    .send_command(network_parameters::WriteNetworkUpdateId::new(value))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects the parameters that differ from the configuration, printing each of them.
+struct Diff(Vec<ParameterChange>);
+
+impl Diff {
+    fn compare<T: PartialEq + std::fmt::Debug>(
+        &mut self,
+        name: &str,
+        current: T,
+        desired: T,
+        change: impl FnOnce(T) -> ParameterChange,
+    ) {
+        if desired != current {
+            println!("- {}: {:?}", name, current);
+            println!("+ {}: {:?}", name, desired);
+            self.0.push(change(desired));
+        }
+    }
+}
+
+pub async fn apply(
+    deconz: &mut DeconzClientHandle,
+    file: &Path,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    let config: NetworkConfig = toml::from_str(&tokio::fs::read_to_string(file).await?)?;
+    if apply_config(deconz, config, dry_run).await? == 0 {
+        info!("device already matches {}", file.display());
+    }
+
+    Ok(())
+}
+
+/// Writes the parameters that differ from the configuration, unless it's a dry run. Returns how
+/// many of them differed.
+async fn apply_config(
+    deconz: &mut DeconzClientHandle,
+    config: NetworkConfig,
+    dry_run: bool,
+) -> Result<usize, anyhow::Error> {
+    let changes = diff(deconz, config).await?;
+    let count = changes.len();
+    if count == 0 {
+        return Ok(0);
+    }
+    if dry_run {
+        info!("dry run, {} parameter(s) left unchanged", count);
+        return Ok(count);
+    }
+
+    write_changes(deconz, changes).await?;
+    Ok(count)
+}
+
+/// Reads the parameters in the configuration from the device, printing those that differ.
+async fn diff(
+    deconz: &mut DeconzClientHandle,
+    config: NetworkConfig,
+) -> Result<Vec<ParameterChange>, anyhow::Error> {
+    let mut diff = Diff(Vec::new());
+
+    if let Some(coordinator) = config.coordinator {
+        let current = deconz
+            .send_command(network_parameters::ReadAPSDesignatedCoordinator::new())
+            .await?
+            .value;
+        let desired = match coordinator {
+            true => APSDesignatedCoordinator::Coordinator,
+            false => APSDesignatedCoordinator::Router,
+        };
+        diff.compare(
+            "aps_designated_coordinator",
+            current,
+            desired,
+            ParameterChange::ApsDesignatedCoordinator,
+        );
+    }
+    if let Some(pan_id) = config.pan_id {
+        let current = *deconz
+            .send_command(network_parameters::ReadNetworkPanId::new())
+            .await?
+            .value;
+        diff.compare("pan_id", Hex(current), Hex(*pan_id), |Hex(value)| {
+            ParameterChange::NetworkPanId(value)
+        });
+    }
+    if let Some(extended_pan_id) = config.extended_pan_id {
+        let current = *deconz
+            .send_command(network_parameters::ReadAPSExtendedPanId::new())
+            .await?
+            .value;
+        diff.compare(
+            "extended_pan_id",
+            Hex(current),
+            Hex(*extended_pan_id),
+            |Hex(value)| ParameterChange::ApsExtendedPanId(value),
+        );
+    }
+    if let Some(channels) = config.channels {
+        let current = deconz
+            .send_command(network_parameters::ReadChannelMask::new())
+            .await?
+            .value;
+        diff.compare(
+            "channels",
+            current.channels().collect::<Vec<_>>(),
+            ChannelMask::from_channels(channels).channels().collect(),
+            |channels| ParameterChange::ChannelMask(ChannelMask::from_channels(channels)),
+        );
+    }
+    if let Some(trust_center_address) = config.trust_center_address {
+        let current = *deconz
+            .send_command(network_parameters::ReadTrustCenterAddress::new())
+            .await?
+            .value;
+        diff.compare(
+            "trust_center_address",
+            Hex(current),
+            Hex(*trust_center_address),
+            |Hex(value)| ParameterChange::TrustCenterAddress(value),
+        );
+    }
+    if let Some(security_mode) = config.security_mode {
+        let current = deconz
+            .send_command(network_parameters::ReadSecurityMode::new())
+            .await?
+            .value;
+        diff.compare(
+            "security_mode",
+            current,
+            security_mode,
+            ParameterChange::SecurityMode,
+        );
+    }
+    if let Some(predefined_pan_id) = config.predefined_pan_id {
+        let current = deconz
+            .send_command(network_parameters::ReadPredefinedNetworkPanId::new())
+            .await?
+            .value;
+        let desired = match predefined_pan_id {
+            true => PredefinedNetworkPanId::Predefined,
+            false => PredefinedNetworkPanId::NotPredefined,
+        };
+        diff.compare(
+            "predefined_pan_id",
+            current,
+            desired,
+            ParameterChange::PredefinedNetworkPanId,
+        );
+    }
+    if let Some(network_key) = config.network_key {
+        let current = match deconz
+            .send_command(network_parameters::ReadNetworkKey::new())
+            .await?
+            .value
+        {
+            NetworkKey::Set(key) => Some(key),
+            NetworkKey::Unset => None,
+        };
+        // Keep the keys themselves out of the output.
+        if current != Some(*network_key) {
+            println!("~ network_key: <changed>");
+            diff.0.push(ParameterChange::NetworkKey(*network_key));
+        }
+    }
+    if let Some(network_update_id) = config.network_update_id {
+        let current = *deconz
+            .send_command(network_parameters::ReadNetworkUpdateId::new())
+            .await?
+            .value;
+        diff.compare(
+            "network_update_id",
+            current,
+            network_update_id,
+            ParameterChange::NetworkUpdateId,
+        );
+    }
+
+    Ok(diff.0)
+}
+
+/// Writes the changes, cycling the network offline and back if one of them needs it.
+async fn write_changes(
+    deconz: &mut DeconzClientHandle,
+    changes: Vec<ParameterChange>,
+) -> Result<(), anyhow::Error> {
+    let online = deconz
+        .send_command(ReadDeviceState::new())
+        .await?
+        .device_state
+        .network_state
+        == NetworkState::NetConnected;
+    let restart = online && changes.iter().any(ParameterChange::needs_restart);
+    if restart {
+        info!("taking the network offline");
+        if !deconz
+            .set_network_state(NetworkState::NetOffline, LEAVE_NETWORK_TIMEOUT)
+            .await?
+        {
+            anyhow::bail!("the device did not go offline");
+        }
+    }
+
+    let written = write_all(deconz, changes).await;
+
+    if restart {
+        // Even after a failed write, rather than leaving the network down.
+        info!("bringing the network back online");
+        let online = deconz
+            .set_network_state(NetworkState::NetConnected, LEAVE_NETWORK_TIMEOUT)
+            .await;
+        if let Err(e) = written {
+            if !matches!(online, Ok(true)) {
+                warn!("the network could not be brought back online either");
+            }
+            return Err(e);
+        }
+        if !online? {
+            anyhow::bail!("the device did not come back online");
+        }
+    }
+
+    written
+}
+
+async fn write_all(
+    deconz: &mut DeconzClientHandle,
+    changes: Vec<ParameterChange>,
+) -> Result<(), anyhow::Error> {
+    let count = changes.len();
+    for change in changes {
+        change.write(deconz).await?;
+    }
+    info!("wrote {} parameter(s)", count);
+    Ok(())
+}
+
+/// Prints integers in the diff the way they're written in the file.
+#[derive(PartialEq)]
+struct Hex<T>(T);
+
+impl<T: std::fmt::LowerHex> std::fmt::Debug for Hex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Rejects channels outside of the Zigbee ones, rather than leaving them out of the mask.
+fn channels<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let channels = Option::<Vec<u8>>::deserialize(deserializer)?;
+    if let Some(channels) = &channels {
+        if channels.is_empty() {
+            return Err(D::Error::custom("expected at least one channel"));
+        }
+        if let Some(channel) = channels
+            .iter()
+            .find(|channel| !ZIGBEE_CHANNELS.contains(channel))
+        {
+            return Err(D::Error::custom(format!(
+                "channel {} is not a Zigbee channel (11-26)",
+                channel
+            )));
+        }
+    }
+    Ok(channels)
+}
+
+#[cfg(test)]
+pub mod test {
+    use deconz::{
+        emulator::DeconzEmulator, protocol::network_parameters::parameters, DeconzClient,
+        DeconzClientConfig, DeconzEvent,
+    };
+
+    use super::*;
+
+    fn config(toml: &str) -> NetworkConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    pub fn test_parse_channels() {
+        assert_eq!(config("channels = [11, 26]").channels, Some(vec![11, 26]));
+        assert!(toml::from_str::<NetworkConfig>("channels = [5]").is_err());
+        assert!(toml::from_str::<NetworkConfig>("channels = [11, 27]").is_err());
+        assert!(toml::from_str::<NetworkConfig>("channels = []").is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_apply() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        let desired = r#"
+            coordinator = true
+            pan_id = "1234"
+            channels = [15, 20]
+            predefined_pan_id = true
+        "#;
+
+        // A dry run only reports the differences.
+        assert_eq!(
+            apply_config(&mut handle, config(desired), true)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            *emulator.parameter::<parameters::NetworkPanId>().unwrap(),
+            0x1A62
+        );
+
+        assert_eq!(
+            apply_config(&mut handle, config(desired), false)
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            *emulator.parameter::<parameters::NetworkPanId>().unwrap(),
+            0x1234
+        );
+        assert_eq!(
+            emulator
+                .parameter::<parameters::ChannelMask>()
+                .unwrap()
+                .channels()
+                .collect::<Vec<_>>(),
+            [15, 20]
+        );
+
+        // Once the device matches, there's nothing left to write.
+        assert_eq!(
+            apply_config(&mut handle, config(desired), false)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_apply_restarts_network() {
+        let emulator = DeconzEmulator::new();
+        emulator.set_network_state(NetworkState::NetConnected);
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        let mut events = handle.subscribe_events().await.unwrap();
+        let mut network_states = move || {
+            let mut network_states = Vec::new();
+            while let Ok(event) = events.try_recv() {
+                if let DeconzEvent::DeviceStateChanged(device_state) = event {
+                    network_states.push(device_state.network_state);
+                }
+            }
+            network_states
+        };
+
+        // The network update id is picked up without forming the network again.
+        apply_config(&mut handle, config("network_update_id = 1"), false)
+            .await
+            .unwrap();
+        assert!(!network_states().contains(&NetworkState::NetOffline));
+
+        apply_config(&mut handle, config(r#"pan_id = "1234""#), false)
+            .await
+            .unwrap();
+        assert!(network_states().contains(&NetworkState::NetOffline));
+        assert_eq!(emulator.network_state(), NetworkState::NetConnected);
+        assert_eq!(
+            *emulator.parameter::<parameters::NetworkPanId>().unwrap(),
+            0x1234
+        );
+
+        // A failed write still brings the network back up.
+        emulator.set_read_only::<parameters::NetworkPanId>();
+        assert!(
+            apply_config(&mut handle, config(r#"pan_id = "5678""#), false)
+                .await
+                .is_err()
+        );
+        assert_eq!(emulator.network_state(), NetworkState::NetConnected);
+    }
+}
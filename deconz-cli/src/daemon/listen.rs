@@ -1,53 +1,56 @@
 use std::{collections::HashMap, pin::Pin};
 
-use deconz::{protocol::aps::IEEEAddress, DeconzClientHandle};
+use deconz::{protocol::aps::IEEEAddress, ApsFilter, DeconzClientHandle};
 use futures::Stream;
-use tokio::sync::oneshot;
 use tonic::Status;
 use tracing::info;
 
 use crate::ZdoDevice;
 
-use super::proto::{self, ApsStreamResponse};
+use super::proto;
 
 pub struct DaemonTask {
-    handle: DeconzClientHandle,
+    _handle: DeconzClientHandle,
 }
 
 type StreamResponse = Pin<Box<dyn Stream<Item = Result<proto::ApsStreamResponse, Status>> + Send>>;
 
 impl DaemonTask {
     pub async fn run(self, deconz: &mut DeconzClientHandle) -> Result<(), anyhow::Error> {
-        let mut sub = deconz.subscribe_aps_data_indication().await?;
+        let mut sub = deconz
+            .subscribe_aps(ApsFilter {
+                endpoint: Some(0),
+                cluster_id: Some(0x0013),
+                ..Default::default()
+            })
+            .await?;
 
-        let mut devices = HashMap::<IEEEAddress, ZdoDevice>::new();
+        let devices = HashMap::<IEEEAddress, ZdoDevice>::new();
 
-        loop {
-            let data = sub.recv().await?;
+        while let Some(data) = sub.recv().await {
             dbg!(&data);
+            info!("received device state");
 
-            if data.destination_endpoint == 0 && data.cluster_id == 0x0013 {
-                info!("received device state");
+            let _ieee = data.source_address.unwrap_ieee_address();
 
-                let ieee = data.source_address.unwrap_ieee_address();
+            let _payload = data.data();
+            // let _seq = payload.get_u8();
+            // let nwk_addr = payload.get_u16_le();
+            // let ieee_addr = payload.get_u64_le();
 
-                let mut payload = data.data();
-                // let _seq = payload.get_u8();
-                // let nwk_addr = payload.get_u16_le();
-                // let ieee_addr = payload.get_u64_le();
-
-                // if let std::collections::hash_map::Entry::Vacant(e) = devices.entry(ieee) {
-                //     e.insert(ZdoDevice {
-                //         ieee: ieee_addr,
-                //         address: nwk_addr,
-                //     });
-                // } else {
-                //     info!("Received data from device we already know about");
-                // }
-            }
+            // if let std::collections::hash_map::Entry::Vacant(e) = devices.entry(ieee) {
+            //     e.insert(ZdoDevice {
+            //         ieee: ieee_addr,
+            //         address: nwk_addr,
+            //     });
+            // } else {
+            //     info!("Received data from device we already know about");
+            // }
 
             dbg!(&devices);
         }
+
+        Ok(())
     }
 }
 
@@ -59,7 +62,7 @@ impl proto::daemon_server::Daemon for DaemonTask {
         &self,
         request: tonic::Request<tonic::Streaming<proto::ApsStreamRequest>>,
     ) -> Result<tonic::Response<Self::ApsStreamStream>, tonic::Status> {
-        let stream = request.into_inner();
+        let _stream = request.into_inner();
 
         todo!()
     }
@@ -1,20 +1,32 @@
+mod apply;
 pub mod daemon;
 mod net_params;
 pub mod util;
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use bytes::Buf;
 use deconz::{
+    backup::NetworkBackup,
+    capture::Capture,
+    firmware::{self, FlashProgress, GcfFile},
+    install_code::InstallCode,
     protocol::{
         aps::{IEEEAddress, NetworkAddress},
         device::{ChangeNetworkState, ReadDeviceState},
+        network_parameters::{parameters::ChannelMask, WriteLinkKey},
         NetworkState,
     },
-    DeconzClient, DeconzClientConfig,
+    ApsFilter, BaudRate, DeconzClient, DeconzClientConfig, DeconzClientHandle, DeconzTransport,
+    FormNetworkOptions, ShutdownOptions, WatchdogEvent, WatchdogKeepAlive,
 };
 use structopt::StructOpt;
-use tracing::info;
+use tracing::{info, warn};
+use util::hex_string::HexString;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -22,26 +34,91 @@ use tracing::info;
     about = "Commands to interface with a deCONZ (RaspBee/ConBee) Serial Device"
 )]
 struct Opt {
-    /// Device path where the the deCONZ compatible device is available at.
+    /// Device path where the the deCONZ compatible device is available at,
+    /// or tcp://host:port for a device forwarded over the network (e.g. by ser2net).
     #[structopt(short, long, default_value = "/dev/ttyUSB0")]
-    device: PathBuf,
+    device: DeconzTransport,
+    /// Serial baud rate, or 'auto' to detect it (ConBee II and RaspBee II use 115200).
+    #[structopt(short, long, default_value = "38400")]
+    baud_rate: BaudRate,
+    /// Keep the ConBee II / RaspBee II firmware watchdog alive, with this TTL in seconds (at least 2).
+    #[structopt(long)]
+    watchdog_ttl: Option<u64>,
+    /// Record the traffic with the device to a pcapng file, to open in Wireshark.
+    #[structopt(long, parse(from_os_str))]
+    capture: Option<PathBuf>,
     #[structopt(subcommand)]
     command: OptCommand,
 }
 
 #[derive(Debug, StructOpt)]
 enum OptCommand {
+    #[structopt(flatten)]
+    Client(ClientCommand),
+    /// Flash a firmware image (.gcf) onto a ConBee II or RaspBee II.
+    Flash {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+/// The commands that go through the client task. Flashing talks to the bootloader instead.
+#[derive(Debug, StructOpt)]
+enum ClientCommand {
     ReadParameters,
     WriteParameter {
         #[structopt(subcommand)]
         param: net_params::WritableParameter,
     },
+    /// List or configure the coordinator's own application endpoints.
+    Endpoints {
+        #[structopt(subcommand)]
+        command: net_params::EndpointCommand,
+    },
+    /// Provision the trust center link key derived from a device's install code, so that the
+    /// device can join with it.
+    InstallCode {
+        ieee: HexString<u64>,
+        /// The install code including its CRC, in hex. Spaces and dashes are ignored.
+        code: String,
+    },
+    /// Back up the network in the Open Coordinator Backup JSON format, as used by zigpy and
+    /// zigbee2mqtt.
+    Backup {
+        /// Where to write the backup to, instead of stdout.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// A device to include the link key of, if it has one. Can be given several times.
+        #[structopt(long = "device")]
+        devices: Vec<HexString<u64>>,
+    },
+    /// Restore a network from an Open Coordinator Backup JSON file. The network is taken
+    /// offline while the parameters are written.
+    Restore {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Form a new network with a random PAN ID, extended PAN ID and network key.
+    FormNetwork {
+        /// Comma separated channels to pick from, instead of 11, 15, 20 and 25.
+        #[structopt(long, use_delimiter = true)]
+        channels: Vec<u8>,
+    },
+    /// Bring the network parameters in line with a TOML file, writing only what differs.
+    Apply {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Only print the differences.
+        #[structopt(long)]
+        dry_run: bool,
+    },
     SetOffline,
     SetOnline,
     DeviceState,
     Daemon,
 }
 
+#[allow(dead_code)] // only inspected through its Debug output for now
 #[derive(Debug, Clone)]
 struct ZdoDevice {
     ieee: IEEEAddress,
@@ -56,64 +133,159 @@ async fn main() -> Result<(), anyhow::Error> {
 
     info!("connecting to device {:?}", opt.device);
 
+    let capture = opt.capture.as_deref().map(Capture::create).transpose()?;
+
     let deconz_config = DeconzClientConfig {
-        device_path: opt.device,
+        baud_rate: opt.baud_rate,
+        watchdog: opt
+            .watchdog_ttl
+            .map(|ttl| WatchdogKeepAlive::new(Duration::from_secs(ttl)))
+            .transpose()?,
+        capture: capture.clone(),
+        ..DeconzClientConfig::new(opt.device)
+    };
+
+    let command = match opt.command {
+        OptCommand::Client(command) => command,
+        // Flashing talks to the bootloader directly, without a client task in the way.
+        OptCommand::Flash { file } => return flash(&deconz_config, &file).await,
     };
 
-    let (watchdog, mut deconz) = DeconzClient::new(deconz_config).start();
+    let (task, mut deconz) = DeconzClient::new(deconz_config).start();
 
-    match opt.command {
-        OptCommand::Daemon => {
-            let mut sub = deconz.subscribe_aps_data_indication().await?;
+    let result = run_command(command, &mut deconz).await;
 
-            // todo: do this better
-            tokio::spawn(async { watchdog.await.unwrap().unwrap() });
+    // Let the task finish what it's doing with the device before exiting.
+    deconz.shutdown(ShutdownOptions::default()).await;
+    let task_result = task.await;
+
+    // The capture is written from a thread of its own, which won't hold up the exit.
+    if let Some(capture) = &capture {
+        capture.flush();
+    }
+    task_result??;
+
+    result
+}
+
+async fn run_command(
+    command: ClientCommand,
+    deconz: &mut DeconzClientHandle,
+) -> Result<(), anyhow::Error> {
+    match command {
+        ClientCommand::Daemon => {
+            // ZDO device announcements.
+            let mut sub = deconz
+                .subscribe_aps(ApsFilter {
+                    endpoint: Some(0),
+                    cluster_id: Some(0x0013),
+                    ..Default::default()
+                })
+                .await?;
+            let mut watchdog_events = deconz.subscribe_watchdog_events().await?;
 
             let mut devices = HashMap::<IEEEAddress, ZdoDevice>::new();
 
             loop {
-                let data = sub.recv().await?;
+                let data = tokio::select! {
+                    data = sub.recv() => match data {
+                        Some(data) => data,
+                        None => break,
+                    },
+                    Ok(WatchdogEvent::RefreshFailed(e)) = watchdog_events.recv() => {
+                        warn!("watchdog refresh failed, the device may reset its radio: {}", e);
+                        continue;
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        info!("interrupted, shutting down");
+                        break;
+                    }
+                };
                 dbg!(&data);
 
-                if data.destination_endpoint == 0 && data.cluster_id == 0x0013 {
-                    info!("received device state");
+                info!("received device state");
 
-                    let ieee = data.source_address.unwrap_ieee_address();
+                let ieee = data.source_address.unwrap_ieee_address();
 
-                    let mut payload = data.data();
-                    let _seq = payload.get_u8();
-                    let nwk_addr = payload.get_u16_le();
-                    let ieee_addr = payload.get_u64_le();
+                let mut payload = data.data();
+                let _seq = payload.get_u8();
+                let nwk_addr = payload.get_u16_le();
+                let ieee_addr = payload.get_u64_le();
 
-                    if let std::collections::hash_map::Entry::Vacant(e) = devices.entry(ieee) {
-                        e.insert(ZdoDevice {
-                            ieee: ieee_addr,
-                            address: nwk_addr,
-                        });
-                    } else {
-                        info!("Received data from device we already know about");
-                    }
+                if let std::collections::hash_map::Entry::Vacant(e) = devices.entry(ieee) {
+                    e.insert(ZdoDevice {
+                        ieee: ieee_addr,
+                        address: nwk_addr,
+                    });
+                } else {
+                    info!("Received data from device we already know about");
                 }
 
                 dbg!(&devices);
             }
         }
-        OptCommand::WriteParameter { param } => {
-            param.write(&mut deconz).await?;
+        ClientCommand::WriteParameter { param } => {
+            param.write(deconz).await?;
+        }
+        ClientCommand::ReadParameters => {
+            net_params::read_all_parameters(deconz).await?;
+        }
+        ClientCommand::Endpoints { command } => {
+            command.run(deconz).await?;
+        }
+        ClientCommand::InstallCode { ieee, code } => {
+            let code: String = code
+                .trim_start_matches("0x")
+                .chars()
+                .filter(|c| !c.is_whitespace() && *c != '-')
+                .collect();
+            let install_code = InstallCode::new(&hex::decode(code)?)?;
+            deconz
+                .send_command(WriteLinkKey::new(install_code.link_key_for(*ieee)))
+                .await?;
+            info!("provisioned link key for {:#018x}", *ieee);
+        }
+        ClientCommand::Backup { output, devices } => {
+            let devices: Vec<IEEEAddress> = devices.iter().map(|device| **device).collect();
+            let json = NetworkBackup::read(deconz, &devices).await?.to_json()?;
+            match output {
+                Some(output) => tokio::fs::write(output, json).await?,
+                None => println!("{}", json),
+            }
+        }
+        ClientCommand::Restore { file } => {
+            let backup = NetworkBackup::from_json(&tokio::fs::read_to_string(file).await?)?;
+            backup.restore(deconz).await?;
+            info!(
+                "restored network {:#06x} on channel {}",
+                backup.pan_id, backup.channel
+            );
+        }
+        ClientCommand::FormNetwork { channels } => {
+            let mut options = FormNetworkOptions::default();
+            if !channels.is_empty() {
+                options.channel_mask = ChannelMask::from_channels(channels);
+            }
+            let network = deconz.form_network(options).await?;
+            println!("PAN ID: {:#06x}", network.pan_id);
+            println!("Extended PAN ID: {:#018x}", network.extended_pan_id);
+            println!("Channel: {}", network.channel);
+            println!("Network Key: {}", hex::encode(network.network_key));
+            println!("Security Mode: {:?}", network.security_mode);
         }
-        OptCommand::ReadParameters => {
-            net_params::read_all_parameters(&mut deconz).await?;
+        ClientCommand::Apply { file, dry_run } => {
+            apply::apply(deconz, &file, dry_run).await?;
         }
-        OptCommand::DeviceState => {
+        ClientCommand::DeviceState => {
             let state = deconz.send_command(ReadDeviceState::new()).await?;
             println!("{:?}", state);
         }
-        OptCommand::SetOffline => {
+        ClientCommand::SetOffline => {
             deconz
                 .send_command(ChangeNetworkState::new(NetworkState::NetOffline))
                 .await?;
         }
-        OptCommand::SetOnline => {
+        ClientCommand::SetOnline => {
             deconz
                 .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
                 .await?;
@@ -123,6 +295,29 @@ async fn main() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+async fn flash(config: &DeconzClientConfig, file: &Path) -> Result<(), anyhow::Error> {
+    let image = GcfFile::parse(tokio::fs::read(file).await?)?;
+    info!(
+        "flashing {} byte image for {:?}",
+        image.data().len(),
+        image.platform()
+    );
+
+    let mut last_percent = None;
+    firmware::flash(config, &image, |progress| match progress {
+        FlashProgress::Uploading { written, total } => {
+            let percent = written * 100 / total.max(1);
+            if last_percent.replace(percent) != Some(percent) {
+                info!("uploading: {}%", percent);
+            }
+        }
+        progress => info!("{:?}", progress),
+    })
+    .await?;
+
+    Ok(())
+}
+
 fn setup_tracing() {
     tracing_subscriber::fmt().init();
 }
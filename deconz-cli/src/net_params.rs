@@ -1,8 +1,14 @@
 use std::time::Duration;
 
 use deconz::{
-    protocol::{device::ReadFirmwareVersion, network_parameters},
-    DeconzClientHandle,
+    protocol::{
+        device::ReadFirmwareVersion,
+        network_parameters::{
+            self,
+            parameters::{Endpoint, SimpleDescriptor},
+        },
+    },
+    DeconzClientHandle, HandleError,
 };
 use structopt::StructOpt;
 
@@ -55,7 +61,9 @@ impl WritableParameter {
     pub async fn write(self, deconz: &mut DeconzClientHandle) -> Result<(), anyhow::Error> {
         match self {
             WritableParameter::NetworkAddress { value } => {
-                deconz.send_command(network_parameters::WriteNetworkAddress::new(*value)).await?;
+                deconz
+                    .send_command(network_parameters::WriteNetworkAddress::new(*value))
+                    .await?;
             }
             WritableParameter::NetworkPanId { value } => {
                 deconz
@@ -129,6 +137,83 @@ impl WritableParameter {
     }
 }
 
+#[derive(Debug, StructOpt)]
+pub(crate) enum EndpointCommand {
+    /// List the coordinator's configured endpoints.
+    List,
+    /// Configure the endpoint in the given slot.
+    Set {
+        index: u8,
+        endpoint: u8,
+        profile_id: HexString<u16>,
+        device_id: HexString<u16>,
+        #[structopt(long, default_value = "1")]
+        device_version: u8,
+        /// Comma separated server clusters, like 0x0000,0x0006.
+        #[structopt(long = "in", use_delimiter = true)]
+        in_clusters: Vec<HexString<u16>>,
+        /// Comma separated client clusters.
+        #[structopt(long = "out", use_delimiter = true)]
+        out_clusters: Vec<HexString<u16>>,
+    },
+}
+
+impl EndpointCommand {
+    pub async fn run(self, deconz: &mut DeconzClientHandle) -> Result<(), anyhow::Error> {
+        match self {
+            EndpointCommand::List => {
+                // The device answers with an error status past the last configured slot.
+                for index in 0..=u8::MAX {
+                    match deconz
+                        .send_command(network_parameters::ReadEndpoint::at_index(index))
+                        .await
+                    {
+                        Ok(response) => println!("{}", response.value),
+                        Err(HandleError::Status(_)) => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            EndpointCommand::Set {
+                index,
+                endpoint,
+                profile_id,
+                device_id,
+                device_version,
+                in_clusters,
+                out_clusters,
+            } => {
+                deconz
+                    .send_command(network_parameters::WriteEndpoint::new(Endpoint {
+                        index,
+                        descriptor: SimpleDescriptor {
+                            endpoint,
+                            profile_id: *profile_id,
+                            device_id: *device_id,
+                            device_version,
+                            in_clusters: clusters(&in_clusters)?,
+                            out_clusters: clusters(&out_clusters)?,
+                        },
+                    }))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Unwraps the clusters given for an endpoint, refusing more than a descriptor can hold.
+fn clusters(clusters: &[HexString<u16>]) -> Result<Vec<u16>, anyhow::Error> {
+    anyhow::ensure!(
+        clusters.len() <= SimpleDescriptor::MAX_CLUSTERS,
+        "an endpoint can list at most {} clusters each way, got {}",
+        SimpleDescriptor::MAX_CLUSTERS,
+        clusters.len()
+    );
+    Ok(clusters.iter().map(|cluster| **cluster).collect())
+}
+
 pub async fn read_all_parameters(deconz: &mut DeconzClientHandle) -> Result<(), anyhow::Error> {
     let firmware_version_res = deconz.send_command(ReadFirmwareVersion::new()).await?;
     println!(
@@ -1,6 +1,7 @@
-use std::str::FromStr;
+use std::{fmt::Display, str::FromStr};
 
 use hex::{FromHex, FromHexError};
+use serde::{de::Error, Deserialize, Deserializer};
 
 #[derive(Debug)]
 pub(crate) struct HexString<T>(T);
@@ -37,3 +38,16 @@ impl FromStr for HexString<[u8; 16]> {
         Ok(Self(<[u8; 16]>::from_hex(s)?))
     }
 }
+
+/// Hex strings are written as TOML strings, as TOML integers can't hold every u64.
+impl<'de, T> Deserialize<'de> for HexString<T>
+where
+    Self: FromStr,
+    <Self as FromStr>::Err: Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,585 @@
+//! Coordinator backups in the [Open Coordinator Backup] format, shared with zigpy and
+//! zigbee2mqtt, so that a network can be moved between sticks (and stacks).
+//!
+//! [Open Coordinator Backup]: https://github.com/zigpy/open-coordinator-backup
+
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+    iter,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::debug;
+
+use crate::{
+    client::network_state::LEAVE_NETWORK_TIMEOUT,
+    protocol::{
+        aps::IEEEAddress,
+        device::ChangeNetworkState,
+        network_parameters::{
+            self,
+            parameters::{
+                APSDesignatedCoordinator, ChannelMask, LinkKey, NetworkKey, PredefinedNetworkPanId,
+                SecurityMode, ZIGBEE_CHANNELS,
+            },
+        },
+        NetworkState,
+    },
+    DeconzClientHandle, HandleError,
+};
+
+const BACKUP_FORMAT: &str = "zigpy/open-coordinator-backup";
+const BACKUP_VERSION: u32 = 1;
+/// Zigbee PRO always uses AES-128-CCM with a 32 bit MIC.
+const SECURITY_LEVEL: u8 = 5;
+
+/// How far the network frame counter is moved ahead on restore. The backup is likely older than
+/// the last frame the network saw, and devices drop frames with a counter they have seen before.
+pub const FRAME_COUNTER_SAFETY_MARGIN: u32 = 2500;
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("failed to talk to the device: {0}")]
+    Handle(#[from] HandleError),
+    #[error("the device has no network key")]
+    NoNetworkKey,
+    #[error("the device did not go offline")]
+    NotOffline,
+    #[error("invalid backup: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unsupported backup format {format:?} version {version}")]
+    UnsupportedFormat { format: String, version: u32 },
+    #[error("invalid value for {field} in backup: {value:?}")]
+    InvalidField { field: &'static str, value: String },
+}
+
+/// Everything needed to bring up the same network on another coordinator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkBackup {
+    pub coordinator_ieee: IEEEAddress,
+    pub pan_id: u16,
+    pub extended_pan_id: u64,
+    /// Usually 0, meaning the network's extended PAN ID is used.
+    pub aps_extended_pan_id: u64,
+    pub channel: u8,
+    pub channel_mask: u32,
+    pub network_key: [u8; 16],
+    pub network_update_id: u8,
+    /// The network frame counter at the time of the backup.
+    pub frame_counter: u32,
+    pub trust_center_address: IEEEAddress,
+    pub security_mode: SecurityMode,
+    /// The unique trust center link keys, of the devices that have one.
+    pub link_keys: Vec<LinkKey>,
+}
+
+impl NetworkBackup {
+    /// Reads the network from the device. Link keys are looked up for the given devices, devices
+    /// using the default link key are left out.
+    pub async fn read(
+        deconz: &mut DeconzClientHandle,
+        devices: &[IEEEAddress],
+    ) -> Result<Self, BackupError> {
+        let network_key = match deconz
+            .send_command(network_parameters::ReadNetworkKey::new())
+            .await?
+            .value
+        {
+            NetworkKey::Set(key) => key,
+            NetworkKey::Unset => return Err(BackupError::NoNetworkKey),
+        };
+
+        let mut link_keys = Vec::new();
+        for &ieee_address in devices {
+            match deconz
+                .send_command(network_parameters::ReadLinkKey::at_index(ieee_address))
+                .await
+            {
+                Ok(response) => link_keys.push(response.value),
+                Err(HandleError::Status(status)) => {
+                    debug!("no link key for {:#018x}: {:?}", ieee_address, status)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(Self {
+            coordinator_ieee: *deconz
+                .send_command(network_parameters::ReadMacAddress::new())
+                .await?
+                .value,
+            pan_id: *deconz
+                .send_command(network_parameters::ReadNetworkPanId::new())
+                .await?
+                .value,
+            extended_pan_id: *deconz
+                .send_command(network_parameters::ReadNetworkExtendedPanId::new())
+                .await?
+                .value,
+            aps_extended_pan_id: *deconz
+                .send_command(network_parameters::ReadAPSExtendedPanId::new())
+                .await?
+                .value,
+            channel: *deconz
+                .send_command(network_parameters::ReadCurrentChannel::new())
+                .await?
+                .value,
+            channel_mask: *deconz
+                .send_command(network_parameters::ReadChannelMask::new())
+                .await?
+                .value,
+            network_key,
+            network_update_id: *deconz
+                .send_command(network_parameters::ReadNetworkUpdateId::new())
+                .await?
+                .value,
+            frame_counter: *deconz
+                .send_command(network_parameters::ReadNetworkFrameCounter::new())
+                .await?
+                .value,
+            trust_center_address: *deconz
+                .send_command(network_parameters::ReadTrustCenterAddress::new())
+                .await?
+                .value,
+            security_mode: deconz
+                .send_command(network_parameters::ReadSecurityMode::new())
+                .await?
+                .value,
+            link_keys,
+        })
+    }
+
+    /// Writes the network to the device and brings it back up. The network is taken offline
+    /// first, as the firmware only picks up the parameters when it (re)forms the network.
+    pub async fn restore(&self, deconz: &mut DeconzClientHandle) -> Result<(), BackupError> {
+        if !deconz
+            .set_network_state(NetworkState::NetOffline, LEAVE_NETWORK_TIMEOUT)
+            .await?
+        {
+            return Err(BackupError::NotOffline);
+        }
+
+        deconz
+            .send_command(network_parameters::WriteMacAddress::new(
+                self.coordinator_ieee,
+            ))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteNetworkAddress::new(0x0000))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteAPSDesignatedCoordinator::new(
+                APSDesignatedCoordinator::Coordinator,
+            ))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteNetworkPanId::new(self.pan_id))
+            .await?;
+        deconz
+            .send_command(network_parameters::WritePredefinedNetworkPanId::new(
+                PredefinedNetworkPanId::Predefined,
+            ))
+            .await?;
+        // The network extended PAN ID can't be written, the firmware forms the network with the
+        // APS one instead.
+        let aps_extended_pan_id = match self.aps_extended_pan_id {
+            0 => self.extended_pan_id,
+            aps_extended_pan_id => aps_extended_pan_id,
+        };
+        deconz
+            .send_command(network_parameters::WriteAPSExtendedPanId::new(
+                aps_extended_pan_id,
+            ))
+            .await?;
+        // Only allow the channel the network was on, so that it doesn't move.
+        deconz
+            .send_command(network_parameters::WriteChannelMask::new(
+                ChannelMask::from_channels([self.channel]),
+            ))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteTrustCenterAddress::new(
+                self.trust_center_address,
+            ))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteSecurityMode::new(
+                self.security_mode,
+            ))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteNetworkKey::new(NetworkKey::Set(
+                self.network_key,
+            )))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteNetworkUpdateId::new(
+                self.network_update_id,
+            ))
+            .await?;
+        deconz
+            .send_command(network_parameters::WriteNetworkFrameCounter::new(
+                self.frame_counter
+                    .saturating_add(FRAME_COUNTER_SAFETY_MARGIN),
+            ))
+            .await?;
+        for link_key in &self.link_keys {
+            deconz
+                .send_command(network_parameters::WriteLinkKey::new(link_key.clone()))
+                .await?;
+        }
+
+        deconz
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await?;
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String, BackupError> {
+        Ok(serde_json::to_string_pretty(&OpenCoordinatorBackup::from(
+            self,
+        ))?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, BackupError> {
+        serde_json::from_str::<OpenCoordinatorBackup>(json)?.try_into()
+    }
+}
+
+/// The JSON document, see the format's schema for what each field means.
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenCoordinatorBackup {
+    metadata: Metadata,
+    #[serde(default)]
+    stack_specific: BTreeMap<String, serde_json::Value>,
+    coordinator_ieee: String,
+    pan_id: String,
+    extended_pan_id: String,
+    nwk_update_id: u8,
+    security_level: u8,
+    channel: u8,
+    channel_mask: Vec<u8>,
+    network_key: BackupNetworkKey,
+    devices: Vec<BackupDevice>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    source: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupNetworkKey {
+    key: String,
+    sequence_number: u8,
+    frame_counter: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupDevice {
+    nwk_address: Option<String>,
+    ieee_address: String,
+    #[serde(default)]
+    is_child: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link_key: Option<BackupLinkKey>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupLinkKey {
+    key: String,
+    tx_counter: u32,
+    rx_counter: u32,
+}
+
+/// The parameters only the deCONZ firmware has, kept under `stack_specific.deconz`.
+#[derive(Debug, Serialize, Deserialize)]
+struct DeconzStackSpecific {
+    aps_extended_pan_id: String,
+    trust_center_address: String,
+    security_mode: u8,
+}
+
+impl From<&NetworkBackup> for OpenCoordinatorBackup {
+    fn from(backup: &NetworkBackup) -> Self {
+        let deconz = DeconzStackSpecific {
+            aps_extended_pan_id: format!("{:016x}", backup.aps_extended_pan_id),
+            trust_center_address: format!("{:016x}", backup.trust_center_address),
+            security_mode: backup.security_mode as u8,
+        };
+
+        Self {
+            metadata: Metadata {
+                format: BACKUP_FORMAT.to_string(),
+                version: BACKUP_VERSION,
+                source: concat!("deconz-rs@", env!("CARGO_PKG_VERSION")).to_string(),
+            },
+            stack_specific: iter::once((
+                "deconz".to_string(),
+                serde_json::to_value(deconz).expect("stack specific values serialize"),
+            ))
+            .collect(),
+            coordinator_ieee: format!("{:016x}", backup.coordinator_ieee),
+            pan_id: format!("{:04x}", backup.pan_id),
+            extended_pan_id: format!("{:016x}", backup.extended_pan_id),
+            nwk_update_id: backup.network_update_id,
+            security_level: SECURITY_LEVEL,
+            channel: backup.channel,
+            channel_mask: ChannelMask::from(backup.channel_mask).channels().collect(),
+            network_key: BackupNetworkKey {
+                key: encode_key(&backup.network_key),
+                sequence_number: 0,
+                frame_counter: backup.frame_counter,
+            },
+            devices: backup
+                .link_keys
+                .iter()
+                .map(|link_key| BackupDevice {
+                    nwk_address: None,
+                    ieee_address: format!("{:016x}", link_key.ieee_address),
+                    is_child: false,
+                    link_key: Some(BackupLinkKey {
+                        key: encode_key(&link_key.key),
+                        tx_counter: 0,
+                        rx_counter: 0,
+                    }),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<OpenCoordinatorBackup> for NetworkBackup {
+    type Error = BackupError;
+
+    fn try_from(backup: OpenCoordinatorBackup) -> Result<Self, Self::Error> {
+        if backup.metadata.format != BACKUP_FORMAT || backup.metadata.version != BACKUP_VERSION {
+            return Err(BackupError::UnsupportedFormat {
+                format: backup.metadata.format,
+                version: backup.metadata.version,
+            });
+        }
+
+        if !ZIGBEE_CHANNELS.contains(&backup.channel) {
+            return Err(BackupError::InvalidField {
+                field: "channel",
+                value: backup.channel.to_string(),
+            });
+        }
+
+        let coordinator_ieee = parse_hex_u64("coordinator_ieee", &backup.coordinator_ieee)?;
+        let extended_pan_id = parse_hex_u64("extended_pan_id", &backup.extended_pan_id)?;
+
+        // Backups made by other stacks don't have these, so fall back to what deCONZ itself
+        // uses for a network it formed.
+        let deconz = backup
+            .stack_specific
+            .get("deconz")
+            .map(|value| DeconzStackSpecific::deserialize(value.clone()))
+            .transpose()?;
+        let (aps_extended_pan_id, trust_center_address, security_mode) = match deconz {
+            Some(deconz) => (
+                parse_hex_u64("aps_extended_pan_id", &deconz.aps_extended_pan_id)?,
+                parse_hex_u64("trust_center_address", &deconz.trust_center_address)?,
+                parse_security_mode(deconz.security_mode)?,
+            ),
+            None => (
+                0,
+                coordinator_ieee,
+                SecurityMode::NoMasterButTrustCenterLinkKey,
+            ),
+        };
+
+        let mut link_keys = Vec::new();
+        for device in &backup.devices {
+            if let Some(link_key) = &device.link_key {
+                link_keys.push(LinkKey {
+                    ieee_address: parse_hex_u64("ieee_address", &device.ieee_address)?,
+                    key: parse_key("link_key", &link_key.key)?,
+                });
+            }
+        }
+
+        Ok(Self {
+            coordinator_ieee,
+            pan_id: u16::from_str_radix(&backup.pan_id, 16).map_err(|_| {
+                BackupError::InvalidField {
+                    field: "pan_id",
+                    value: backup.pan_id.clone(),
+                }
+            })?,
+            extended_pan_id,
+            aps_extended_pan_id,
+            channel: backup.channel,
+            channel_mask: *ChannelMask::from_channels(backup.channel_mask.iter().copied()),
+            network_key: parse_key("network_key", &backup.network_key.key)?,
+            network_update_id: backup.nwk_update_id,
+            frame_counter: backup.network_key.frame_counter,
+            trust_center_address,
+            security_mode,
+            link_keys,
+        })
+    }
+}
+
+fn encode_key(key: &[u8; 16]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_key(field: &'static str, value: &str) -> Result<[u8; 16], BackupError> {
+    let invalid = || BackupError::InvalidField {
+        field,
+        value: value.to_string(),
+    };
+    if value.len() != 32 || !value.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut key = [0; 16];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+/// Parses an EUI64 or extended PAN ID, written most significant byte first. zigpy separates the
+/// bytes with colons in some places, so those are accepted too.
+fn parse_hex_u64(field: &'static str, value: &str) -> Result<u64, BackupError> {
+    u64::from_str_radix(&value.replace(':', ""), 16).map_err(|_| BackupError::InvalidField {
+        field,
+        value: value.to_string(),
+    })
+}
+
+fn parse_security_mode(value: u8) -> Result<SecurityMode, BackupError> {
+    Ok(match value {
+        x if x == SecurityMode::NoSecurity as u8 => SecurityMode::NoSecurity,
+        x if x == SecurityMode::PreconfiguredNetworkKey as u8 => {
+            SecurityMode::PreconfiguredNetworkKey
+        }
+        x if x == SecurityMode::NetworkKeyFromTrustCenter as u8 => {
+            SecurityMode::NetworkKeyFromTrustCenter
+        }
+        x if x == SecurityMode::NoMasterButTrustCenterLinkKey as u8 => {
+            SecurityMode::NoMasterButTrustCenterLinkKey
+        }
+        value => {
+            return Err(BackupError::InvalidField {
+                field: "security_mode",
+                value: value.to_string(),
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{emulator::DeconzEmulator, protocol::network_parameters::parameters, DeconzClient};
+    use crate::{DeconzClientConfig, ShutdownOptions};
+
+    fn example_backup() -> NetworkBackup {
+        NetworkBackup {
+            coordinator_ieee: 0x00212EFFFF0A0B0C,
+            pan_id: 0x4A3F,
+            extended_pan_id: 0xDDDDDDDDDDDDDDDD,
+            aps_extended_pan_id: 0,
+            channel: 15,
+            channel_mask: 1 << 15 | 1 << 20,
+            network_key: [
+                0x01, 0x03, 0x05, 0x07, 0x09, 0x0B, 0x0D, 0x0F, 0x00, 0x02, 0x04, 0x06, 0x08, 0x0A,
+                0x0C, 0x0D,
+            ],
+            network_update_id: 2,
+            frame_counter: 10_000,
+            trust_center_address: 0x00212EFFFF0A0B0C,
+            security_mode: SecurityMode::NoMasterButTrustCenterLinkKey,
+            link_keys: vec![LinkKey {
+                ieee_address: 0x00124B0001020304,
+                key: [0xAB; 16],
+            }],
+        }
+    }
+
+    #[test]
+    pub fn test_backup_json() {
+        let backup = example_backup();
+        let json = backup.to_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["metadata"]["format"], BACKUP_FORMAT);
+        assert_eq!(value["coordinator_ieee"], "00212effff0a0b0c");
+        assert_eq!(value["pan_id"], "4a3f");
+        assert_eq!(value["channel_mask"], serde_json::json!([15, 20]));
+        assert_eq!(
+            value["network_key"]["key"],
+            "01030507090b0d0f00020406080a0c0d"
+        );
+        assert_eq!(value["devices"][0]["ieee_address"], "00124b0001020304");
+
+        assert_eq!(NetworkBackup::from_json(&json).unwrap(), backup);
+
+        let off_band = json.replace("\"channel\": 15", "\"channel\": 40");
+        assert!(matches!(
+            NetworkBackup::from_json(&off_band),
+            Err(BackupError::InvalidField {
+                field: "channel",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_backup_restore() {
+        let emulator = DeconzEmulator::new();
+        let (task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        let backup = example_backup();
+        backup.restore(&mut handle).await.unwrap();
+
+        assert_eq!(emulator.network_state(), NetworkState::NetConnected);
+        assert_eq!(
+            *emulator.parameter::<parameters::MacAddress>().unwrap(),
+            0x00212EFFFF0A0B0C
+        );
+        assert_eq!(
+            *emulator
+                .parameter::<parameters::APSExtendedPanId>()
+                .unwrap(),
+            0xDDDDDDDDDDDDDDDD
+        );
+        assert_eq!(
+            *emulator.parameter::<parameters::ChannelMask>().unwrap(),
+            1 << 15
+        );
+        assert_eq!(
+            *emulator
+                .parameter::<parameters::NetworkFrameCounter>()
+                .unwrap(),
+            10_000 + FRAME_COUNTER_SAFETY_MARGIN
+        );
+        assert_eq!(
+            emulator.indexed_parameter::<LinkKey>(0x00124B0001020304),
+            Some(backup.link_keys[0].clone())
+        );
+
+        let read = NetworkBackup::read(&mut handle, &[0x00124B0001020304, 0x00124B0005060708])
+            .await
+            .unwrap();
+        assert_eq!(read.network_key, backup.network_key);
+        assert_eq!(read.link_keys, backup.link_keys);
+        assert_eq!(
+            read.frame_counter,
+            backup.frame_counter + FRAME_COUNTER_SAFETY_MARGIN
+        );
+
+        handle.shutdown(ShutdownOptions::default()).await;
+        task.await.unwrap().unwrap();
+    }
+}
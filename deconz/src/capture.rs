@@ -0,0 +1,600 @@
+//! Captures of the traffic with the device in the pcapng format, for Wireshark.
+//!
+//! Every frame exchanged with the device is written as-is (including the CRC) to the first
+//! interface of the capture. Wireshark has no dissector for the serial protocol, so APS data
+//! indications and requests are also written to a second interface, re-encapsulated as 802.15.4
+//! frames carrying a Zigbee NWK and APS header. That gets the ZDO and ZCL payloads decoded.
+//!
+//! The serial protocol doesn't tell everything those headers hold, so some of it is made up: the
+//! PAN id is left at 0xFFFF, sequence numbers are counted per capture (the APS counter of requests
+//! is their request id), frames are shown unsecured and sent in a single hop, and requests are
+//! shown as coming from the coordinator.
+//!
+//! Records are handed off to a thread of their own for writing, so that a slow disk doesn't hold
+//! up the traffic with the device. Should that thread fall behind, records are dropped instead.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::warn;
+
+use crate::{
+    protocol::{
+        aps::{
+            Broadcast, DestinationAddress, NetworkAddress, ReadReceivedDataResponse,
+            ReceivedSendData, SourceAddress,
+        },
+        CommandId, DeconzCommandResponse, StatusCode,
+    },
+    DeconzFrame, FrameDirection,
+};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END_OF_OPTIONS: u16 = 0;
+const OPT_SHB_USER_APPLICATION: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_DESCRIPTION: u16 = 3;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+/// Reserved for private use, which suits the serial protocol.
+const LINKTYPE_USER0: u16 = 147;
+/// 802.15.4 frames behind a TLV header, which carries the signal strength and link quality.
+const LINKTYPE_IEEE802_15_4_TAP: u16 = 283;
+
+const INTERFACE_SERIAL: u32 = 0;
+const INTERFACE_ZIGBEE: u32 = 1;
+
+const TAP_FCS_TYPE: u16 = 0;
+const TAP_RSS: u16 = 1;
+const TAP_LQI: u16 = 10;
+
+/// A data frame with PAN id compression and short addresses.
+const MAC_FRAME_CONTROL: u16 = 0x8841;
+const MAC_NO_SHORT_ADDRESS: NetworkAddress = 0xFFFE;
+const UNKNOWN_PAN_ID: u16 = 0xFFFF;
+
+/// A data frame of protocol version 2 (Zigbee 2006 and later).
+const NWK_FRAME_CONTROL: u16 = 0x0008;
+const NWK_DESTINATION_IEEE: u16 = 1 << 11;
+const NWK_SOURCE_IEEE: u16 = 1 << 12;
+const NWK_DEFAULT_RADIUS: u8 = 30;
+const COORDINATOR_ADDRESS: NetworkAddress = 0x0000;
+
+const APS_DELIVERY_UNICAST: u8 = 0b00 << 2;
+const APS_DELIVERY_BROADCAST: u8 = 0b10 << 2;
+const APS_DELIVERY_GROUP: u8 = 0b11 << 2;
+const APS_ACK_REQUEST: u8 = 1 << 6;
+const TX_OPTION_USE_APS_ACKS: u8 = 0x04;
+
+/// How many records may wait for the writer thread before new ones are dropped.
+const CAPTURE_QUEUE_LEN: usize = 1024;
+
+/// A pcapng capture of the traffic with the device, see the [module documentation](self). Clones
+/// write to the same capture, so that it can outlive reconnects. The capture is complete once
+/// every clone is dropped, or after [`Capture::flush`].
+#[derive(Clone)]
+pub struct Capture {
+    sender: SyncSender<CaptureMessage>,
+}
+
+enum CaptureMessage {
+    Record {
+        direction: FrameDirection,
+        timestamp: SystemTime,
+        packet: Bytes,
+        frame: Option<DeconzFrame<Bytes>>,
+    },
+    /// Flushes everything recorded before it, then acknowledges.
+    Flush(mpsc::Sender<()>),
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").finish_non_exhaustive()
+    }
+}
+
+impl Capture {
+    /// Creates (or truncates) the file at the given path and starts a capture in it.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Starts a capture, writing the section header and interface descriptions right away.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer = CaptureWriter {
+            writer: Box::new(writer),
+            mac_sequence: 0,
+            nwk_sequence: 0,
+            aps_counter: 0,
+        };
+        writer.write_header()?;
+        writer.writer.flush()?;
+
+        let (sender, receiver) = mpsc::sync_channel(CAPTURE_QUEUE_LEN);
+        thread::Builder::new()
+            .name("deconz-capture".into())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(Self { sender })
+    }
+
+    /// Waits for everything recorded so far to be written out, blocking the calling thread.
+    /// Useful before exiting, as the writer thread doesn't keep the process alive.
+    pub fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        if self.sender.send(CaptureMessage::Flush(done)).is_ok() {
+            flushed.recv().ok();
+        }
+    }
+
+    /// Records a packet as it went over the wire, and the frame parsed from it if it was valid.
+    /// Never blocks: if the writer thread falls behind, the record is dropped.
+    pub(crate) fn record(
+        &self,
+        direction: FrameDirection,
+        packet: Bytes,
+        frame: Option<DeconzFrame<Bytes>>,
+    ) {
+        let message = CaptureMessage::Record {
+            direction,
+            timestamp: SystemTime::now(),
+            packet,
+            frame,
+        };
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(message) {
+            warn!("the capture is falling behind, dropped a frame");
+        }
+    }
+}
+
+/// An APS frame to re-encapsulate, from either an indication or a request.
+struct ZigbeeFrame<'a> {
+    source: SourceAddress,
+    source_endpoint: u8,
+    destination: DestinationAddress,
+    /// Unused for [`DestinationAddress::GroupAddress`].
+    destination_endpoint: u8,
+    profile_id: u16,
+    cluster_id: u16,
+    ack_request: bool,
+    radius: u8,
+    payload: &'a [u8],
+    /// Counted per capture if not given.
+    aps_counter: Option<u8>,
+    /// The link quality and signal strength (in dBm) the frame was received with.
+    signal: Option<(u8, i8)>,
+}
+
+struct CaptureWriter {
+    writer: Box<dyn Write + Send>,
+    mac_sequence: u8,
+    nwk_sequence: u8,
+    aps_counter: u8,
+}
+
+impl CaptureWriter {
+    /// Writes records until every [`Capture`] is dropped. The file is flushed whenever there is
+    /// nothing left to write for the moment, rather than after every record.
+    fn run(mut self, receiver: Receiver<CaptureMessage>) {
+        while let Ok(mut message) = receiver.recv() {
+            loop {
+                match message {
+                    CaptureMessage::Record {
+                        direction,
+                        timestamp,
+                        packet,
+                        frame,
+                    } => {
+                        let result =
+                            self.write_serial(direction, timestamp, &packet)
+                                .and_then(|_| match &frame {
+                                    Some(frame) => self.write_zigbee(direction, timestamp, frame),
+                                    None => Ok(()),
+                                });
+                        if let Err(e) = result {
+                            warn!("failed to write to the capture: {}", e);
+                        }
+                    }
+                    CaptureMessage::Flush(done) => {
+                        if let Err(e) = self.writer.flush() {
+                            warn!("failed to flush the capture: {}", e);
+                        }
+                        done.send(()).ok();
+                    }
+                }
+
+                message = match receiver.try_recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                };
+            }
+
+            if let Err(e) = self.writer.flush() {
+                warn!("failed to flush the capture: {}", e);
+            }
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut body = BytesMut::new();
+        body.put_u32_le(BYTE_ORDER_MAGIC);
+        body.put_u16_le(1); // major version
+        body.put_u16_le(0); // minor version
+        body.put_i64_le(-1); // unknown section length
+        put_option(&mut body, OPT_SHB_USER_APPLICATION, b"deconz-rs");
+        put_option(&mut body, OPT_END_OF_OPTIONS, &[]);
+        self.write_block(BLOCK_SECTION_HEADER, &body)?;
+
+        self.write_interface(
+            LINKTYPE_USER0,
+            "serial",
+            "deCONZ serial protocol frames, including the CRC",
+        )?;
+        self.write_interface(
+            LINKTYPE_IEEE802_15_4_TAP,
+            "zigbee",
+            "APS data indications and requests, re-encapsulated",
+        )
+    }
+
+    fn write_interface(&mut self, link_type: u16, name: &str, description: &str) -> io::Result<()> {
+        let mut body = BytesMut::new();
+        body.put_u16_le(link_type);
+        body.put_u16_le(0); // reserved
+        body.put_u32_le(0); // no snapshot length
+        put_option(&mut body, OPT_IF_NAME, name.as_bytes());
+        put_option(&mut body, OPT_IF_DESCRIPTION, description.as_bytes());
+        put_option(&mut body, OPT_END_OF_OPTIONS, &[]);
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION, &body)
+    }
+
+    fn write_packet(
+        &mut self,
+        interface: u32,
+        direction: FrameDirection,
+        timestamp: SystemTime,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        // In microseconds, the default timestamp resolution.
+        let timestamp = timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_micros() as u64);
+        let flags = match direction {
+            FrameDirection::Incoming => EPB_FLAGS_INBOUND,
+            FrameDirection::Outgoing => EPB_FLAGS_OUTBOUND,
+        };
+
+        let mut body = BytesMut::new();
+        body.put_u32_le(interface);
+        body.put_u32_le((timestamp >> 32) as u32);
+        body.put_u32_le(timestamp as u32);
+        body.put_u32_le(packet.len() as u32); // captured length
+        body.put_u32_le(packet.len() as u32); // original length
+        body.put_slice(packet);
+        pad(&mut body);
+        put_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        put_option(&mut body, OPT_END_OF_OPTIONS, &[]);
+        self.write_block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total_length = (body.len() + 12) as u32;
+        let mut block = BytesMut::with_capacity(total_length as usize);
+        block.put_u32_le(block_type);
+        block.put_u32_le(total_length);
+        block.put_slice(body);
+        block.put_u32_le(total_length);
+        self.writer.write_all(&block)
+    }
+
+    fn write_serial(
+        &mut self,
+        direction: FrameDirection,
+        timestamp: SystemTime,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        self.write_packet(INTERFACE_SERIAL, direction, timestamp, packet)
+    }
+
+    /// Writes the frame to the Zigbee interface, if it carries an APS frame. Frames that fail to
+    /// parse are left to the client to report.
+    fn write_zigbee(
+        &mut self,
+        direction: FrameDirection,
+        timestamp: SystemTime,
+        frame: &DeconzFrame<Bytes>,
+    ) -> io::Result<()> {
+        match (direction, frame.command_id()) {
+            (FrameDirection::Incoming, CommandId::ApsDataIndication)
+                if frame.status() == StatusCode::Success =>
+            {
+                let indication = match ReadReceivedDataResponse::from_frame(frame.clone()) {
+                    Ok((indication, _)) => indication,
+                    Err(_) => return Ok(()),
+                };
+                let packet = self.zigbee_packet(&ZigbeeFrame {
+                    source: indication.source_address,
+                    source_endpoint: indication.source_endpoint,
+                    destination: indication.destination_address,
+                    destination_endpoint: indication.destination_endpoint,
+                    profile_id: indication.profile_id,
+                    cluster_id: indication.cluster_id,
+                    ack_request: false,
+                    radius: NWK_DEFAULT_RADIUS,
+                    payload: &indication.application_specific_data_unit,
+                    aps_counter: None,
+                    signal: Some((
+                        indication.link_quality_indication,
+                        indication.received_signal_strength_indication,
+                    )),
+                });
+                self.write_packet(INTERFACE_ZIGBEE, direction, timestamp, &packet)
+            }
+            (FrameDirection::Outgoing, CommandId::ApsDataRequest) => {
+                let request = match ReceivedSendData::from_frame(frame.clone()) {
+                    Ok(request) => request,
+                    Err(_) => return Ok(()),
+                };
+                let packet = self.zigbee_packet(&ZigbeeFrame {
+                    source: SourceAddress::NetworkAddress(COORDINATOR_ADDRESS),
+                    source_endpoint: request.source_endpoint,
+                    destination: request.destination_address,
+                    destination_endpoint: request.destination_endpoint.unwrap_or_default(),
+                    profile_id: request.profile_id,
+                    cluster_id: request.cluster_id,
+                    ack_request: request.tx_options & TX_OPTION_USE_APS_ACKS != 0,
+                    radius: match request.radius {
+                        0 => NWK_DEFAULT_RADIUS,
+                        radius => radius,
+                    },
+                    payload: &request.payload,
+                    // Makes it easier to match requests up with their confirms.
+                    aps_counter: Some(request.request_id),
+                    signal: None,
+                });
+                self.write_packet(INTERFACE_ZIGBEE, direction, timestamp, &packet)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Builds the 802.15.4 TAP header, followed by the MAC, NWK and APS headers and the payload.
+    fn zigbee_packet(&mut self, frame: &ZigbeeFrame) -> BytesMut {
+        let mut tlvs = BytesMut::new();
+        put_option(&mut tlvs, TAP_FCS_TYPE, &[0]); // no FCS
+        if let Some((link_quality, signal_strength)) = frame.signal {
+            put_option(
+                &mut tlvs,
+                TAP_RSS,
+                &f32::from(signal_strength).to_le_bytes(),
+            );
+            put_option(&mut tlvs, TAP_LQI, &[link_quality]);
+        }
+
+        let mut packet = BytesMut::new();
+        packet.put_u8(0); // version
+        packet.put_u8(0); // reserved
+        packet.put_u16_le((tlvs.len() + 4) as u16);
+        packet.put_slice(&tlvs);
+
+        let (nwk_source, source_ieee) = match frame.source {
+            SourceAddress::NetworkAddress(network_address) => (network_address, None),
+            SourceAddress::IEEEAddress(ieee_address) => (MAC_NO_SHORT_ADDRESS, Some(ieee_address)),
+            SourceAddress::Both {
+                network_address,
+                ieee_address,
+            } => (network_address, Some(ieee_address)),
+        };
+        let (nwk_destination, destination_ieee) = match frame.destination {
+            DestinationAddress::GroupAddress(_) => {
+                (Broadcast::RxOnWhenIdle as NetworkAddress, None)
+            }
+            DestinationAddress::NetworkAddress(network_address) => (network_address, None),
+            DestinationAddress::IEEEAddress(ieee_address) => {
+                (MAC_NO_SHORT_ADDRESS, Some(ieee_address))
+            }
+        };
+        let mac_destination = if Broadcast::from_network_address(nwk_destination).is_some() {
+            Broadcast::AllDevices as NetworkAddress
+        } else {
+            nwk_destination
+        };
+
+        packet.put_u16_le(MAC_FRAME_CONTROL);
+        packet.put_u8(next_sequence(&mut self.mac_sequence));
+        packet.put_u16_le(UNKNOWN_PAN_ID);
+        packet.put_u16_le(mac_destination);
+        packet.put_u16_le(nwk_source);
+
+        let mut nwk_frame_control = NWK_FRAME_CONTROL;
+        if destination_ieee.is_some() {
+            nwk_frame_control |= NWK_DESTINATION_IEEE;
+        }
+        if source_ieee.is_some() {
+            nwk_frame_control |= NWK_SOURCE_IEEE;
+        }
+        packet.put_u16_le(nwk_frame_control);
+        packet.put_u16_le(nwk_destination);
+        packet.put_u16_le(nwk_source);
+        packet.put_u8(frame.radius);
+        packet.put_u8(next_sequence(&mut self.nwk_sequence));
+        if let Some(ieee_address) = destination_ieee {
+            packet.put_u64_le(ieee_address);
+        }
+        if let Some(ieee_address) = source_ieee {
+            packet.put_u64_le(ieee_address);
+        }
+
+        let mut aps_frame_control = match frame.destination {
+            DestinationAddress::GroupAddress(_) => APS_DELIVERY_GROUP,
+            _ if frame.destination.as_broadcast().is_some() => APS_DELIVERY_BROADCAST,
+            _ => APS_DELIVERY_UNICAST,
+        };
+        if frame.ack_request {
+            aps_frame_control |= APS_ACK_REQUEST;
+        }
+        packet.put_u8(aps_frame_control);
+        match frame.destination {
+            DestinationAddress::GroupAddress(group_address) => packet.put_u16_le(group_address),
+            _ => packet.put_u8(frame.destination_endpoint),
+        }
+        packet.put_u16_le(frame.cluster_id);
+        packet.put_u16_le(frame.profile_id);
+        packet.put_u8(frame.source_endpoint);
+        packet.put_u8(
+            frame
+                .aps_counter
+                .unwrap_or_else(|| next_sequence(&mut self.aps_counter)),
+        );
+        packet.put_slice(frame.payload);
+
+        packet
+    }
+}
+
+fn next_sequence(sequence: &mut u8) -> u8 {
+    let current = *sequence;
+    *sequence = current.wrapping_add(1);
+    current
+}
+
+/// Writes a pcapng option, or an 802.15.4 TAP TLV, which are laid out the same.
+fn put_option(buf: &mut BytesMut, code: u16, value: &[u8]) {
+    buf.put_u16_le(code);
+    buf.put_u16_le(value.len() as u16);
+    buf.put_slice(value);
+    pad(buf);
+}
+
+/// Pads to a multiple of 4 bytes, as blocks, options and TLVs are aligned to.
+fn pad(buf: &mut BytesMut) {
+    while !buf.len().is_multiple_of(4) {
+        buf.put_u8(0);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocol::{device::DeviceState, DeconzCommandResponseEncoder};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Splits a capture into its block types and bodies, checking the lengths on the way.
+    fn read_blocks(mut capture: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let read_u32 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let mut blocks = Vec::new();
+        while !capture.is_empty() {
+            let block_type = read_u32(capture);
+            let length = read_u32(&capture[4..]) as usize;
+            assert_eq!(length % 4, 0);
+            assert_eq!(read_u32(&capture[length - 4..]) as usize, length);
+            blocks.push((block_type, capture[8..length - 4].to_vec()));
+            capture = &capture[length..];
+        }
+        blocks
+    }
+
+    #[test]
+    pub fn test_capture_indication() {
+        let buffer = SharedBuffer::default();
+        let capture = Capture::new(buffer.clone()).unwrap();
+
+        let indication = ReadReceivedDataResponse {
+            device_state: DeviceState::from(0x22),
+            destination_address: DestinationAddress::NetworkAddress(0x0000),
+            destination_endpoint: 0x00,
+            source_address: SourceAddress::Both {
+                network_address: 0x1234,
+                ieee_address: 0x0011_2233_4455_6677,
+            },
+            source_endpoint: 0x00,
+            profile_id: 0x0000,
+            cluster_id: 0x0013,
+            application_specific_data_unit: vec![0x81, 0x34, 0x12],
+            link_quality_indication: 0xFF,
+            received_signal_strength_indication: -40,
+        };
+        let outgoing = indication.as_frame(7, StatusCode::Success);
+        let frame = outgoing.to_parsed();
+        let packet = outgoing.encode();
+        capture.record(FrameDirection::Incoming, packet.clone(), Some(frame));
+        capture.flush();
+
+        let blocks = read_blocks(&buffer.0.lock().unwrap());
+        let block_types: Vec<_> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(
+            block_types,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+            ]
+        );
+        assert_eq!(&blocks[2].1[..2], &LINKTYPE_IEEE802_15_4_TAP.to_le_bytes());
+
+        // The serial frame as-is, marked inbound.
+        let serial = &blocks[3].1;
+        assert_eq!(&serial[..4], &INTERFACE_SERIAL.to_le_bytes());
+        assert_eq!(&serial[20..20 + packet.len()], &packet[..]);
+        let options = &serial[20 + packet.len() + (4 - packet.len() % 4) % 4..];
+        assert_eq!(
+            &options[..8],
+            &[0x02, 0x00, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+
+        let zigbee = &blocks[4].1;
+        assert_eq!(&zigbee[..4], &INTERFACE_ZIGBEE.to_le_bytes());
+        let length = u32::from_le_bytes([zigbee[12], zigbee[13], zigbee[14], zigbee[15]]) as usize;
+        let mut expected = vec![
+            // TAP header: no FCS, -40 dBm, LQI 255.
+            0x00, 0x00, 0x1C, 0x00, //
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x01, 0x00, 0x04, 0x00,
+        ];
+        expected.extend_from_slice(&(-40f32).to_le_bytes());
+        expected.extend_from_slice(&[
+            0x0A, 0x00, 0x01, 0x00, 0xFF, 0x00, 0x00, 0x00, //
+            // MAC header
+            0x41, 0x88, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x34, 0x12, //
+            // NWK header, with the source IEEE address
+            0x08, 0x10, 0x00, 0x00, 0x34, 0x12, 0x1E, 0x00, //
+            0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, //
+            // APS header, unicast
+            0x00, 0x00, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            // ZDO device announce (truncated)
+            0x81, 0x34, 0x12,
+        ]);
+        assert_eq!(&zigbee[20..20 + length], &expected[..]);
+    }
+}
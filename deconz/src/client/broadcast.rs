@@ -0,0 +1,81 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+/// Paces broadcasts (and group sends, which are broadcast too) sent through APS data requests.
+/// Every router remembers each broadcast it relays in its broadcast transaction table for a
+/// while, and drops new broadcasts while the table is full. Bulk group commands easily fill it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastLimit {
+    /// How many broadcasts may be sent within the window. Zero is taken as one, as no broadcast
+    /// could be sent at all otherwise.
+    pub max_broadcasts: usize,
+    /// How long a broadcast counts against the budget.
+    pub window: Duration,
+}
+
+impl Default for BroadcastLimit {
+    /// Stays within the 9 entries most stacks keep for the 9 seconds a broadcast is remembered.
+    fn default() -> Self {
+        Self {
+            max_broadcasts: 8,
+            window: Duration::from_secs(9),
+        }
+    }
+}
+
+/// Keeps track of the broadcasts sent within the window of a [`BroadcastLimit`].
+pub(crate) struct BroadcastLimiter {
+    limit: Option<BroadcastLimit>,
+    sent: VecDeque<Instant>,
+}
+
+impl BroadcastLimiter {
+    pub(crate) fn new(limit: Option<BroadcastLimit>) -> Self {
+        // Without any budget, broadcasts would wait forever for a wake-up that never comes.
+        let limit = limit.map(|limit| BroadcastLimit {
+            max_broadcasts: limit.max_broadcasts.max(1),
+            ..limit
+        });
+        Self {
+            limit,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Returns `true` if another broadcast can be sent right now.
+    pub(crate) fn has_budget(&mut self, now: Instant) -> bool {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        while matches!(self.sent.front(), Some(sent) if *sent + limit.window <= now) {
+            self.sent.pop_front();
+        }
+        self.sent.len() < limit.max_broadcasts
+    }
+
+    pub(crate) fn record(&mut self, now: Instant) {
+        if self.limit.is_some() {
+            self.sent.push_back(now);
+        }
+    }
+
+    /// Returns when the budget allows another broadcast, if it currently doesn't.
+    pub(crate) fn next_budget(&self, now: Instant) -> Option<Instant> {
+        let limit = self.limit.as_ref()?;
+        let in_window = self
+            .sent
+            .iter()
+            .filter(|sent| **sent + limit.window > now)
+            .count();
+        if in_window < limit.max_broadcasts {
+            return None;
+        }
+        // Sends are recorded in order, so the oldest one in the window frees up first.
+        self.sent
+            .iter()
+            .map(|sent| *sent + limit.window)
+            .find(|expires| *expires > now)
+    }
+}
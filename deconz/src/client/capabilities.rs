@@ -0,0 +1,24 @@
+use crate::protocol::device::{FirmwareVersion, FirmwareVersionPlatform};
+
+/// What the connected device is running, as read by the client task on every (re)connect. See
+/// [`DeconzClientHandle::device_capabilities`](crate::DeconzClientHandle::device_capabilities).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+    pub firmware_version: FirmwareVersion,
+    pub platform: FirmwareVersionPlatform,
+    /// The serial protocol version, or 0 if the firmware is too old to report it.
+    pub protocol_version: u16,
+}
+
+impl DeviceCapabilities {
+    /// Returns `true` if the device speaks at least the given protocol version.
+    pub fn supports_protocol_version(&self, protocol_version: u16) -> bool {
+        self.protocol_version >= protocol_version
+    }
+
+    /// Returns `true` if the device runs at least the given firmware version, see
+    /// [`FirmwareVersion::is_at_least`].
+    pub fn supports_firmware_version(&self, firmware_version: FirmwareVersion) -> bool {
+        self.firmware_version.is_at_least(firmware_version)
+    }
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+/// The state of the connection between the client task and the device, as published to
+/// [`DeconzClientHandle::connection_state`](crate::DeconzClientHandle::connection_state).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The transport is being opened for the first time.
+    Connecting,
+    /// The transport is open. The device state is re-read every time this is entered.
+    Connected,
+    /// The connection was lost, commands that were in flight have failed.
+    Disconnected,
+    /// Waiting `delay` before the given reconnect attempt, counting from 1.
+    Reconnecting { attempt: u32, delay: Duration },
+}
+
+impl ConnectionState {
+    /// Returns `true` if the connection state is [`Connected`].
+    ///
+    /// [`Connected`]: ConnectionState::Connected
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Self::Connected)
+    }
+}
+
+/// How the client task reopens the transport after losing the connection, for example when the
+/// USB stick is unplugged. The delay between attempts doubles up to `max_delay`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up and end the task after this many failed attempts. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// The delay before the given attempt, counting from 1.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    pub(crate) fn allows_attempt(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt <= max)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use bytes::Bytes;
+
+use super::ConnectionState;
+use crate::{
+    protocol::{
+        aps::{ReadConfirmDataResponse, ReadReceivedDataResponse},
+        device::DeviceState,
+        mac::{MACBeaconIndication, MACPollIndication},
+    },
+    DeconzFrame,
+};
+
+/// Everything the device reports without being asked, and changes to the connection to it, see
+/// [`DeconzClientHandle::subscribe_events`](crate::DeconzClientHandle::subscribe_events).
+#[derive(Debug, Clone)]
+pub enum DeconzEvent {
+    /// A frame was received from the network.
+    ApsDataIndication(ReadReceivedDataResponse),
+    /// The device finished sending an APS data request, successfully or not.
+    ApsDataConfirm(ReadConfirmDataResponse),
+    /// A beacon was received, typically while scanning for networks.
+    MacBeacon(MACBeaconIndication),
+    /// A child polled the device for pending frames.
+    MacPoll(MACPollIndication),
+    /// The device state flags changed, as reported by the device itself or along with a response.
+    DeviceStateChanged(DeviceState),
+    ConnectionStateChanged(ConnectionState),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    /// Received from the device.
+    Incoming,
+    /// Sent to the device.
+    Outgoing,
+}
+
+/// A copy of a frame exchanged with the device, see
+/// [`DeconzClientHandle::subscribe_frames`](crate::DeconzClientHandle::subscribe_frames).
+#[derive(Debug, Clone)]
+pub struct TappedFrame {
+    pub direction: FrameDirection,
+    /// Outgoing frames are in the same shape as incoming ones, with a status of
+    /// [`StatusCode::Success`](crate::protocol::StatusCode::Success) for the reserved status byte.
+    pub frame: DeconzFrame<Bytes>,
+}
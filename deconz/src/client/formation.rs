@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use rand::{seq::IteratorRandom, Rng};
+use thiserror::Error;
+
+use super::{
+    handle::{DeconzClientHandle, HandleError},
+    network_state::LEAVE_NETWORK_TIMEOUT,
+};
+use crate::protocol::{
+    network_parameters::{
+        self,
+        parameters::{
+            APSDesignatedCoordinator, ChannelMask, NetworkKey, PredefinedNetworkPanId, SecurityMode,
+        },
+    },
+    NetworkState,
+};
+
+#[derive(Error, Debug)]
+pub enum FormNetworkError {
+    #[error("failed to talk to the device: {0}")]
+    Handle(#[from] HandleError),
+    #[error("the channel mask allows none of the Zigbee channels (11-26)")]
+    NoChannel,
+    #[error("the device did not leave its current network")]
+    NotOffline,
+    #[error("the network did not come up in time")]
+    Timeout,
+}
+
+/// See [`DeconzClientHandle::form_network`].
+#[derive(Debug, Clone)]
+pub struct FormNetworkOptions {
+    /// The channels to pick the network's channel from.
+    pub channel_mask: ChannelMask,
+    pub security_mode: SecurityMode,
+    /// How long to wait for the network to come up.
+    pub timeout: Duration,
+}
+
+impl Default for FormNetworkOptions {
+    /// Picks one of the channels least likely to overlap with Wi-Fi (11, 15, 20 and 25), with
+    /// devices joining through the trust center link key.
+    fn default() -> Self {
+        Self {
+            channel_mask: ChannelMask::from_channels([11, 15, 20, 25]),
+            security_mode: SecurityMode::NoMasterButTrustCenterLinkKey,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The settings of a newly formed network. Keep them around: the network can't be brought back
+/// up on another coordinator without the network key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormedNetwork {
+    pub pan_id: u16,
+    pub extended_pan_id: u64,
+    pub channel: u8,
+    pub network_key: [u8; 16],
+    pub security_mode: SecurityMode,
+}
+
+impl DeconzClientHandle {
+    /// Forms a new network with a random PAN ID, extended PAN ID and network key, on a random
+    /// channel out of the allowed ones. Any network the device is currently part of is left.
+    pub async fn form_network(
+        &mut self,
+        options: FormNetworkOptions,
+    ) -> Result<FormedNetwork, FormNetworkError> {
+        let mut rng = rand::thread_rng();
+        let channel = options
+            .channel_mask
+            .channels()
+            .choose(&mut rng)
+            .ok_or(FormNetworkError::NoChannel)?;
+        // 0x0000 and 0xFFFF are reserved, as are the extended PAN IDs with all bits set or clear.
+        let pan_id = rng.gen_range(0x0001..0xFFFF);
+        let extended_pan_id = rng.gen_range(0x0000_0000_0000_0001..0xFFFF_FFFF_FFFF_FFFF);
+        let network_key = rng.gen();
+
+        if !self
+            .set_network_state(NetworkState::NetOffline, LEAVE_NETWORK_TIMEOUT)
+            .await?
+        {
+            return Err(FormNetworkError::NotOffline);
+        }
+
+        let mac_address = *self
+            .send_command(network_parameters::ReadMacAddress::new())
+            .await?
+            .value;
+        self.send_command(network_parameters::WriteAPSDesignatedCoordinator::new(
+            APSDesignatedCoordinator::Coordinator,
+        ))
+        .await?;
+        self.send_command(network_parameters::WriteNetworkAddress::new(0x0000))
+            .await?;
+        self.send_command(network_parameters::WriteNetworkPanId::new(pan_id))
+            .await?;
+        self.send_command(network_parameters::WritePredefinedNetworkPanId::new(
+            PredefinedNetworkPanId::Predefined,
+        ))
+        .await?;
+        self.send_command(network_parameters::WriteAPSExtendedPanId::new(
+            extended_pan_id,
+        ))
+        .await?;
+        self.send_command(network_parameters::WriteChannelMask::new(
+            ChannelMask::from_channels([channel]),
+        ))
+        .await?;
+        self.send_command(network_parameters::WriteTrustCenterAddress::new(
+            mac_address,
+        ))
+        .await?;
+        self.send_command(network_parameters::WriteSecurityMode::new(
+            options.security_mode,
+        ))
+        .await?;
+        self.send_command(network_parameters::WriteNetworkKey::new(NetworkKey::Set(
+            network_key,
+        )))
+        .await?;
+        self.send_command(network_parameters::WriteNetworkUpdateId::new(0))
+            .await?;
+
+        if !self
+            .set_network_state(NetworkState::NetConnected, options.timeout)
+            .await?
+        {
+            return Err(FormNetworkError::Timeout);
+        }
+
+        Ok(FormedNetwork {
+            pan_id,
+            extended_pan_id,
+            channel: *self
+                .send_command(network_parameters::ReadCurrentChannel::new())
+                .await?
+                .value,
+            network_key,
+            security_mode: options.security_mode,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{emulator::DeconzEmulator, protocol::network_parameters::parameters};
+    use crate::{DeconzClient, DeconzClientConfig};
+
+    #[tokio::test]
+    pub async fn test_form_network() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        let options = FormNetworkOptions {
+            channel_mask: ChannelMask::from_channels([20]),
+            ..Default::default()
+        };
+        let network = handle.form_network(options).await.unwrap();
+
+        assert_eq!(emulator.network_state(), NetworkState::NetConnected);
+        assert_eq!(network.channel, 20);
+        assert_eq!(
+            *emulator.parameter::<parameters::NetworkPanId>().unwrap(),
+            network.pan_id
+        );
+        assert_eq!(
+            *emulator
+                .parameter::<parameters::APSExtendedPanId>()
+                .unwrap(),
+            network.extended_pan_id
+        );
+        assert!(matches!(
+            emulator.parameter::<NetworkKey>(),
+            Some(NetworkKey::Set(key)) if key == network.network_key
+        ));
+
+        let options = FormNetworkOptions {
+            channel_mask: ChannelMask::from(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            handle.form_network(options).await,
+            Err(FormNetworkError::NoChannel)
+        ));
+    }
+}
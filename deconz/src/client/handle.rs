@@ -1,44 +1,278 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
 use thiserror::Error;
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
-use super::task::{SubscribeRequest, TaskMessage};
-use crate::protocol::{
-    aps::ReadReceivedDataResponse, DeconzCommand, DeconzCommandResponse,
+use super::{
+    capabilities::DeviceCapabilities,
+    events::{DeconzEvent, TappedFrame},
+    queue::ApsConfirmSender,
+    subscription::{ApsFilter, ApsSubscription, ApsSubscriptionOptions},
+    task::{SubscribeRequest, TaskMessage},
+    watchdog::WatchdogEvent,
+    ConnectionState, ShutdownOptions,
+};
+use crate::{
+    frame::ProtocolError,
+    protocol::{
+        aps::{ReadConfirmDataResponse, ReadReceivedDataResponse, SendData},
+        device::{DeviceState, FirmwareVersion},
+        DeconzCommand, DeconzCommandResponse, StatusCode,
+    },
+    DeconzFrame, DeconzStreamError,
 };
 
 #[derive(Error, Debug)]
 pub enum HandleError {
     #[error("error communicating with the task")]
     TaskFailure,
+    #[error("failed to send the command to the device: {0}")]
+    Transport(Arc<DeconzStreamError>),
+    #[error("the device responded with status {0:?}")]
+    Status(StatusCode),
+    #[error("failed to parse the device response: {0}")]
+    Parse(#[from] ProtocolError),
+    #[error("the connection to the device was lost before a response was received")]
+    Disconnected,
+    #[error("the device did not respond in time")]
+    Timeout,
+    #[error("the command was cancelled before it was sent")]
+    Cancelled,
+    #[error("the client has shut down")]
+    Shutdown,
+    #[error("the command needs protocol version {required:#06x}, but the device has {protocol_version:#06x}")]
+    Unsupported {
+        required: u16,
+        protocol_version: u16,
+    },
+    #[error(
+        "the command needs firmware version {required}, but the device has {firmware_version}"
+    )]
+    UnsupportedFirmware {
+        required: FirmwareVersion,
+        firmware_version: FirmwareVersion,
+    },
+}
+
+/// Identifies a submitted command, so that it can be cancelled later on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CommandToken(u64);
+
+impl CommandToken {
+    pub(crate) fn next() -> Self {
+        static NEXT_COMMAND_TOKEN: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_COMMAND_TOKEN.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Cancels a command, as long as it is still waiting in the queue. Once a command has been
+/// written to the device, cancelling it has no effect.
+#[derive(Clone)]
+pub struct CancelHandle {
+    task_tx: mpsc::UnboundedSender<TaskMessage>,
+    command_token: CommandToken,
+}
+
+impl CancelHandle {
+    /// Removes the command from the queue, failing it with [`HandleError::Cancelled`].
+    pub fn cancel(&self) {
+        self.task_tx
+            .send(TaskMessage::CancelCommand(self.command_token))
+            .ok();
+    }
+}
+
+/// Cancels the command when the future waiting for its response is dropped early.
+struct CancelOnDrop(Option<CancelHandle>);
+
+impl CancelOnDrop {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(cancel_handle) = self.0.take() {
+            cancel_handle.cancel();
+        }
+    }
 }
 
 /// The DeconzClientHandle has methods for interacting with the Deconz client task.
 #[derive(Clone)]
 pub struct DeconzClientHandle {
     task_tx: mpsc::UnboundedSender<TaskMessage>,
+    connection_state: watch::Receiver<ConnectionState>,
+    device_state: watch::Receiver<Option<DeviceState>>,
+    device_capabilities: watch::Receiver<Option<DeviceCapabilities>>,
 }
 
 impl DeconzClientHandle {
     /// Used by DeconzClient to construct a new Handle.
-    pub(super) fn new(task_tx: mpsc::UnboundedSender<TaskMessage>) -> Self {
-        Self { task_tx }
+    pub(super) fn new(
+        task_tx: mpsc::UnboundedSender<TaskMessage>,
+        connection_state: watch::Receiver<ConnectionState>,
+        device_state: watch::Receiver<Option<DeviceState>>,
+        device_capabilities: watch::Receiver<Option<DeviceCapabilities>>,
+    ) -> Self {
+        Self {
+            task_tx,
+            connection_state,
+            device_state,
+            device_capabilities,
+        }
+    }
+
+    /// Returns a receiver for watching the connection to the device go down and come back up.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.connection_state.clone()
+    }
+
+    /// Returns a receiver for watching the device state, like whether the network is up. It's
+    /// `None` until the device first reports its state, and again while disconnected.
+    pub fn device_state(&self) -> watch::Receiver<Option<DeviceState>> {
+        self.device_state.clone()
+    }
+
+    /// Returns a receiver for the capabilities of the connected device. They're `None` until the
+    /// firmware and protocol version have been read, which happens again after every reconnect.
+    pub fn device_capabilities(&self) -> watch::Receiver<Option<DeviceCapabilities>> {
+        self.device_capabilities.clone()
     }
 
+    /// Sends a command, failing with [`HandleError::Timeout`] if the device doesn't respond within
+    /// [`DeconzClientConfig::command_timeout`](crate::DeconzClientConfig::command_timeout).
+    ///
+    /// Dropping the returned future before the command is sent removes it from the queue.
     pub async fn send_command<T>(&mut self, outgoing_command: T) -> Result<T::Response, HandleError>
+    where
+        T: DeconzCommand,
+    {
+        self.submit_command(outgoing_command, None, None).1.await
+    }
+
+    /// Sends a command with its own timeout, instead of the configured default.
+    pub async fn send_command_with_timeout<T>(
+        &mut self,
+        outgoing_command: T,
+        timeout: Duration,
+    ) -> Result<T::Response, HandleError>
+    where
+        T: DeconzCommand,
+    {
+        self.submit_command(outgoing_command, Some(timeout), None)
+            .1
+            .await
+    }
+
+    /// Enqueues a command right away, returning a handle to cancel it with alongside the future
+    /// resolving to its response.
+    pub fn send_command_cancellable<T>(
+        &mut self,
+        outgoing_command: T,
+    ) -> (
+        CancelHandle,
+        impl Future<Output = Result<T::Response, HandleError>>,
+    )
+    where
+        T: DeconzCommand,
+    {
+        self.submit_command(outgoing_command, None, None)
+    }
+
+    /// Sends an APS data request, resolving with its confirm once the device reports whether the
+    /// frame was delivered, rather than once it was accepted like [`Self::send_command`] does.
+    /// With APS acks requested the confirm reflects the destination, otherwise only the next hop.
+    ///
+    /// The request id is allocated by the client, whatever it was set to. Fails with
+    /// [`HandleError::Timeout`] if the confirm doesn't arrive within
+    /// [`DeconzClientConfig::aps_confirm_timeout`](crate::DeconzClientConfig::aps_confirm_timeout).
+    pub async fn send_data(
+        &mut self,
+        send_data: SendData,
+    ) -> Result<ReadConfirmDataResponse, HandleError> {
+        let (confirm_tx, confirm_rx) = oneshot::channel();
+        self.submit_command(send_data, None, Some(confirm_tx))
+            .1
+            .await?;
+        confirm_rx.await.map_err(|_| HandleError::TaskFailure)?
+    }
+
+    fn submit_command<T>(
+        &mut self,
+        outgoing_command: T,
+        timeout: Option<Duration>,
+        aps_confirm: Option<ApsConfirmSender>,
+    ) -> (
+        CancelHandle,
+        impl Future<Output = Result<T::Response, HandleError>>,
+    )
     where
         T: DeconzCommand,
     {
         let (tx, rx) = oneshot::channel();
-        let response_parser = move |frame| {
-            let (response, device_state) = T::Response::from_frame(frame);
-            tx.send(response).ok();
+        let response_parser = move |frame: Result<DeconzFrame<Bytes>, HandleError>| {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    tx.send(Err(e)).ok();
+                    return None;
+                }
+            };
+
+            // Responses with a non-success status are still parsed on a best-effort basis, as
+            // they usually carry a device state that the queue relies on.
+            let status = frame.status();
+            let (result, device_state) = match T::Response::from_frame(frame) {
+                Ok((response, device_state)) if status == StatusCode::Success => {
+                    (Ok(response), device_state)
+                }
+                Ok((_, device_state)) => (Err(HandleError::Status(status)), device_state),
+                Err(e) if status == StatusCode::Success => (Err(HandleError::Parse(e)), None),
+                Err(_) => (Err(HandleError::Status(status)), None),
+            };
+            tx.send(result).ok();
             device_state
         };
+        let cancel_handle = CancelHandle {
+            task_tx: self.task_tx.clone(),
+            command_token: CommandToken::next(),
+        };
         let task_message = TaskMessage::CommandRequest {
             command_request: Box::new(outgoing_command.into_request()),
             response_parser: Box::new(response_parser),
+            timeout,
+            command_token: cancel_handle.command_token,
+            aps_confirm,
+        };
+        let sent = self.task_tx.send(task_message);
+
+        let mut cancel_on_drop = CancelOnDrop(Some(cancel_handle.clone()));
+        let response = async move {
+            sent.map_err(|_| HandleError::TaskFailure)?;
+            let response = rx.await.map_err(|_| HandleError::TaskFailure)?;
+            cancel_on_drop.disarm();
+            response
         };
 
+        (cancel_handle, response)
+    }
+
+    pub async fn subscribe_aps_data_indication(
+        &mut self,
+    ) -> Result<broadcast::Receiver<ReadReceivedDataResponse>, HandleError> {
+        let (tx, rx) = oneshot::channel();
+
+        let task_message = TaskMessage::SubscribeRequest(SubscribeRequest::ApsDataIndication(tx));
         self.task_tx
             .send(task_message)
             .map_err(|_| HandleError::TaskFailure)?;
@@ -46,16 +280,94 @@ impl DeconzClientHandle {
         rx.await.map_err(|_| HandleError::TaskFailure)
     }
 
-    pub async fn subscribe_aps_data_indication(
+    /// Subscribes to the outcome of watchdog refreshes, see [`WatchdogKeepAlive`](crate::WatchdogKeepAlive).
+    pub async fn subscribe_watchdog_events(
         &mut self,
-    ) -> Result<broadcast::Receiver<ReadReceivedDataResponse>, HandleError> {
+    ) -> Result<broadcast::Receiver<WatchdogEvent>, HandleError> {
         let (tx, rx) = oneshot::channel();
 
-        let task_message = TaskMessage::SubscribeRequest(SubscribeRequest::ApsDataIndication(tx));
+        let task_message = TaskMessage::SubscribeRequest(SubscribeRequest::WatchdogEvent(tx));
         self.task_tx
             .send(task_message)
             .map_err(|_| HandleError::TaskFailure)?;
 
         rx.await.map_err(|_| HandleError::TaskFailure)
     }
+
+    /// Subscribes to the APS data indications matching the filter, queued with the default
+    /// [`ApsSubscriptionOptions`].
+    pub async fn subscribe_aps(
+        &mut self,
+        filter: ApsFilter,
+    ) -> Result<ApsSubscription, HandleError> {
+        self.subscribe_aps_with_options(filter, Default::default())
+            .await
+    }
+
+    /// Subscribes to the APS data indications matching the filter. Frames are filtered inside
+    /// the client task and queued per subscription, so slow subscribers only affect each other
+    /// when they ask for [`OverflowPolicy::Backpressure`](crate::OverflowPolicy::Backpressure).
+    pub async fn subscribe_aps_with_options(
+        &mut self,
+        filter: ApsFilter,
+        options: ApsSubscriptionOptions,
+    ) -> Result<ApsSubscription, HandleError> {
+        let (tx, rx) = oneshot::channel();
+
+        let task_message = TaskMessage::SubscribeRequest(SubscribeRequest::Aps {
+            filter,
+            options,
+            sender: tx,
+        });
+        self.task_tx
+            .send(task_message)
+            .map_err(|_| HandleError::TaskFailure)?;
+
+        rx.await.map_err(|_| HandleError::TaskFailure)
+    }
+
+    /// Subscribes to everything the device reports unsolicited, along with changes to the
+    /// connection to it. Events are only delivered from the moment of subscribing.
+    pub async fn subscribe_events(
+        &mut self,
+    ) -> Result<broadcast::Receiver<DeconzEvent>, HandleError> {
+        let (tx, rx) = oneshot::channel();
+
+        let task_message = TaskMessage::SubscribeRequest(SubscribeRequest::Event(tx));
+        self.task_tx
+            .send(task_message)
+            .map_err(|_| HandleError::TaskFailure)?;
+
+        rx.await.map_err(|_| HandleError::TaskFailure)
+    }
+
+    /// Subscribes to a copy of every frame sent to and received from the device, for looking
+    /// into commands this crate doesn't know about. See [`RawCommand`](crate::protocol::raw::RawCommand).
+    pub async fn subscribe_frames(
+        &mut self,
+    ) -> Result<broadcast::Receiver<TappedFrame>, HandleError> {
+        let (tx, rx) = oneshot::channel();
+
+        let task_message = TaskMessage::SubscribeRequest(SubscribeRequest::Frame(tx));
+        self.task_tx
+            .send(task_message)
+            .map_err(|_| HandleError::TaskFailure)?;
+
+        rx.await.map_err(|_| HandleError::TaskFailure)
+    }
+
+    /// Stops the client task, and waits for it to close the connection to the device.
+    ///
+    /// The task also shuts down on its own, with the default options, once every handle is dropped.
+    pub async fn shutdown(&self, options: ShutdownOptions) {
+        let (done, stopped) = oneshot::channel();
+        if self
+            .task_tx
+            .send(TaskMessage::Shutdown { options, done })
+            .is_ok()
+        {
+            // The task never sends anything, it just drops the sender once it's stopped.
+            stopped.await.ok();
+        }
+    }
 }
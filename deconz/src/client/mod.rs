@@ -1,21 +1,112 @@
-use std::path::PathBuf;
+use std::time::Duration;
 
-use tokio::{sync::mpsc, task::JoinHandle};
-
-use self::{
-    handle::DeconzClientHandle,
-    task::{DeconzTask, TaskError},
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
 };
+pub use tokio_serial::FlowControl;
+
+use self::{handle::DeconzClientHandle, task::DeconzTask};
+use crate::capture::Capture;
 
+mod broadcast;
+mod capabilities;
+mod connection;
+mod events;
+pub(crate) mod formation;
 pub(crate) mod handle;
+pub(crate) mod network_state;
 mod queue;
+mod subscription;
 mod task;
+pub(crate) mod transport;
+mod watchdog;
+
+pub use broadcast::BroadcastLimit;
+pub use capabilities::DeviceCapabilities;
+pub use connection::{ConnectionState, ReconnectPolicy};
+pub use events::{DeconzEvent, FrameDirection, TappedFrame};
+pub use formation::{FormNetworkError, FormNetworkOptions, FormedNetwork};
+pub use network_state::{EnsureOnlineError, LEAVE_NETWORK_TIMEOUT};
+pub use subscription::{
+    ApsFilter, ApsSource, ApsSubscription, ApsSubscriptionOptions, OverflowPolicy,
+};
+pub use task::TaskError;
+pub use transport::{
+    detect_baud_rate, BaudRate, DeconzTransport, DetectedSerial, DEFAULT_BAUD_RATE_CANDIDATES,
+};
+pub use watchdog::{WatchdogEvent, WatchdogKeepAlive, WatchdogTtlError};
 
 /// Common configuration passed to the deCONZ client and used by the underlying task.
 #[derive(Clone)]
 pub struct DeconzClientConfig {
-    /// The path to a deCONZ-compatible device, like /dev/ttyUSB0.
-    pub device_path: PathBuf,
+    /// How to reach the deCONZ-compatible device, like a serial device at /dev/ttyUSB0.
+    pub transport: DeconzTransport,
+    /// The serial baud rate. Only used for [`DeconzTransport::Serial`].
+    pub baud_rate: BaudRate,
+    /// Serial flow control. Only used for [`DeconzTransport::Serial`].
+    pub flow_control: FlowControl,
+    /// The serial port timeout. Only used for [`DeconzTransport::Serial`].
+    pub timeout: Duration,
+    /// How long to wait for the device to respond to a command.
+    pub command_timeout: Duration,
+    /// How many times idempotent commands (like reading a parameter) are resent after timing out.
+    pub command_retries: u32,
+    /// How long [`DeconzClientHandle::send_data`] waits for the device to confirm a sent frame.
+    pub aps_confirm_timeout: Duration,
+    /// Paces broadcast and group sends, e.g. with `Some(BroadcastLimit::default())`. Off by
+    /// default, which sends them as fast as the device takes them.
+    pub broadcast_limit: Option<BroadcastLimit>,
+    /// Keeps the firmware watchdog of ConBee II and RaspBee II devices from resetting the radio.
+    /// Off by default.
+    pub watchdog: Option<WatchdogKeepAlive>,
+    /// How to reconnect after the connection to the device is lost. `None` ends the task instead.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Records the traffic with the device to a pcapng capture, for Wireshark. Off by default.
+    pub capture: Option<Capture>,
+}
+
+impl DeconzClientConfig {
+    /// Creates a config for the given transport, with the serial settings of the original ConBee
+    /// and RaspBee (38400 baud, no flow control).
+    pub fn new(transport: impl Into<DeconzTransport>) -> Self {
+        Self {
+            transport: transport.into(),
+            baud_rate: Default::default(),
+            flow_control: FlowControl::None,
+            timeout: Duration::from_secs(10),
+            command_timeout: Duration::from_secs(5),
+            command_retries: 0,
+            aps_confirm_timeout: Duration::from_secs(20),
+            broadcast_limit: None,
+            watchdog: None,
+            reconnect: Some(Default::default()),
+            capture: None,
+        }
+    }
+}
+
+/// How [`DeconzClientHandle::shutdown`] winds the client task down.
+#[derive(Debug, Clone)]
+pub struct ShutdownOptions {
+    /// Send the commands that are still enqueued before stopping. Otherwise they fail with
+    /// [`HandleError::Shutdown`](crate::HandleError::Shutdown). Commands that are already in
+    /// flight always get to finish.
+    pub drain: bool,
+    /// Take the network offline with a `ChangeNetworkState` command as the last thing before stopping.
+    pub take_network_offline: bool,
+    /// How long to wait for commands to finish, before failing them and stopping anyway.
+    pub timeout: Duration,
+}
+
+impl Default for ShutdownOptions {
+    fn default() -> Self {
+        Self {
+            drain: true,
+            take_network_offline: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
 }
 
 /// The deCONZ-protocol client, capable of connecting to a device and providing a means to communicate with it.
@@ -30,14 +121,608 @@ impl DeconzClient {
         Self { config }
     }
 
-    /// Starts a deCONZ task and returns a handle to it.
+    /// Starts a deCONZ task and returns a handle to it. The task runs until it's shut down through
+    /// [`DeconzClientHandle::shutdown`], every handle is dropped, or the connection is lost for good.
     pub fn start(self) -> (JoinHandle<Result<(), TaskError>>, DeconzClientHandle) {
         let (task_tx, task_rx) = mpsc::unbounded_channel();
-        let task = DeconzTask::new(self.config, task_rx);
+        let (connection_state_tx, connection_state_rx) =
+            watch::channel(ConnectionState::Connecting);
+        let (device_state_tx, device_state_rx) = watch::channel(None);
+        let (device_capabilities_tx, device_capabilities_rx) = watch::channel(None);
+        let task = DeconzTask::new(
+            self.config,
+            task_rx,
+            connection_state_tx,
+            device_state_tx,
+            device_capabilities_tx,
+        );
 
         // deconz task runner
         let task_joinhandle = tokio::spawn(task.run());
 
-        (task_joinhandle, DeconzClientHandle::new(task_tx))
+        (
+            task_joinhandle,
+            DeconzClientHandle::new(
+                task_tx,
+                connection_state_rx,
+                device_state_rx,
+                device_capabilities_rx,
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use bytes::{BufMut, BytesMut};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        emulator::DeconzEmulator,
+        frame::DeconzFrame,
+        protocol::{
+            aps::{
+                APSFramePayload, Broadcast, ConfirmStatus, DestinationAddress, MacStatus,
+                ReadReceivedDataResponse, SendData, SendDataOptions, SourceAddress,
+            },
+            device::{
+                ChangeNetworkState, FirmwareVersion, FirmwareVersionPlatform, ReadFirmwareVersion,
+            },
+            mac::MACPollIndication,
+            network_parameters::{
+                parameters, ReadNetworkPanId, WriteNetworkPanId, WriteWatchdogTtl,
+            },
+            CommandId, DeconzCommand, DeconzCommandRequest, NetworkState, StatusCode,
+        },
+        DeconzStream, HandleError,
+    };
+
+    /// Wraps a command to make it look like it needs a newer device than it does.
+    #[derive(Debug)]
+    struct Requiring<R> {
+        request: R,
+        min_protocol_version: Option<u16>,
+        min_firmware_version: Option<FirmwareVersion>,
+    }
+
+    impl<C: DeconzCommand> DeconzCommand for Requiring<C> {
+        type Request = Requiring<C::Request>;
+        type Response = C::Response;
+
+        fn into_request(self) -> Self::Request {
+            Requiring {
+                request: self.request.into_request(),
+                min_protocol_version: self.min_protocol_version,
+                min_firmware_version: self.min_firmware_version,
+            }
+        }
+    }
+
+    impl<R: DeconzCommandRequest> DeconzCommandRequest for Requiring<R> {
+        fn command_id(&self) -> CommandId {
+            self.request.command_id()
+        }
+
+        fn payload_data(&self) -> Option<BytesMut> {
+            self.request.payload_data()
+        }
+
+        fn min_protocol_version(&self) -> Option<u16> {
+            self.min_protocol_version
+        }
+
+        fn min_firmware_version(&self) -> Option<FirmwareVersion> {
+            self.min_firmware_version
+        }
+
+        fn set_aps_request_id(&mut self, request_id: u8) {
+            self.request.set_aps_request_id(request_id)
+        }
+    }
+
+    async fn wait_for_connection_state(
+        connection_state: &mut watch::Receiver<ConnectionState>,
+        predicate: impl Fn(&ConnectionState) -> bool,
+    ) {
+        while !predicate(&connection_state.borrow()) {
+            connection_state.changed().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_client_over_duplex() {
+        let (device_io, host_io) = tokio::io::duplex(1024);
+        let (_task, mut handle) = DeconzClient::new(DeconzClientConfig::new(
+            DeconzTransport::from_stream(host_io),
+        ))
+        .start();
+
+        tokio::spawn(async move {
+            let mut device = DeconzStream::new(device_io);
+            while let Some(Ok(frame)) = device.next_frame().await {
+                let mut payload = BytesMut::new();
+                match frame.command_id() {
+                    CommandId::DeviceState => payload.put_u8(0x00),
+                    CommandId::Version => payload.put_u32_le(0x26720700),
+                    _ => continue,
+                }
+                let response =
+                    DeconzFrame::new(frame.command_id(), frame.sequence_id(), Some(payload));
+                device.write_frame(response).await.unwrap();
+            }
+        });
+
+        let version = handle
+            .send_command(ReadFirmwareVersion::new())
+            .await
+            .unwrap();
+        assert_eq!(version.major_version, 0x26);
+        assert_eq!(version.minor_version, 0x72);
+    }
+
+    #[tokio::test]
+    pub async fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let emulator = DeconzEmulator::new();
+        let (connections_tx, mut connections) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let connection = tokio::spawn(emulator.clone().run(stream));
+                connections_tx.send(connection).ok();
+            }
+        });
+
+        let config = DeconzClientConfig {
+            reconnect: Some(ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                ..Default::default()
+            }),
+            ..DeconzClientConfig::new(DeconzTransport::Tcp(address.to_string()))
+        };
+        let (_task, mut handle) = DeconzClient::new(config).start();
+        let mut connection_state = handle.connection_state();
+
+        handle
+            .send_command(ReadFirmwareVersion::new())
+            .await
+            .unwrap();
+        assert!(connection_state.borrow().is_connected());
+
+        // Pull the plug.
+        connections.recv().await.unwrap().abort();
+        wait_for_connection_state(&mut connection_state, |state| {
+            matches!(state, ConnectionState::Reconnecting { attempt: 1, .. })
+        })
+        .await;
+        wait_for_connection_state(&mut connection_state, ConnectionState::is_connected).await;
+
+        let version = handle
+            .send_command(ReadFirmwareVersion::new())
+            .await
+            .unwrap();
+        assert_eq!(version.major_version, 0x26);
+    }
+
+    #[tokio::test]
+    pub async fn test_status_code_error() {
+        let emulator = DeconzEmulator::new();
+        emulator.set_read_only::<parameters::NetworkPanId>();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        let result = handle.send_command(WriteNetworkPanId::new(0x4321)).await;
+        assert!(matches!(
+            result,
+            Err(HandleError::Status(StatusCode::Unsupported))
+        ));
+        let pan_id = handle.send_command(ReadNetworkPanId::new()).await.unwrap();
+        assert_eq!(*pan_id.value, 0x1A62);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_command_timeout_and_retry() {
+        let emulator = DeconzEmulator::new();
+        let config = DeconzClientConfig {
+            command_retries: 1,
+            ..DeconzClientConfig::new(emulator.transport())
+        };
+        let (_task, mut handle) = DeconzClient::new(config).start();
+        handle
+            .send_command(ReadFirmwareVersion::new())
+            .await
+            .unwrap();
+
+        // Reads are idempotent, so the lost request is retried.
+        emulator.ignore_requests(1);
+        let pan_id = handle.send_command(ReadNetworkPanId::new()).await.unwrap();
+        assert_eq!(*pan_id.value, 0x1A62);
+
+        // Writes are not.
+        emulator.ignore_requests(1);
+        let result = handle
+            .send_command_with_timeout(WriteNetworkPanId::new(0x4321), Duration::from_secs(1))
+            .await;
+        assert!(matches!(result, Err(HandleError::Timeout)));
+    }
+
+    fn send_data(cluster_id: u16) -> SendData {
+        SendData {
+            destination_address: DestinationAddress::NetworkAddress(0x1234),
+            destination_endpoint: 1,
+            profile_id: 0x0104,
+            cluster_id,
+            source_endpoint: 1,
+            payload: APSFramePayload::from_vec(vec![0x01, 0x00, 0x02]).unwrap(),
+            options: SendDataOptions::default(),
+            radius: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_cancel_enqueued_aps_data_requests() {
+        let emulator = DeconzEmulator::new();
+        emulator.set_aps_data_request_slots(0);
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        // With the slots full, both requests wait in the queue until they're given up on.
+        let dropped = tokio::time::timeout(
+            Duration::from_secs(1),
+            handle.send_command(send_data(0x0001)),
+        )
+        .await;
+        assert!(dropped.is_err());
+        let (cancel_handle, cancelled) = handle.send_command_cancellable(send_data(0x0002));
+        cancel_handle.cancel();
+        assert!(matches!(cancelled.await, Err(HandleError::Cancelled)));
+
+        emulator.set_aps_data_request_slots(4);
+        handle.send_command(send_data(0x0003)).await.unwrap();
+        let requests = emulator.aps_data_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].cluster_id, 0x0003);
+    }
+
+    #[tokio::test]
+    pub async fn test_send_data_confirm() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        let confirm = handle.send_data(send_data(0x0001)).await.unwrap();
+        assert!(confirm.confirm_status.is_success());
+
+        emulator.set_aps_confirm_status(ConfirmStatus::Mac(MacStatus::NoAck));
+        let confirm = handle.send_data(send_data(0x0002)).await.unwrap();
+        assert_eq!(confirm.confirm_status, ConfirmStatus::Mac(MacStatus::NoAck));
+
+        // Each request got its own id, which its confirm carries.
+        let requests = emulator.aps_data_requests();
+        assert_eq!(requests.len(), 2);
+        assert_ne!(requests[0].request_id, requests[1].request_id);
+        assert_eq!(confirm.request_id, requests[1].request_id);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_broadcast_limit() {
+        let emulator = DeconzEmulator::new();
+        let mut config = DeconzClientConfig::new(emulator.transport());
+        config.broadcast_limit = Some(BroadcastLimit {
+            max_broadcasts: 2,
+            window: Duration::from_secs(5),
+        });
+        let (_task, mut handle) = DeconzClient::new(config).start();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        let broadcast = |cluster_id| SendData {
+            destination_address: Broadcast::RxOnWhenIdle.into(),
+            ..send_data(cluster_id)
+        };
+        let start = tokio::time::Instant::now();
+        handle.send_command(broadcast(0x0001)).await.unwrap();
+        handle.send_command(broadcast(0x0002)).await.unwrap();
+
+        // The third broadcast is held back until the first leaves the window, unicasts aren't.
+        let (_, held) = handle.send_command_cancellable(broadcast(0x0003));
+        handle.send_command(send_data(0x0004)).await.unwrap();
+        held.await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(5));
+
+        let cluster_ids: Vec<u16> = emulator
+            .aps_data_requests()
+            .iter()
+            .map(|request| request.cluster_id)
+            .collect();
+        assert_eq!(cluster_ids, [0x0001, 0x0002, 0x0004, 0x0003]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_zero_broadcast_limit() {
+        let emulator = DeconzEmulator::new();
+        let mut config = DeconzClientConfig::new(emulator.transport());
+        config.broadcast_limit = Some(BroadcastLimit {
+            max_broadcasts: 0,
+            window: Duration::from_secs(5),
+        });
+        let (_task, mut handle) = DeconzClient::new(config).start();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        // Taken as a budget of one, so the broadcast still goes out.
+        let broadcast = SendData {
+            destination_address: Broadcast::RxOnWhenIdle.into(),
+            ..send_data(0x0001)
+        };
+        tokio::time::timeout(Duration::from_secs(1), handle.send_command(broadcast))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(emulator.aps_data_requests().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_events() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        let mut events = handle.subscribe_events().await.unwrap();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        let confirm = handle.send_data(send_data(0x0001)).await.unwrap();
+        emulator.inject_mac_poll_indication(MACPollIndication {
+            source_address: SourceAddress::NetworkAddress(0x1234),
+            link_quality_indicator: 255,
+            received_signal_strength_indication: -40,
+            neighbor_table_state: None,
+        });
+
+        let mut connected = false;
+        let mut confirmed = false;
+        loop {
+            match events.recv().await.unwrap() {
+                DeconzEvent::DeviceStateChanged(device_state) => {
+                    connected |= device_state.network_state == NetworkState::NetConnected;
+                }
+                DeconzEvent::ApsDataConfirm(event) => {
+                    confirmed |= event.request_id == confirm.request_id;
+                }
+                DeconzEvent::MacPoll(poll) => {
+                    assert_eq!(poll.source_address.unwrap_network_address(), 0x1234);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert!(connected);
+        assert!(confirmed);
+    }
+
+    #[tokio::test]
+    pub async fn test_subscribe_aps() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        let mut on_off = handle
+            .subscribe_aps(ApsFilter {
+                cluster_id: Some(0x0006),
+                source: Some(ApsSource::NetworkAddress(0x1234)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        for (cluster_id, source_address) in [(0x0008, 0x1234), (0x0006, 0x5678), (0x0006, 0x1234)] {
+            emulator.inject_aps_data_indication(ReadReceivedDataResponse {
+                device_state: 0.into(),
+                destination_address: DestinationAddress::NetworkAddress(0x0000),
+                destination_endpoint: 1,
+                source_address: SourceAddress::NetworkAddress(source_address),
+                source_endpoint: 1,
+                profile_id: 0x0104,
+                cluster_id,
+                application_specific_data_unit: vec![],
+                link_quality_indication: 255,
+                received_signal_strength_indication: -40,
+            });
+        }
+
+        let indication = on_off.recv().await.unwrap();
+        assert_eq!(indication.cluster_id, 0x0006);
+        assert_eq!(indication.source_address.unwrap_network_address(), 0x1234);
+        assert_eq!(on_off.dropped(), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_shutdown() {
+        let emulator = DeconzEmulator::new();
+        emulator.set_aps_data_request_slots(0);
+        let (task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        let (_, stuck) = handle.send_command_cancellable(send_data(0x0001));
+        handle
+            .shutdown(ShutdownOptions {
+                drain: false,
+                take_network_offline: true,
+                ..Default::default()
+            })
+            .await;
+
+        task.await.unwrap().unwrap();
+        assert!(matches!(stuck.await, Err(HandleError::Shutdown)));
+        assert_eq!(emulator.network_state(), NetworkState::NetOffline);
+        assert!(matches!(
+            handle.send_command(ReadFirmwareVersion::new()).await,
+            Err(HandleError::TaskFailure)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_shutdown_on_last_handle_dropped() {
+        let emulator = DeconzEmulator::new();
+        let (task, handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        drop(handle);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_watchdog_keep_alive() {
+        let emulator = DeconzEmulator::new();
+        let config = DeconzClientConfig {
+            watchdog: Some(WatchdogKeepAlive::new(Duration::from_secs(60)).unwrap()),
+            ..DeconzClientConfig::new(emulator.transport())
+        };
+        let (_task, mut handle) = DeconzClient::new(config).start();
+        let mut events = handle.subscribe_watchdog_events().await.unwrap();
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            WatchdogEvent::Refreshed { .. }
+        ));
+        assert_eq!(
+            *emulator.parameter::<parameters::WatchdogTtl>().unwrap(),
+            Duration::from_secs(60)
+        );
+
+        emulator.set_read_only::<parameters::WatchdogTtl>();
+        match events.recv().await.unwrap() {
+            WatchdogEvent::RefreshFailed(e) => {
+                assert!(matches!(*e, HandleError::Status(StatusCode::Unsupported)))
+            }
+            event => panic!("unexpected watchdog event {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_device_capabilities() {
+        let emulator = DeconzEmulator::new();
+        emulator.set_parameter(parameters::ProtocolVersion::from(0x0107));
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        let mut device_capabilities = handle.device_capabilities();
+        while device_capabilities.borrow().is_none() {
+            device_capabilities.changed().await.unwrap();
+        }
+        let device_capabilities = device_capabilities.borrow().unwrap();
+        assert_eq!(
+            device_capabilities.firmware_version.to_string(),
+            "0x26720700"
+        );
+        assert_eq!(
+            device_capabilities.platform,
+            FirmwareVersionPlatform::ArmR21
+        );
+        assert_eq!(device_capabilities.protocol_version, 0x0107);
+
+        let result = handle
+            .send_command(WriteWatchdogTtl::new(Duration::from_secs(60)))
+            .await;
+        assert!(matches!(
+            result,
+            Err(HandleError::Unsupported {
+                required: 0x0108,
+                protocol_version: 0x0107
+            })
+        ));
+        assert_eq!(
+            *emulator.parameter::<parameters::WatchdogTtl>().unwrap(),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_unsupported_aps_data_request() {
+        let emulator = DeconzEmulator::new();
+        emulator.set_parameter(parameters::ProtocolVersion::from(0x0107));
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+
+        let result = handle
+            .send_command(Requiring {
+                request: send_data(0x0001),
+                min_protocol_version: Some(0x0108),
+                min_firmware_version: None,
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(HandleError::Unsupported {
+                required: 0x0108,
+                protocol_version: 0x0107
+            })
+        ));
+
+        // Data requests the device does understand still go out.
+        handle.send_command(send_data(0x0002)).await.unwrap();
+        let requests = emulator.aps_data_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].cluster_id, 0x0002);
+    }
+
+    #[tokio::test]
+    pub async fn test_unsupported_firmware() {
+        let emulator = DeconzEmulator::new();
+        emulator.set_firmware_version(0x26720700);
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        let result = handle
+            .send_command(Requiring {
+                request: ReadFirmwareVersion::new(),
+                min_protocol_version: None,
+                min_firmware_version: Some(FirmwareVersion(0x26780700)),
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(HandleError::UnsupportedFirmware {
+                required: FirmwareVersion(0x26780700),
+                firmware_version: FirmwareVersion(0x26720700),
+            })
+        ));
+
+        // The platform doesn't count, only the major and minor version.
+        let response = handle
+            .send_command(Requiring {
+                request: ReadFirmwareVersion::new(),
+                min_protocol_version: None,
+                min_firmware_version: Some(FirmwareVersion(0x26720500)),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.firmware_version(), FirmwareVersion(0x26720700));
     }
 }
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::time::Instant;
+
+use super::handle::{DeconzClientHandle, HandleError};
+use crate::protocol::{
+    device::{ChangeNetworkState, DeviceState, ReadDeviceState},
+    NetworkState,
+};
+
+/// How often the device state is read while waiting on it, in case the device doesn't report
+/// the change by itself.
+const DEVICE_STATE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait for the device to leave the network before writing to it.
+pub const LEAVE_NETWORK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum EnsureOnlineError {
+    #[error("failed to talk to the device: {0}")]
+    Handle(#[from] HandleError),
+    #[error("the device went back offline instead of joining the network")]
+    JoinFailed,
+    #[error("the network did not come up in time")]
+    Timeout,
+}
+
+impl DeconzClientHandle {
+    /// Asks the device to go online or offline, then waits until it got there. Returns `false`
+    /// if it didn't within the timeout.
+    pub async fn set_network_state(
+        &mut self,
+        network_state: NetworkState,
+        timeout: Duration,
+    ) -> Result<bool, HandleError> {
+        self.send_command(ChangeNetworkState::new(network_state))
+            .await?;
+        self.wait_for_network_state(network_state, timeout).await
+    }
+
+    /// Waits for the device to report the given network state. Returns `false` if it didn't
+    /// within the timeout.
+    pub async fn wait_for_network_state(
+        &mut self,
+        network_state: NetworkState,
+        timeout: Duration,
+    ) -> Result<bool, HandleError> {
+        let deadline = Instant::now() + timeout;
+        let device_state = self
+            .wait_for_device_state(deadline, |device_state| {
+                device_state.network_state == network_state
+            })
+            .await?;
+        Ok(device_state.is_some())
+    }
+
+    /// Brings the network up, unless it already is. The device passes through the joining state
+    /// on its way, and falls back to offline if it can't join (or form) the network after all.
+    pub async fn ensure_online(&mut self, timeout: Duration) -> Result<(), EnsureOnlineError> {
+        let deadline = Instant::now() + timeout;
+        let device_state = self
+            .send_command(ReadDeviceState::new())
+            .await?
+            .device_state;
+        if device_state.network_state == NetworkState::NetConnected {
+            return Ok(());
+        }
+
+        self.send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await?;
+        // Until the device starts joining, it may still be reported offline from before.
+        let mut joining = false;
+        let device_state = self
+            .wait_for_device_state(deadline, |device_state| match device_state.network_state {
+                NetworkState::NetConnected => true,
+                NetworkState::NetJoining => {
+                    joining = true;
+                    false
+                }
+                NetworkState::NetOffline => joining,
+                NetworkState::NetLeaving => false,
+            })
+            .await?;
+
+        match device_state.map(|device_state| device_state.network_state) {
+            Some(NetworkState::NetConnected) => Ok(()),
+            Some(_) => Err(EnsureOnlineError::JoinFailed),
+            None => Err(EnsureOnlineError::Timeout),
+        }
+    }
+
+    /// Watches the device state until the predicate holds, returning the state it held for.
+    /// Returns `None` once past the deadline.
+    async fn wait_for_device_state(
+        &mut self,
+        deadline: Instant,
+        mut predicate: impl FnMut(&DeviceState) -> bool,
+    ) -> Result<Option<DeviceState>, HandleError> {
+        let mut device_state = self.device_state();
+        loop {
+            let current = *device_state.borrow();
+            if let Some(current) = current.filter(|current| predicate(current)) {
+                return Ok(Some(current));
+            }
+
+            tokio::select! {
+                changed = device_state.changed() => {
+                    changed.map_err(|_| HandleError::TaskFailure)?;
+                }
+                _ = tokio::time::sleep(DEVICE_STATE_POLL_INTERVAL) => {
+                    // The response updates the watched state.
+                    self.send_command(ReadDeviceState::new()).await?;
+                }
+                _ = tokio::time::sleep_until(deadline) => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{emulator::DeconzEmulator, DeconzClient, DeconzClientConfig};
+
+    #[tokio::test]
+    pub async fn test_ensure_online() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        handle.ensure_online(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(
+            handle.device_state().borrow().unwrap().network_state,
+            NetworkState::NetConnected
+        );
+
+        assert!(handle
+            .set_network_state(NetworkState::NetOffline, Duration::from_secs(5))
+            .await
+            .unwrap());
+        emulator.stall_joins(true);
+        let ensure_online = {
+            let mut handle = handle.clone();
+            tokio::spawn(async move { handle.ensure_online(Duration::from_secs(5)).await })
+        };
+        assert!(handle
+            .wait_for_network_state(NetworkState::NetJoining, Duration::from_secs(5))
+            .await
+            .unwrap());
+        emulator.set_network_state(NetworkState::NetOffline);
+        assert!(matches!(
+            ensure_online.await.unwrap(),
+            Err(EnsureOnlineError::JoinFailed)
+        ));
+    }
+}
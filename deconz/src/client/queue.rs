@@ -1,20 +1,40 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
-use bytes::{Buf, Bytes};
-use tokio::sync::broadcast;
-use tokio_serial::SerialStream;
-use tracing::info;
+use bytes::Bytes;
+use tokio::{
+    sync::{broadcast, oneshot, watch},
+    time::Instant,
+};
+use tracing::{info, warn};
 
+use super::{
+    broadcast::{BroadcastLimit, BroadcastLimiter},
+    capabilities::DeviceCapabilities,
+    events::{DeconzEvent, FrameDirection, TappedFrame},
+    handle::{CommandToken, HandleError},
+    subscription::ApsSubscriptions,
+    transport::BoxedTransportIo,
+    watchdog::WatchdogEvent,
+};
 use crate::{
+    frame::{ProtocolError, TryBuf},
     protocol::{
         aps::{
             ReadConfirmData, ReadConfirmDataResponse, ReadReceivedData, ReadReceivedDataResponse,
         },
-        device::{DeviceState, ReadDeviceState, ReadDeviceStateResponse},
+        device::{
+            DeviceState, FirmwareVersion, ReadDeviceState, ReadDeviceStateResponse,
+            ReadFirmwareVersion, ReadFirmwareVersionResponse,
+        },
         mac::{MACBeaconIndication, MACPollIndication},
-        CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse,
+        network_parameters::{parameters, ReadParameterResponse, ReadProtocolVersion},
+        CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse, StatusCode,
     },
-    DeconzFrame, DeconzStream,
+    DeconzFrame, DeconzStream, DeconzStreamError,
 };
 
 const MAX_IN_FLIGHT_COMMANDS: usize = 16;
@@ -22,17 +42,44 @@ const MAX_IN_FLIGHT_COMMANDS: usize = 16;
 struct EnqueuedCommand {
     command_request: Box<dyn DeconzCommandRequest>,
     in_flight_command: InFlightCommand,
+    timeout: Duration,
+    retries_left: u32,
+    command_token: Option<CommandToken>,
+    /// Where the confirm goes, for APS data requests sent through [`DeconzClientHandle::send_data`].
+    ///
+    /// [`DeconzClientHandle::send_data`]: super::handle::DeconzClientHandle::send_data
+    aps_confirm: Option<ApsConfirmSender>,
+    /// Allocated when an APS data request is sent.
+    aps_request_id: Option<u8>,
 }
 
 impl EnqueuedCommand {
-    fn new_internal<T: DeconzCommand>(command: T) -> Self {
+    /// Internal commands are never retried, as they're sent again whenever the device state calls for it.
+    fn new_internal<T: DeconzCommand>(command: T, timeout: Duration) -> Self {
         Self {
             command_request: command.into_boxed_request(),
             in_flight_command: InFlightCommand::Internal,
+            timeout,
+            retries_left: 0,
+            command_token: None,
+            aps_confirm: None,
+            aps_request_id: None,
         }
     }
 }
 
+/// An APS data request that the device accepted, awaiting its confirm.
+struct PendingApsConfirm {
+    sender: ApsConfirmSender,
+    deadline: Instant,
+}
+
+/// A command that was written to the device, and is awaiting its response.
+struct SentCommand {
+    enqueued_command: EnqueuedCommand,
+    deadline: Instant,
+}
+
 enum ApsDataRequestStatus {
     /// We don't have confirmation yet that the device has additional aps data request slots available.
     ///
@@ -46,6 +93,14 @@ enum ApsDataRequestStatus {
     SlotsFull,
 }
 
+/// Whether the connected device can handle a command, going by what it reported on connect.
+enum CommandSupport {
+    /// The command has requirements, but the device hasn't told us yet what it runs.
+    Unknown,
+    Supported,
+    Unsupported(HandleError),
+}
+
 impl ApsDataRequestStatus {
     /// Returns `true` if the aps_data_request_status is [`SlotsAvailable`].
     fn has_slots_available(&self) -> bool {
@@ -55,16 +110,49 @@ impl ApsDataRequestStatus {
 
 pub(crate) struct DeconzBroadcastChannels {
     aps_data_indication: broadcast::Sender<ReadReceivedDataResponse>,
+    pub(crate) watchdog: broadcast::Sender<WatchdogEvent>,
+    events: broadcast::Sender<DeconzEvent>,
+    frames: broadcast::Sender<TappedFrame>,
 }
 
 impl DeconzBroadcastChannels {
     fn new() -> Self {
         let (aps_data_indication, _) = broadcast::channel(128);
+        let (watchdog, _) = broadcast::channel(16);
+        let (events, _) = broadcast::channel(128);
+        let (frames, _) = broadcast::channel(128);
         Self {
             aps_data_indication,
+            watchdog,
+            events,
+            frames,
         }
     }
 
+    pub(crate) fn subscribe_frames(&self) -> broadcast::Receiver<TappedFrame> {
+        self.frames.subscribe()
+    }
+
+    /// Copies the frame to the frame subscribers, if there are any.
+    fn tap_frame(&self, direction: FrameDirection, frame: impl FnOnce() -> DeconzFrame<Bytes>) {
+        if self.frames.receiver_count() > 0 {
+            self.frames
+                .send(TappedFrame {
+                    direction,
+                    frame: frame(),
+                })
+                .ok();
+        }
+    }
+
+    pub(crate) fn subscribe_events(&self) -> broadcast::Receiver<DeconzEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn broadcast_event(&self, event: DeconzEvent) {
+        self.events.send(event).ok();
+    }
+
     pub(crate) fn subscribe_aps_data_indication(
         &self,
     ) -> broadcast::Receiver<ReadReceivedDataResponse> {
@@ -72,6 +160,7 @@ impl DeconzBroadcastChannels {
     }
 
     fn broadcast_aps_data_indication(&self, data: ReadReceivedDataResponse) {
+        self.broadcast_event(DeconzEvent::ApsDataIndication(data.clone()));
         self.aps_data_indication.send(data).ok();
     }
 }
@@ -79,23 +168,54 @@ impl DeconzBroadcastChannels {
 pub(crate) struct DeconzQueue {
     next_sequence_id: u8,
     device_state: Option<DeviceState>,
+    /// The device state as published to handles. Unlike `device_state`, it's kept when the
+    /// flags go stale, until the connection is lost.
+    published_device_state: watch::Sender<Option<DeviceState>>,
+    firmware_version: Option<ReadFirmwareVersionResponse>,
+    protocol_version: Option<u16>,
+    device_capabilities: watch::Sender<Option<DeviceCapabilities>>,
     enqueued_commands: VecDeque<EnqueuedCommand>,
     enqueued_aps_data_request_commands: VecDeque<EnqueuedCommand>,
-    in_flight_commands: HashMap<CommandId, HashMap<u8, InFlightCommand>>,
+    in_flight_commands: HashMap<CommandId, HashMap<u8, SentCommand>>,
     aps_data_request_status: ApsDataRequestStatus,
+    next_aps_request_id: u8,
+    pending_aps_confirms: HashMap<u8, PendingApsConfirm>,
+    broadcast_limiter: BroadcastLimiter,
+    command_timeout: Duration,
+    command_retries: u32,
+    aps_confirm_timeout: Duration,
     pub(crate) broadcast_channels: DeconzBroadcastChannels,
+    pub(crate) aps_subscriptions: ApsSubscriptions,
 }
 
 impl DeconzQueue {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        command_timeout: Duration,
+        command_retries: u32,
+        aps_confirm_timeout: Duration,
+        broadcast_limit: Option<BroadcastLimit>,
+        published_device_state: watch::Sender<Option<DeviceState>>,
+        device_capabilities: watch::Sender<Option<DeviceCapabilities>>,
+    ) -> Self {
         Self {
             next_sequence_id: 0,
+            command_timeout,
+            command_retries,
+            aps_confirm_timeout,
             device_state: None,
+            published_device_state,
+            firmware_version: None,
+            protocol_version: None,
+            device_capabilities,
             aps_data_request_status: ApsDataRequestStatus::PendingNextDeviceUpdate,
+            next_aps_request_id: 0,
+            pending_aps_confirms: Default::default(),
+            broadcast_limiter: BroadcastLimiter::new(broadcast_limit),
             enqueued_commands: Default::default(),
             enqueued_aps_data_request_commands: Default::default(),
             in_flight_commands: Default::default(),
             broadcast_channels: DeconzBroadcastChannels::new(),
+            aps_subscriptions: ApsSubscriptions::new(),
         }
     }
 
@@ -103,8 +223,72 @@ impl DeconzQueue {
         &mut self,
         command_request: Box<dyn DeconzCommandRequest>,
         in_flight_command: InFlightCommand,
+        timeout: Option<Duration>,
+        command_token: CommandToken,
+        aps_confirm: Option<ApsConfirmSender>,
     ) {
-        let command_id = command_request.command_id();
+        let retries_left = match command_request.is_idempotent() {
+            true => self.command_retries,
+            false => 0,
+        };
+        self.push_enqueued_command(EnqueuedCommand {
+            command_request,
+            in_flight_command,
+            timeout: timeout.unwrap_or(self.command_timeout),
+            retries_left,
+            command_token: Some(command_token),
+            aps_confirm,
+            aps_request_id: None,
+        });
+    }
+
+    /// The firmware version of the connected device, once it has been read.
+    pub(crate) fn firmware_version(&self) -> Option<ReadFirmwareVersionResponse> {
+        self.firmware_version
+    }
+
+    /// Publishes the device capabilities once both the firmware and protocol version are known.
+    fn update_device_capabilities(&mut self) {
+        let device_capabilities = match (self.firmware_version, self.protocol_version) {
+            (Some(firmware_version), Some(protocol_version)) => DeviceCapabilities {
+                firmware_version: firmware_version.firmware_version(),
+                platform: firmware_version.platform,
+                protocol_version,
+            },
+            _ => return,
+        };
+        info!("device capabilities: {:?}", device_capabilities);
+        self.device_capabilities
+            .send_replace(Some(device_capabilities));
+    }
+
+    /// Removes a command that hasn't been sent yet from the queue. Commands that are already in
+    /// flight are left alone, their response just won't have anyone listening for it.
+    pub(crate) fn cancel_command(&mut self, command_token: CommandToken) {
+        for queue in [
+            &mut self.enqueued_commands,
+            &mut self.enqueued_aps_data_request_commands,
+        ] {
+            let position = queue
+                .iter()
+                .position(|enqueued_command| enqueued_command.command_token == Some(command_token));
+            if let Some(enqueued_command) = position.and_then(|position| queue.remove(position)) {
+                info!(
+                    "cancelled enqueued {:?} command",
+                    enqueued_command.command_request.command_id()
+                );
+                if let InFlightCommand::External { response_parser } =
+                    enqueued_command.in_flight_command
+                {
+                    (response_parser)(Err(HandleError::Cancelled));
+                }
+                return;
+            }
+        }
+    }
+
+    fn push_enqueued_command(&mut self, enqueued_command: EnqueuedCommand) {
+        let command_id = enqueued_command.command_request.command_id();
         // We split between two queues here, apsd commands go to their own queue, whos consumption
         // is regulated by the device state and [`aps_data_request_status`]. Any other commands,
         // go through a regular queue that is regulated by a maximum outstanding concurrency
@@ -114,10 +298,7 @@ impl DeconzQueue {
             _ => &mut self.enqueued_commands,
         };
 
-        queue.push_back(EnqueuedCommand {
-            command_request,
-            in_flight_command,
-        });
+        queue.push_back(enqueued_command);
     }
 
     pub(crate) fn update_device_state(&mut self, device_state: DeviceState) {
@@ -129,6 +310,11 @@ impl DeconzQueue {
 
         info!("device state updated to {:?}", device_state);
         self.device_state = Some(device_state);
+        if *self.published_device_state.borrow() != Some(device_state) {
+            self.published_device_state.send_replace(Some(device_state));
+            self.broadcast_channels
+                .broadcast_event(DeconzEvent::DeviceStateChanged(device_state));
+        }
     }
 
     fn has_in_flight_command_for_command_id(&self, command_id: CommandId) -> bool {
@@ -154,102 +340,386 @@ impl DeconzQueue {
         &mut self,
         command_id: CommandId,
         sequence_id: u8,
-    ) -> Option<InFlightCommand> {
+    ) -> Option<EnqueuedCommand> {
         match self.in_flight_commands.get_mut(&command_id) {
-            Some(in_flight) => in_flight.remove(&sequence_id),
+            Some(in_flight) => in_flight
+                .remove(&sequence_id)
+                .map(|sent_command| sent_command.enqueued_command),
             None => None,
         }
     }
 
-    pub(crate) async fn try_io(&mut self, deconz_stream: &mut DeconzStream<SerialStream>) {
+    /// Stops waiting for the confirm of an APS data request that the device didn't accept.
+    fn forget_aps_confirm(&mut self, enqueued_command: &EnqueuedCommand) {
+        if let Some(request_id) = enqueued_command.aps_request_id {
+            self.pending_aps_confirms.remove(&request_id);
+        }
+    }
+
+    fn is_sequence_id_in_flight(&self, sequence_id: u8) -> bool {
+        self.in_flight_commands
+            .values()
+            .any(|in_flight| in_flight.contains_key(&sequence_id))
+    }
+
+    /// Returns when the next in-flight command times out, if any are in flight, or when a
+    /// broadcast held back by the [`BroadcastLimit`] can be sent.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let next_broadcast = match self
+            .enqueued_aps_data_request_commands
+            .iter()
+            .any(|enqueued_command| enqueued_command.command_request.is_broadcast())
+        {
+            true => self.broadcast_limiter.next_budget(Instant::now()),
+            false => None,
+        };
+        self.in_flight_commands
+            .values()
+            .flat_map(|in_flight| in_flight.values())
+            .map(|sent_command| sent_command.deadline)
+            .chain(
+                self.pending_aps_confirms
+                    .values()
+                    .map(|pending_confirm| pending_confirm.deadline),
+            )
+            .chain(next_broadcast)
+            .min()
+    }
+
+    /// Frees the slots of in-flight commands that the device didn't answer in time. Commands with
+    /// retries left are put back at the front of the queue, the others fail with a timeout.
+    pub(crate) fn expire_in_flight_commands(&mut self) {
+        let now = Instant::now();
+        self.expire_aps_confirms(now);
+
+        let mut expired = Vec::new();
+        for in_flight in self.in_flight_commands.values_mut() {
+            let sequence_ids: Vec<u8> = in_flight
+                .iter()
+                .filter(|(_, sent_command)| sent_command.deadline <= now)
+                .map(|(sequence_id, _)| *sequence_id)
+                .collect();
+            for sequence_id in sequence_ids {
+                expired.extend(in_flight.remove(&sequence_id));
+            }
+        }
+        if expired.is_empty() {
+            return;
+        }
+
+        // Whatever we missed may have changed the device state (e.g. the aps data request slots),
+        // so read it again.
+        self.device_state = None;
+
+        for SentCommand {
+            mut enqueued_command,
+            ..
+        } in expired
+        {
+            self.forget_aps_confirm(&enqueued_command);
+            let command_id = enqueued_command.command_request.command_id();
+            if enqueued_command.retries_left > 0 {
+                enqueued_command.retries_left -= 1;
+                warn!("{:?} command timed out, retrying", command_id);
+                self.enqueued_commands.push_front(enqueued_command);
+                continue;
+            }
+
+            warn!("{:?} command timed out", command_id);
+            if let InFlightCommand::External { response_parser } =
+                enqueued_command.in_flight_command
+            {
+                (response_parser)(Err(HandleError::Timeout));
+            }
+        }
+    }
+
+    /// Fails the APS data requests whose confirm is overdue. Confirms come in late when the
+    /// frame needed route discovery or retries, but not never.
+    fn expire_aps_confirms(&mut self, now: Instant) {
+        let request_ids: Vec<u8> = self
+            .pending_aps_confirms
+            .iter()
+            .filter(|(_, pending_confirm)| pending_confirm.deadline <= now)
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        for request_id in request_ids {
+            warn!("aps data request {} was never confirmed", request_id);
+            if let Some(pending_confirm) = self.pending_aps_confirms.remove(&request_id) {
+                pending_confirm.sender.send(Err(HandleError::Timeout)).ok();
+            }
+        }
+    }
+
+    pub(crate) async fn try_io(
+        &mut self,
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), Arc<DeconzStreamError>> {
         // If we have not received any device state yet, then we should request one.
         let device_state = match self.device_state {
             Some(ds) => ds,
             None => return self.send_device_state_request(deconz_stream).await,
         };
 
+        // Whether some commands can be sent at all depends on the device, so find out early on.
+        self.send_device_capabilities_requests(deconz_stream)
+            .await?;
+
         // Only process apsde commands when we are connected to the network.
         if device_state.network_state.is_connected() {
-            if device_state.apsde_data_indication {
+            // Backpressured subscribers keep frames waiting on the device until they catch up.
+            if device_state.apsde_data_indication && !self.aps_subscriptions.is_blocked() {
                 self.send_aps_data_indication_read_request(deconz_stream)
-                    .await;
+                    .await?;
             }
 
             if device_state.apsde_data_confirm {
-                self.send_aps_data_confirm_read_request(deconz_stream).await;
+                self.send_aps_data_confirm_read_request(deconz_stream)
+                    .await?;
             }
 
-            self.try_send_aps_data_request(deconz_stream).await;
+            self.try_send_aps_data_request(deconz_stream).await?;
         }
 
         // Dequeue commands if we don't have too many in-flight requests.
         while !self.in_flight_commands_full() {
-            let enqueued_command = match self.enqueued_commands.pop_front() {
-                Some(enqueued_command) => enqueued_command,
+            let command_support = match self.enqueued_commands.front() {
+                Some(enqueued_command) => self.command_support(&*enqueued_command.command_request),
                 None => break,
             };
+            let enqueued_command = match command_support {
+                // Hold the queue until we know whether the device understands the command.
+                CommandSupport::Unknown => break,
+                CommandSupport::Unsupported(error) => {
+                    if let Some(enqueued_command) = self.enqueued_commands.pop_front() {
+                        Self::refuse_command(enqueued_command, error);
+                    }
+                    continue;
+                }
+                CommandSupport::Supported => match self.enqueued_commands.pop_front() {
+                    Some(enqueued_command) => enqueued_command,
+                    None => break,
+                },
+            };
+
+            self.send_command(enqueued_command, deconz_stream).await?;
+        }
+
+        Ok(())
+    }
+
+    fn command_support(&self, command_request: &dyn DeconzCommandRequest) -> CommandSupport {
+        let firmware_version = self.firmware_version.map(FirmwareVersion::from);
+        match (command_request.min_firmware_version(), firmware_version) {
+            (None, _) => {}
+            (Some(_), None) => return CommandSupport::Unknown,
+            (Some(required), Some(firmware_version)) if !firmware_version.is_at_least(required) => {
+                return CommandSupport::Unsupported(HandleError::UnsupportedFirmware {
+                    required,
+                    firmware_version,
+                })
+            }
+            (Some(_), Some(_)) => {}
+        }
+
+        match (
+            command_request.min_protocol_version(),
+            self.protocol_version,
+        ) {
+            (None, _) => CommandSupport::Supported,
+            (Some(_), None) => CommandSupport::Unknown,
+            (Some(required), Some(protocol_version)) if protocol_version < required => {
+                CommandSupport::Unsupported(HandleError::Unsupported {
+                    required,
+                    protocol_version,
+                })
+            }
+            (Some(_), Some(_)) => CommandSupport::Supported,
+        }
+    }
 
-            self.send_command(enqueued_command, deconz_stream).await;
+    /// Fails a command taken off the queue, rather than sending bytes the device would
+    /// misinterpret.
+    fn refuse_command(enqueued_command: EnqueuedCommand, error: HandleError) {
+        warn!(
+            "refusing to send {:?} command: {}",
+            enqueued_command.command_request, error
+        );
+        if let InFlightCommand::External { response_parser } = enqueued_command.in_flight_command {
+            (response_parser)(Err(error));
         }
     }
 
+    /// Forgets everything tied to a lost connection, so that the device state is read again once
+    /// reconnected. In-flight commands are failed, as there's no telling whether the device
+    /// executed them. Enqueued commands are kept and sent after reconnecting.
+    pub(crate) fn reset_connection(&mut self) {
+        self.device_state = None;
+        self.firmware_version = None;
+        self.protocol_version = None;
+        self.device_capabilities.send_replace(None);
+        self.published_device_state.send_replace(None);
+        self.aps_data_request_status = ApsDataRequestStatus::PendingNextDeviceUpdate;
+        self.fail_in_flight_commands(|| HandleError::Disconnected);
+    }
+
+    pub(crate) fn fail_in_flight_commands(&mut self, error: fn() -> HandleError) {
+        for (_, pending_confirm) in self.pending_aps_confirms.drain() {
+            pending_confirm.sender.send(Err(error())).ok();
+        }
+        for (_, in_flight) in self.in_flight_commands.drain() {
+            for (_, sent_command) in in_flight {
+                if let InFlightCommand::External { response_parser } =
+                    sent_command.enqueued_command.in_flight_command
+                {
+                    (response_parser)(Err(error()));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn fail_enqueued_commands(&mut self, error: fn() -> HandleError) {
+        let enqueued_commands = self
+            .enqueued_commands
+            .drain(..)
+            .chain(self.enqueued_aps_data_request_commands.drain(..));
+        for enqueued_command in enqueued_commands {
+            if let InFlightCommand::External { response_parser } =
+                enqueued_command.in_flight_command
+            {
+                (response_parser)(Err(error()));
+            }
+        }
+    }
+
+    /// Returns `true` if any commands sent through a handle are still enqueued or in flight.
+    pub(crate) fn has_pending_external_commands(&self) -> bool {
+        !self.enqueued_commands.is_empty()
+            || !self.enqueued_aps_data_request_commands.is_empty()
+            || !self.pending_aps_confirms.is_empty()
+            || self
+                .in_flight_commands
+                .values()
+                .flat_map(|in_flight| in_flight.values())
+                .any(|sent_command| {
+                    matches!(
+                        sent_command.enqueued_command.in_flight_command,
+                        InFlightCommand::External { .. }
+                    )
+                })
+    }
+
     pub(crate) fn handle_deconz_frame(&mut self, deconz_frame: DeconzFrame<Bytes>) {
-        // An unsolicited device state changed was received, so we just need to update our state.
-        let device_state = match deconz_frame.command_id() {
+        self.broadcast_channels
+            .tap_frame(FrameDirection::Incoming, || deconz_frame.clone());
+        let command_id = deconz_frame.command_id();
+        let device_state = match command_id {
+            // An unsolicited device state changed was received, so we just need to update our state.
             CommandId::DeviceStateChanged => {
                 let mut deconz_frame = deconz_frame;
-                Some(deconz_frame.get_u8().into())
-            }
-            CommandId::MacBeaconIndication => {
-                let (mac_beacon_indication, device_state) =
-                    MACBeaconIndication::from_frame(deconz_frame);
-                self.handle_mac_beacon_indication(mac_beacon_indication);
-                device_state
-            }
-            CommandId::MacPollIndication => {
-                let (mac_poll_indication, device_state) =
-                    MACPollIndication::from_frame(deconz_frame);
-                self.handle_mac_poll_indication(mac_poll_indication);
-                device_state
+                deconz_frame.try_get_u8().map(|flags| Some(flags.into()))
             }
+            CommandId::MacBeaconIndication => MACBeaconIndication::from_frame(deconz_frame).map(
+                |(mac_beacon_indication, device_state)| {
+                    self.handle_mac_beacon_indication(mac_beacon_indication);
+                    device_state
+                },
+            ),
+            CommandId::MacPollIndication => MACPollIndication::from_frame(deconz_frame).map(
+                |(mac_poll_indication, device_state)| {
+                    self.handle_mac_poll_indication(mac_poll_indication);
+                    device_state
+                },
+            ),
             command_id => match self.take_in_flight_command(command_id, deconz_frame.sequence_id())
             {
-                Some(InFlightCommand::External { response_parser }) => {
-                    // todo: error handling?
-                    (response_parser)(deconz_frame)
-                }
-                Some(InFlightCommand::Internal) => {
-                    self.handle_in_flight_command_internal_response(deconz_frame)
+                Some(enqueued_command) => {
+                    if deconz_frame.status() != StatusCode::Success {
+                        self.forget_aps_confirm(&enqueued_command);
+                    }
+                    match enqueued_command.in_flight_command {
+                        // Parse errors for external commands are reported back to the caller by the parser itself.
+                        InFlightCommand::External { response_parser } => {
+                            Ok((response_parser)(Ok(deconz_frame)))
+                        }
+                        InFlightCommand::Internal => {
+                            self.handle_in_flight_command_internal_response(deconz_frame)
+                        }
+                    }
                 }
                 None => {
                     info!("frame has no in-flight command handler registered, dropping!");
-                    None
+                    Ok(None)
                 }
             },
         };
 
-        if let Some(device_state) = device_state {
-            self.update_device_state(device_state);
+        match device_state {
+            Ok(Some(device_state)) => self.update_device_state(device_state),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "dropping {:?} frame that failed to parse: {}",
+                command_id, e
+            ),
         }
     }
 
     fn handle_in_flight_command_internal_response(
         &mut self,
         deconz_frame: DeconzFrame<Bytes>,
-    ) -> Option<DeviceState> {
-        match deconz_frame.command_id() {
+    ) -> Result<Option<DeviceState>, ProtocolError> {
+        let status = deconz_frame.status();
+        if status != StatusCode::Success && deconz_frame.command_id() == CommandId::ReadParameter {
+            // The only parameter read internally is the protocol version, which firmware from
+            // before it was introduced doesn't know about.
+            warn!(
+                "device didn't report its protocol version (status {:?}), assuming the oldest",
+                status
+            );
+            self.protocol_version = Some(0);
+            self.update_device_capabilities();
+            return Ok(None);
+        }
+        if status != StatusCode::Success {
+            // Typically a read of an aps queue that turned out to be empty. The device state flags
+            // that made us send it are stale, so read a fresh device state before trying again.
+            info!(
+                "internal {:?} command failed with status {:?}",
+                deconz_frame.command_id(),
+                status
+            );
+            self.device_state = None;
+            return Ok(None);
+        }
+
+        Ok(match deconz_frame.command_id() {
             CommandId::ApsDataIndication => {
-                let (response, device_state) = ReadReceivedDataResponse::from_frame(deconz_frame);
+                let (response, device_state) = ReadReceivedDataResponse::from_frame(deconz_frame)?;
                 self.handle_aps_data_indication_response(response);
                 device_state
             }
             CommandId::ApsDataConfirm => {
-                let (response, device_state) = ReadConfirmDataResponse::from_frame(deconz_frame);
+                let (response, device_state) = ReadConfirmDataResponse::from_frame(deconz_frame)?;
                 self.handle_aps_data_confirm_response(response);
                 device_state
             }
             CommandId::DeviceState => {
-                let (_, device_state) = ReadDeviceStateResponse::from_frame(deconz_frame);
+                let (_, device_state) = ReadDeviceStateResponse::from_frame(deconz_frame)?;
+                device_state
+            }
+            CommandId::Version => {
+                let (response, device_state) =
+                    ReadFirmwareVersionResponse::from_frame(deconz_frame)?;
+                info!("device firmware version: {}", response.firmware_version());
+                self.firmware_version = Some(response);
+                self.update_device_capabilities();
+                device_state
+            }
+            CommandId::ReadParameter => {
+                let (response, device_state) =
+                    ReadParameterResponse::<parameters::ProtocolVersion>::from_frame(deconz_frame)?;
+                self.protocol_version = Some(*response.value);
+                self.update_device_capabilities();
                 device_state
             }
             command_id => {
@@ -259,7 +729,7 @@ impl DeconzQueue {
                 );
                 None
             }
-        }
+        })
     }
 
     fn handle_aps_data_indication_response(
@@ -271,6 +741,8 @@ impl DeconzQueue {
             "got aps data indication response: {:?}",
             read_received_data_response
         );
+        self.aps_subscriptions
+            .dispatch(&read_received_data_response);
         self.broadcast_channels
             .broadcast_aps_data_indication(read_received_data_response);
         // todo!()
@@ -284,106 +756,291 @@ impl DeconzQueue {
             "got aps data confirm response: {:?}",
             read_confirm_data_response
         );
+        self.broadcast_channels
+            .broadcast_event(DeconzEvent::ApsDataConfirm(
+                read_confirm_data_response.clone(),
+            ));
+        if let Some(pending_confirm) = self
+            .pending_aps_confirms
+            .remove(&read_confirm_data_response.request_id)
+        {
+            pending_confirm
+                .sender
+                .send(Ok(read_confirm_data_response))
+                .ok();
+        }
     }
 
     fn handle_mac_beacon_indication(&mut self, mac_beacon_indication: MACBeaconIndication) {
         info!("got mac_beacon_indication: {:?}", mac_beacon_indication);
+        self.broadcast_channels
+            .broadcast_event(DeconzEvent::MacBeacon(mac_beacon_indication));
     }
 
     fn handle_mac_poll_indication(&mut self, mac_poll_indication: MACPollIndication) {
         info!("got mac_poll_indication: {:?}", mac_poll_indication);
+        self.broadcast_channels
+            .broadcast_event(DeconzEvent::MacPoll(mac_poll_indication));
     }
 
-    async fn send_device_state_request(&mut self, deconz_stream: &mut DeconzStream<SerialStream>) {
+    async fn send_device_state_request(
+        &mut self,
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), Arc<DeconzStreamError>> {
         // We're already requesting a device state, no need to duplicate that effort.
         if self.has_in_flight_command_for_command_id(CommandId::DeviceState) {
-            return;
+            return Ok(());
+        }
+
+        let enqueued_command =
+            EnqueuedCommand::new_internal(ReadDeviceState::new(), self.command_timeout);
+        self.send_command(enqueued_command, deconz_stream).await
+    }
+
+    async fn send_device_capabilities_requests(
+        &mut self,
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), Arc<DeconzStreamError>> {
+        if self.firmware_version.is_none()
+            && !self.has_in_flight_command_for_command_id(CommandId::Version)
+        {
+            let enqueued_command =
+                EnqueuedCommand::new_internal(ReadFirmwareVersion::new(), self.command_timeout);
+            self.send_command(enqueued_command, deconz_stream).await?;
+        }
+
+        if self.protocol_version.is_none()
+            && !self.has_in_flight_command_for_command_id(CommandId::ReadParameter)
+        {
+            let enqueued_command =
+                EnqueuedCommand::new_internal(ReadProtocolVersion::new(), self.command_timeout);
+            self.send_command(enqueued_command, deconz_stream).await?;
         }
 
-        let enqueued_command = EnqueuedCommand::new_internal(ReadDeviceState::new());
-        self.send_command(enqueued_command, deconz_stream).await;
+        Ok(())
     }
 
     async fn send_aps_data_confirm_read_request(
         &mut self,
-        deconz_stream: &mut DeconzStream<SerialStream>,
-    ) {
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), Arc<DeconzStreamError>> {
         // Already has in-flight request, so we won't enqueue anything for the time being, until the data read confirm request
         // sends a result back.
         if self.has_in_flight_command_for_command_id(CommandId::ApsDataConfirm) {
-            return;
+            return Ok(());
         }
 
         info!("device-state indicates there is an available aps confirm. sending request.");
-        let enqueued_command = EnqueuedCommand::new_internal(ReadConfirmData::new());
-        self.send_command(enqueued_command, deconz_stream).await;
+        let enqueued_command =
+            EnqueuedCommand::new_internal(ReadConfirmData::new(), self.command_timeout);
+        self.send_command(enqueued_command, deconz_stream).await
     }
 
     async fn send_aps_data_indication_read_request(
         &mut self,
-        deconz_stream: &mut DeconzStream<SerialStream>,
-    ) {
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), Arc<DeconzStreamError>> {
         // Already has in-flight request, so we won't enqueue anything for the time being, until the data read request
         // sends a result back.
         if self.has_in_flight_command_for_command_id(CommandId::ApsDataIndication) {
-            return;
+            return Ok(());
         }
 
         info!("device-state indicates there is an available aps data. sending request.");
-        let enqueued_command = EnqueuedCommand::new_internal(ReadReceivedData::new());
-        self.send_command(enqueued_command, deconz_stream).await;
+        let enqueued_command =
+            EnqueuedCommand::new_internal(ReadReceivedData::new(), self.command_timeout);
+        self.send_command(enqueued_command, deconz_stream).await
     }
 
-    async fn try_send_aps_data_request(&mut self, deconz_stream: &mut DeconzStream<SerialStream>) {
+    async fn try_send_aps_data_request(
+        &mut self,
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), Arc<DeconzStreamError>> {
         // If no slots are available, we won't try to consume from the queue just yet, a future device state update
         // will inform us we have more slots.
         if !self.aps_data_request_status.has_slots_available() || self.in_flight_commands_full() {
-            return;
+            return Ok(());
         }
 
-        // We have a slot available, let's pop a data request.
-        let enqueued_command = match self.enqueued_aps_data_request_commands.pop_front() {
+        // Data requests the device can't handle are failed, the same as other commands.
+        let mut position = 0;
+        while position < self.enqueued_aps_data_request_commands.len() {
+            let command_request =
+                &self.enqueued_aps_data_request_commands[position].command_request;
+            match self.command_support(&**command_request) {
+                CommandSupport::Unsupported(error) => {
+                    if let Some(enqueued_command) =
+                        self.enqueued_aps_data_request_commands.remove(position)
+                    {
+                        Self::refuse_command(enqueued_command, error);
+                    }
+                }
+                _ => position += 1,
+            }
+        }
+
+        // We have a slot available, let's pop a data request. Broadcasts over the budget are
+        // skipped for now, unicasts behind them don't have to wait, and neither do they for
+        // requests held until the device's capabilities are known.
+        let now = Instant::now();
+        let can_broadcast = self.broadcast_limiter.has_budget(now);
+        let enqueued_command = match self
+            .enqueued_aps_data_request_commands
+            .iter()
+            .position(|enqueued_command| {
+                (can_broadcast || !enqueued_command.command_request.is_broadcast())
+                    && matches!(
+                        self.command_support(&*enqueued_command.command_request),
+                        CommandSupport::Supported
+                    )
+            })
+            .and_then(|position| self.enqueued_aps_data_request_commands.remove(position))
+        {
             Some(enqueud_command) => enqueud_command,
-            None => return,
+            None => return Ok(()),
         };
+        if enqueued_command.command_request.is_broadcast() {
+            self.broadcast_limiter.record(now);
+        }
 
         // Now that we've just sent a command, we're unsure on whether or not there's slots remaining. We'll wait
         // until the next device state update is received in order to unblock production of more data requests.
         self.aps_data_request_status = ApsDataRequestStatus::PendingNextDeviceUpdate;
-        self.send_command(enqueued_command, deconz_stream).await;
+        self.send_command(enqueued_command, deconz_stream).await
     }
 
     async fn send_command(
         &mut self,
-        enqueued_command: EnqueuedCommand,
-        deconz_stream: &mut DeconzStream<SerialStream>,
-    ) {
-        let EnqueuedCommand {
-            command_request,
-            in_flight_command,
-        } = enqueued_command;
+        mut enqueued_command: EnqueuedCommand,
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), Arc<DeconzStreamError>> {
         let sequence_id = self.next_sequence_id();
-        let command_id = command_request.command_id();
+        let command_id = enqueued_command.command_request.command_id();
+        if command_id == CommandId::ApsDataRequest {
+            let request_id = self.next_aps_request_id();
+            enqueued_command
+                .command_request
+                .set_aps_request_id(request_id);
+            enqueued_command.aps_request_id = Some(request_id);
+            if let Some(sender) = enqueued_command.aps_confirm.take() {
+                self.pending_aps_confirms.insert(
+                    request_id,
+                    PendingApsConfirm {
+                        sender,
+                        deadline: Instant::now() + self.aps_confirm_timeout,
+                    },
+                );
+            }
+        }
+        let frame = enqueued_command.command_request.as_frame(sequence_id);
+        self.broadcast_channels
+            .tap_frame(FrameDirection::Outgoing, || frame.to_parsed());
 
         self.in_flight_commands
             .entry(command_id)
             .or_default()
-            .insert(sequence_id, in_flight_command);
+            .insert(
+                sequence_id,
+                SentCommand {
+                    deadline: Instant::now() + enqueued_command.timeout,
+                    enqueued_command,
+                },
+            );
+
+        if let Err(e) = deconz_stream.write_frame(frame).await {
+            // The caller hears about the failed write itself, the task treats it as a lost connection.
+            let e = Arc::new(e);
+            if let Some(enqueued_command) = self.take_in_flight_command(command_id, sequence_id) {
+                self.forget_aps_confirm(&enqueued_command);
+                if let InFlightCommand::External { response_parser } =
+                    enqueued_command.in_flight_command
+                {
+                    (response_parser)(Err(HandleError::Transport(e.clone())));
+                }
+            }
+            return Err(e);
+        }
 
-        let frame = command_request.as_frame(sequence_id);
-        deconz_stream.write_frame(frame).await.unwrap(); // todo: Error handling!
+        Ok(())
     }
 
+    /// APS request ids wrap around just like sequence ids, so the ids of requests that are still
+    /// awaiting their confirm are skipped.
+    fn next_aps_request_id(&mut self) -> u8 {
+        loop {
+            let request_id = self.next_aps_request_id;
+            self.next_aps_request_id = self.next_aps_request_id.wrapping_add(1);
+            let in_flight = self
+                .in_flight_commands
+                .get(&CommandId::ApsDataRequest)
+                .into_iter()
+                .flat_map(|in_flight| in_flight.values())
+                .any(|sent_command| {
+                    sent_command.enqueued_command.aps_request_id == Some(request_id)
+                });
+            if !in_flight && !self.pending_aps_confirms.contains_key(&request_id) {
+                return request_id;
+            }
+        }
+    }
+
+    /// Sequence ids wrap around, so ids that are still in flight are skipped to make sure a
+    /// response can't be matched to the wrong command. There are never 256 commands in flight.
     fn next_sequence_id(&mut self) -> u8 {
-        let sequence_number = self.next_sequence_id;
-        self.next_sequence_id = self.next_sequence_id.wrapping_add(1);
-        sequence_number
+        loop {
+            let sequence_number = self.next_sequence_id;
+            self.next_sequence_id = self.next_sequence_id.wrapping_add(1);
+            if !self.is_sequence_id_in_flight(sequence_number) {
+                return sequence_number;
+            }
+        }
     }
 }
 
+/// Receives the response frame for an external command, or the reason there won't be one.
+/// Returns the device state the response carried, if any.
+pub(crate) type ResponseParser =
+    Box<dyn FnOnce(Result<DeconzFrame<Bytes>, HandleError>) -> Option<DeviceState> + Send>;
+
+/// Receives the confirm of an APS data request, or the reason there won't be one.
+pub(crate) type ApsConfirmSender = oneshot::Sender<Result<ReadConfirmDataResponse, HandleError>>;
+
 pub(crate) enum InFlightCommand {
-    External {
-        response_parser: Box<dyn FnOnce(DeconzFrame<Bytes>) -> Option<DeviceState> + Send>,
-    },
+    External { response_parser: ResponseParser },
     Internal,
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_next_sequence_id_skips_in_flight() {
+        let mut queue = DeconzQueue::new(
+            Duration::from_secs(5),
+            0,
+            Duration::from_secs(20),
+            None,
+            watch::channel(None).0,
+            watch::channel(None).0,
+        );
+        queue.next_sequence_id = 255;
+        queue
+            .in_flight_commands
+            .entry(CommandId::DeviceState)
+            .or_default()
+            .insert(
+                0,
+                SentCommand {
+                    enqueued_command: EnqueuedCommand::new_internal(
+                        ReadDeviceState::new(),
+                        queue.command_timeout,
+                    ),
+                    deadline: Instant::now(),
+                },
+            );
+
+        assert_eq!(queue.next_sequence_id(), 255);
+        assert_eq!(queue.next_sequence_id(), 1);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::sync::Notify;
+
+use crate::protocol::aps::{IEEEAddress, NetworkAddress, ReadReceivedDataResponse, SourceAddress};
+
+/// Which APS data indications an [`ApsSubscription`] receives. Fields left unset match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApsFilter {
+    pub profile_id: Option<u16>,
+    pub cluster_id: Option<u16>,
+    /// The local endpoint the frame was sent to.
+    pub endpoint: Option<u8>,
+    pub source: Option<ApsSource>,
+}
+
+/// The sender an [`ApsFilter`] matches. Frames carrying both addresses match either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApsSource {
+    NetworkAddress(NetworkAddress),
+    IEEEAddress(IEEEAddress),
+}
+
+impl ApsFilter {
+    pub fn matches(&self, indication: &ReadReceivedDataResponse) -> bool {
+        let source_matches = match (self.source, indication.source_address) {
+            (None, _) => true,
+            (
+                Some(ApsSource::NetworkAddress(expected)),
+                SourceAddress::NetworkAddress(network_address)
+                | SourceAddress::Both {
+                    network_address, ..
+                },
+            ) => expected == network_address,
+            (
+                Some(ApsSource::IEEEAddress(expected)),
+                SourceAddress::IEEEAddress(ieee_address) | SourceAddress::Both { ieee_address, .. },
+            ) => expected == ieee_address,
+            _ => false,
+        };
+
+        source_matches
+            && field_matches(self.profile_id, indication.profile_id)
+            && field_matches(self.cluster_id, indication.cluster_id)
+            && field_matches(self.endpoint, indication.destination_endpoint)
+    }
+}
+
+fn field_matches<T: PartialEq>(expected: Option<T>, actual: T) -> bool {
+    expected.is_none() || expected == Some(actual)
+}
+
+/// What happens to frames for a subscription whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Makes room by dropping the oldest queued frame.
+    DropOldest,
+    /// Drops the new frame, keeping the queued ones.
+    DropNewest,
+    /// Stops reading frames from the device until the subscriber catches up. The device buffers
+    /// only a few frames itself, so this holds up every other subscriber too.
+    Backpressure,
+}
+
+/// See [`DeconzClientHandle::subscribe_aps_with_options`](crate::DeconzClientHandle::subscribe_aps_with_options).
+#[derive(Debug, Clone)]
+pub struct ApsSubscriptionOptions {
+    /// How many frames are queued for the subscriber before the overflow policy kicks in. A
+    /// capacity of zero is taken as one, as a backpressured queue couldn't ever take a frame.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for ApsSubscriptionOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 128,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+struct SubscriptionQueue {
+    frames: VecDeque<ReadReceivedDataResponse>,
+    dropped: u64,
+    /// Set once either side goes away.
+    closed: bool,
+}
+
+struct SubscriptionShared {
+    filter: ApsFilter,
+    options: ApsSubscriptionOptions,
+    queue: Mutex<SubscriptionQueue>,
+    frame_ready: Notify,
+    /// Shared by every subscription, wakes the task up when a backpressured queue has room.
+    room_available: Arc<Notify>,
+}
+
+impl SubscriptionShared {
+    fn queue(&self) -> MutexGuard<'_, SubscriptionQueue> {
+        self.queue.lock().expect("subscription queue lock poisoned")
+    }
+}
+
+/// The APS data indications matching an [`ApsFilter`], queued up inside the client task.
+pub struct ApsSubscription {
+    shared: Arc<SubscriptionShared>,
+}
+
+impl ApsSubscription {
+    /// Waits for the next matching frame. Returns `None` once the client task has stopped.
+    pub async fn recv(&mut self) -> Option<ReadReceivedDataResponse> {
+        loop {
+            {
+                let mut queue = self.shared.queue();
+                if let Some(frame) = queue.frames.pop_front() {
+                    drop(queue);
+                    if self.shared.options.overflow == OverflowPolicy::Backpressure {
+                        self.shared.room_available.notify_one();
+                    }
+                    return Some(frame);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.shared.frame_ready.notified().await;
+        }
+    }
+
+    /// How many frames were dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.queue().dropped
+    }
+}
+
+impl std::fmt::Debug for ApsSubscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApsSubscription")
+            .field("filter", &self.shared.filter)
+            .field("options", &self.shared.options)
+            .finish()
+    }
+}
+
+impl Drop for ApsSubscription {
+    fn drop(&mut self) {
+        self.shared.queue().closed = true;
+        // A backpressured subscription that goes away no longer holds the task up.
+        self.shared.room_available.notify_one();
+    }
+}
+
+/// The task's side of the APS subscriptions.
+pub(crate) struct ApsSubscriptions {
+    subscriptions: Vec<Arc<SubscriptionShared>>,
+    room_available: Arc<Notify>,
+}
+
+impl ApsSubscriptions {
+    pub(crate) fn new() -> Self {
+        Self {
+            subscriptions: Vec::new(),
+            room_available: Arc::new(Notify::new()),
+        }
+    }
+
+    pub(crate) fn subscribe(
+        &mut self,
+        filter: ApsFilter,
+        options: ApsSubscriptionOptions,
+    ) -> ApsSubscription {
+        let options = ApsSubscriptionOptions {
+            capacity: options.capacity.max(1),
+            ..options
+        };
+        let shared = Arc::new(SubscriptionShared {
+            filter,
+            options,
+            queue: Mutex::new(SubscriptionQueue {
+                frames: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            frame_ready: Notify::new(),
+            room_available: self.room_available.clone(),
+        });
+        self.subscriptions.push(shared.clone());
+        ApsSubscription { shared }
+    }
+
+    /// Returns `true` if a backpressured subscription has no room for another frame, in which
+    /// case no frames should be read from the device.
+    pub(crate) fn is_blocked(&self) -> bool {
+        self.subscriptions.iter().any(|shared| {
+            let queue = shared.queue();
+            shared.options.overflow == OverflowPolicy::Backpressure
+                && !queue.closed
+                && queue.frames.len() >= shared.options.capacity
+        })
+    }
+
+    /// Notified whenever a backpressured subscription may have made room.
+    pub(crate) fn room_available(&self) -> Arc<Notify> {
+        self.room_available.clone()
+    }
+
+    /// Queues the frame for every subscription whose filter matches it.
+    pub(crate) fn dispatch(&mut self, indication: &ReadReceivedDataResponse) {
+        self.subscriptions.retain(|shared| !shared.queue().closed);
+
+        for shared in &self.subscriptions {
+            if !shared.filter.matches(indication) {
+                continue;
+            }
+
+            let mut queue = shared.queue();
+            if queue.frames.len() >= shared.options.capacity {
+                match shared.options.overflow {
+                    OverflowPolicy::DropOldest => {
+                        queue.frames.pop_front();
+                        queue.dropped += 1;
+                    }
+                    OverflowPolicy::DropNewest => {
+                        queue.dropped += 1;
+                        continue;
+                    }
+                    // Frames are only read while there's room.
+                    OverflowPolicy::Backpressure => {}
+                }
+            }
+            queue.frames.push_back(indication.clone());
+            drop(queue);
+            shared.frame_ready.notify_one();
+        }
+    }
+}
+
+impl Drop for ApsSubscriptions {
+    fn drop(&mut self) {
+        for shared in &self.subscriptions {
+            shared.queue().closed = true;
+            shared.frame_ready.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::protocol::aps::DestinationAddress;
+
+    fn indication(cluster_id: u16, source_address: SourceAddress) -> ReadReceivedDataResponse {
+        ReadReceivedDataResponse {
+            device_state: 0.into(),
+            destination_address: DestinationAddress::NetworkAddress(0x0000),
+            destination_endpoint: 1,
+            source_address,
+            source_endpoint: 1,
+            profile_id: 0x0104,
+            cluster_id,
+            application_specific_data_unit: vec![],
+            link_quality_indication: 255,
+            received_signal_strength_indication: -40,
+        }
+    }
+
+    #[test]
+    pub fn test_aps_filter() {
+        let both = SourceAddress::Both {
+            network_address: 0x1234,
+            ieee_address: 0x00212EFFFF000001,
+        };
+        let filter = ApsFilter {
+            cluster_id: Some(0x0006),
+            source: Some(ApsSource::IEEEAddress(0x00212EFFFF000001)),
+            ..Default::default()
+        };
+        assert!(filter.matches(&indication(0x0006, both)));
+        assert!(!filter.matches(&indication(0x0008, both)));
+        assert!(!filter.matches(&indication(0x0006, SourceAddress::NetworkAddress(0x1234))));
+
+        let filter = ApsFilter {
+            source: Some(ApsSource::NetworkAddress(0x1234)),
+            endpoint: Some(1),
+            ..Default::default()
+        };
+        assert!(filter.matches(&indication(0x0006, both)));
+        assert!(ApsFilter::default().matches(&indication(0x0006, both)));
+    }
+
+    #[tokio::test]
+    pub async fn test_overflow_policies() {
+        let mut subscriptions = ApsSubscriptions::new();
+        let options = |overflow| ApsSubscriptionOptions {
+            capacity: 2,
+            overflow,
+        };
+        let mut drop_oldest =
+            subscriptions.subscribe(Default::default(), options(OverflowPolicy::DropOldest));
+        let mut drop_newest =
+            subscriptions.subscribe(Default::default(), options(OverflowPolicy::DropNewest));
+        let mut backpressure =
+            subscriptions.subscribe(Default::default(), options(OverflowPolicy::Backpressure));
+
+        for cluster_id in 1..=2 {
+            subscriptions.dispatch(&indication(cluster_id, SourceAddress::NetworkAddress(1)));
+        }
+        assert!(subscriptions.is_blocked());
+        assert_eq!(backpressure.recv().await.unwrap().cluster_id, 1);
+        assert!(!subscriptions.is_blocked());
+        subscriptions.dispatch(&indication(3, SourceAddress::NetworkAddress(1)));
+
+        assert_eq!(drop_oldest.recv().await.unwrap().cluster_id, 2);
+        assert_eq!(drop_oldest.dropped(), 1);
+        assert_eq!(drop_newest.recv().await.unwrap().cluster_id, 1);
+        assert_eq!(drop_newest.dropped(), 1);
+
+        drop(backpressure);
+        assert!(!subscriptions.is_blocked());
+        drop(subscriptions);
+        assert_eq!(drop_oldest.recv().await.unwrap().cluster_id, 3);
+        assert!(drop_oldest.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_zero_capacity() {
+        let mut subscriptions = ApsSubscriptions::new();
+        let mut backpressure = subscriptions.subscribe(
+            Default::default(),
+            ApsSubscriptionOptions {
+                capacity: 0,
+                overflow: OverflowPolicy::Backpressure,
+            },
+        );
+
+        // Taken as a capacity of one, so frames still make it through.
+        assert!(!subscriptions.is_blocked());
+        subscriptions.dispatch(&indication(1, SourceAddress::NetworkAddress(1)));
+        assert!(subscriptions.is_blocked());
+        assert_eq!(backpressure.recv().await.unwrap().cluster_id, 1);
+        assert!(!subscriptions.is_blocked());
+    }
+}
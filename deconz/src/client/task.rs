@@ -1,28 +1,49 @@
 use std::{
     fmt::{Debug, Display},
+    sync::Arc,
     time::Duration,
 };
 
 use bytes::Bytes;
 use thiserror::Error;
-use tokio::sync::{broadcast, mpsc, oneshot};
-use tokio_serial::{FlowControl, SerialStream};
-use tracing::info;
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, watch},
+    time::Instant,
+};
+use tracing::{debug, info, warn};
 
 use crate::{
-    protocol::{aps::ReadReceivedDataResponse, device::DeviceState, DeconzCommandRequest},
-    DeconzFrame, DeconzStream,
+    protocol::{
+        aps::ReadReceivedDataResponse,
+        device::{ChangeNetworkState, DeviceState, FirmwareVersionPlatform},
+        network_parameters::WriteWatchdogTtl,
+        DeconzCommand, DeconzCommandRequest, NetworkState,
+    },
+    DeconzFrame, DeconzStream, DeconzStreamError,
 };
 
 use super::{
-    queue::{DeconzQueue, InFlightCommand},
-    DeconzClientConfig,
+    events::{DeconzEvent, TappedFrame},
+    handle::{CommandToken, HandleError},
+    queue::{ApsConfirmSender, DeconzQueue, InFlightCommand, ResponseParser},
+    subscription::{ApsFilter, ApsSubscription, ApsSubscriptionOptions},
+    transport::BoxedTransportIo,
+    watchdog::{self, WatchdogEvent},
+    ConnectionState, DeconzClientConfig, DeviceCapabilities, ShutdownOptions,
 };
 
 pub enum TaskMessage {
     CommandRequest {
         command_request: Box<dyn DeconzCommandRequest>,
-        response_parser: Box<dyn FnOnce(DeconzFrame<Bytes>) -> Option<DeviceState> + Send>,
+        response_parser: ResponseParser,
+        timeout: Option<Duration>,
+        command_token: CommandToken,
+        aps_confirm: Option<ApsConfirmSender>,
+    },
+    CancelCommand(CommandToken),
+    Shutdown {
+        options: ShutdownOptions,
+        done: oneshot::Sender<()>,
     },
     SubscribeRequest(SubscribeRequest),
 }
@@ -30,12 +51,22 @@ pub enum TaskMessage {
 #[derive(Debug)]
 pub enum SubscribeRequest {
     ApsDataIndication(oneshot::Sender<broadcast::Receiver<ReadReceivedDataResponse>>),
+    WatchdogEvent(oneshot::Sender<broadcast::Receiver<WatchdogEvent>>),
+    Event(oneshot::Sender<broadcast::Receiver<DeconzEvent>>),
+    Frame(oneshot::Sender<broadcast::Receiver<TappedFrame>>),
+    Aps {
+        filter: ApsFilter,
+        options: ApsSubscriptionOptions,
+        sender: oneshot::Sender<ApsSubscription>,
+    },
 }
 
 impl Display for TaskMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TaskMessage::CommandRequest { .. } => f.write_str("CommandRequest"),
+            TaskMessage::CancelCommand(_) => f.write_str("CancelCommand"),
+            TaskMessage::Shutdown { .. } => f.write_str("Shutdown"),
             TaskMessage::SubscribeRequest(_) => f.write_str("SubscribeRequest"),
         }
     }
@@ -47,10 +78,26 @@ impl Debug for TaskMessage {
             TaskMessage::CommandRequest {
                 command_request: command_outgoing,
                 response_parser: _,
+                timeout,
+                command_token,
+                aps_confirm,
             } => f
                 .debug_struct("TaskMessage::CommandRequest")
                 .field("command", command_outgoing)
                 .field("response_parser", &"...")
+                .field("timeout", timeout)
+                .field("command_token", command_token)
+                .field("aps_confirm", &aps_confirm.is_some())
+                .finish(),
+
+            TaskMessage::CancelCommand(command_token) => f
+                .debug_tuple("TaskMessage::CancelCommand")
+                .field(command_token)
+                .finish(),
+
+            TaskMessage::Shutdown { options, done: _ } => f
+                .debug_struct("TaskMessage::Shutdown")
+                .field("options", options)
                 .finish(),
 
             TaskMessage::SubscribeRequest(request) => f
@@ -65,58 +112,340 @@ impl Debug for TaskMessage {
 pub enum TaskError {
     #[error(transparent)]
     SerialError(#[from] tokio_serial::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("the transport stream was already used by a previous connection")]
+    TransportConsumed,
+    #[error("the device did not respond at any of the candidate baud rates")]
+    BaudRateNotDetected,
+    #[error("deconz stream ended")]
+    StreamEnded,
+    #[error(transparent)]
+    Stream(#[from] DeconzStreamError),
+    #[error("failed to write to the device: {0}")]
+    Write(Arc<DeconzStreamError>),
+}
+
+/// How soon to check again for the device platform, when it isn't known yet at refresh time.
+const WATCHDOG_PLATFORM_RETRY: Duration = Duration::from_secs(1);
+
+/// The shortest time between watchdog refreshes, whatever the configured interval.
+const MIN_WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A shutdown requested through a handle, or by dropping the last one.
+struct PendingShutdown {
+    options: ShutdownOptions,
+    deadline: Instant,
+    network_offline_requested: bool,
+    /// Dropped once the task has stopped, which is what [`DeconzClientHandle::shutdown`] waits for.
+    ///
+    /// [`DeconzClientHandle::shutdown`]: super::DeconzClientHandle::shutdown
+    done: Vec<oneshot::Sender<()>>,
 }
 
 /// The main loop task has a few responsibilities:
-/// - Initiating a deCONZ device communications stream.
+/// - Initiating a deCONZ device communications stream, and re-establishing it when lost.
 /// - Reacting to and/or responding to TaskMessages sent from client handles.
 /// - Sending and receiving messages to the deCONZ device stream.
+/// - Winding all of that down again on shutdown.
 pub struct DeconzTask {
     config: DeconzClientConfig,
     task_rx: mpsc::UnboundedReceiver<TaskMessage>,
+    handles_dropped: bool,
+    connection_state: watch::Sender<ConnectionState>,
     queue: DeconzQueue,
+    shutdown: Option<PendingShutdown>,
+    next_watchdog_refresh: Option<Instant>,
 }
 
 impl DeconzTask {
-    pub fn new(config: DeconzClientConfig, task_rx: mpsc::UnboundedReceiver<TaskMessage>) -> Self {
+    pub fn new(
+        config: DeconzClientConfig,
+        task_rx: mpsc::UnboundedReceiver<TaskMessage>,
+        connection_state: watch::Sender<ConnectionState>,
+        device_state: watch::Sender<Option<DeviceState>>,
+        device_capabilities: watch::Sender<Option<DeviceCapabilities>>,
+    ) -> Self {
         Self {
-            config,
             task_rx,
-            queue: DeconzQueue::new(),
+            handles_dropped: false,
+            connection_state,
+            queue: DeconzQueue::new(
+                config.command_timeout,
+                config.command_retries,
+                config.aps_confirm_timeout,
+                config.broadcast_limit.clone(),
+                device_state,
+                device_capabilities,
+            ),
+            shutdown: None,
+            next_watchdog_refresh: None,
+            config,
         }
     }
 
-    /// Consumes the task, starting the main loop.
+    /// Consumes the task, starting the main loop. Returns once shut down, or when the connection
+    /// is lost for good.
     pub async fn run(mut self) -> Result<(), TaskError> {
-        let serial_stream = self.connect_serial()?;
-        let mut deconz_stream = DeconzStream::new(serial_stream);
+        let result = self.run_until_shutdown().await;
+
+        // Whatever didn't make it out before stopping fails now, rather than hanging forever.
+        self.queue.fail_enqueued_commands(|| HandleError::Shutdown);
+        self.queue.fail_in_flight_commands(|| HandleError::Shutdown);
+        self.set_connection_state(ConnectionState::Disconnected);
+        info!("client task stopped");
+
+        result
+    }
+
+    fn open_stream(&self, transport_io: BoxedTransportIo) -> DeconzStream<BoxedTransportIo> {
+        let mut deconz_stream = DeconzStream::new(transport_io);
+        if let Some(capture) = &self.config.capture {
+            deconz_stream.set_capture(capture.clone());
+        }
+        deconz_stream
+    }
+
+    async fn run_until_shutdown(&mut self) -> Result<(), TaskError> {
+        let transport_io = self.config.connect().await?;
+        let mut deconz_stream = self.open_stream(transport_io);
 
         loop {
-            self.queue.try_io(&mut deconz_stream).await;
+            self.set_connection_state(ConnectionState::Connected);
+            let error = match self.run_connection(&mut deconz_stream).await {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+
+            warn!("lost connection to the device: {}", error);
+            self.queue.reset_connection();
+            self.set_connection_state(ConnectionState::Disconnected);
+
+            deconz_stream = match self.reconnect(error).await? {
+                Some(deconz_stream) => deconz_stream,
+                None => return Ok(()),
+            };
+        }
+    }
+
+    /// Talks to the device until the connection is lost, returning why, or until a requested
+    /// shutdown is complete.
+    async fn run_connection(
+        &mut self,
+        deconz_stream: &mut DeconzStream<BoxedTransportIo>,
+    ) -> Result<(), TaskError> {
+        if self.config.watchdog.is_some() {
+            // The platform decides whether there's a watchdog to keep alive at all, which is known
+            // once the queue has read the firmware version.
+            self.next_watchdog_refresh = Some(Instant::now());
+        }
+
+        loop {
+            if self.shutdown_complete() {
+                return Ok(());
+            }
+
+            self.queue
+                .try_io(deconz_stream)
+                .await
+                .map_err(TaskError::Write)?;
+            let next_deadline = match (self.queue.next_deadline(), &self.shutdown) {
+                (Some(deadline), Some(shutdown)) => Some(deadline.min(shutdown.deadline)),
+                (deadline, shutdown) => deadline.or(shutdown.as_ref().map(|s| s.deadline)),
+            };
+            let aps_subscriptions_blocked = self.queue.aps_subscriptions.is_blocked();
+            let room_available = self.queue.aps_subscriptions.room_available();
 
             tokio::select! {
-                Some(Ok(frame)) = deconz_stream.next_frame() => {
-                    self.handle_deconz_frame(frame).await;
+                frame = deconz_stream.next_frame() => match frame {
+                    Some(Ok(frame)) => self.handle_deconz_frame(frame).await,
+                    Some(Err(e)) if e.is_io() => return Err(e.into()),
+                    Some(Err(e)) => {
+                        let stats = deconz_stream.stats();
+                        warn!(
+                            "dropping invalid deconz frame: {} (corrupted={}, dropped={})",
+                            e, stats.corrupted_frames, stats.dropped_frames
+                        );
+                    }
+                    None => return Err(TaskError::StreamEnded),
+                },
+                task_message = self.task_rx.recv(), if !self.handles_dropped => {
+                    self.handle_task_rx(task_message).await?;
                 }
-                Some(task_message) = self.task_rx.recv() => {
-                    self.handle_task_message(task_message).await?;
+                _ = sleep_until_deadline(next_deadline), if next_deadline.is_some() => {
+                    self.queue.expire_in_flight_commands();
+                }
+                // Nothing to do but go around, and read the frames that were held up.
+                _ = room_available.notified(), if aps_subscriptions_blocked => {}
+                _ = sleep_until_deadline(self.next_watchdog_refresh),
+                    if self.next_watchdog_refresh.is_some() =>
+                {
+                    self.refresh_watchdog();
                 }
             }
         }
     }
 
-    fn connect_serial(&self) -> Result<SerialStream, TaskError> {
-        Ok(SerialStream::open(
-            &tokio_serial::new(
-                self.config
-                    .device_path
-                    .to_str()
-                    .expect("expected device path str"),
-                38400,
-            )
-            .flow_control(FlowControl::None)
-            .timeout(Duration::from_secs(10)),
-        )?)
+    /// Publishes the connection state to handles, and to event subscribers if it changed.
+    fn set_connection_state(&mut self, connection_state: ConnectionState) {
+        if self.connection_state.send_replace(connection_state) != connection_state {
+            self.queue
+                .broadcast_channels
+                .broadcast_event(DeconzEvent::ConnectionStateChanged(connection_state));
+        }
+    }
+
+    /// Rewrites the watchdog TTL on ArmR21 devices, and schedules the next refresh.
+    fn refresh_watchdog(&mut self) {
+        let watchdog = match &self.config.watchdog {
+            Some(watchdog) => watchdog.clone(),
+            None => return,
+        };
+
+        let platform = self
+            .queue
+            .firmware_version()
+            .map(|firmware_version| firmware_version.platform);
+        let next_refresh = match platform {
+            Some(FirmwareVersionPlatform::ArmR21) => {
+                debug!("refreshing the watchdog ttl");
+                self.queue.enqueue_command(
+                    WriteWatchdogTtl::new(watchdog.ttl()).into_boxed_request(),
+                    InFlightCommand::External {
+                        response_parser: watchdog::refresh_response_parser(
+                            watchdog.ttl(),
+                            self.queue.broadcast_channels.watchdog.clone(),
+                        ),
+                    },
+                    None,
+                    CommandToken::next(),
+                    None,
+                );
+                // The interval can be set by hand, and a zero one would flood the queue.
+                Some(Instant::now() + watchdog.interval().max(MIN_WATCHDOG_INTERVAL))
+            }
+            Some(platform) => {
+                info!("{:?} devices have no watchdog to keep alive", platform);
+                None
+            }
+            // Still waiting on the firmware version.
+            None => Some(Instant::now() + WATCHDOG_PLATFORM_RETRY),
+        };
+        self.next_watchdog_refresh = next_refresh;
+    }
+
+    /// Checks whether a pending shutdown is done waiting on commands. Once the commands have
+    /// drained, this is also where the network is taken offline, if asked to.
+    fn shutdown_complete(&mut self) -> bool {
+        let shutdown = match &mut self.shutdown {
+            Some(shutdown) => shutdown,
+            None => return false,
+        };
+
+        if Instant::now() >= shutdown.deadline {
+            warn!("timed out waiting for commands to finish before shutting down");
+            return true;
+        }
+        if self.queue.has_pending_external_commands() {
+            return false;
+        }
+        if shutdown.options.take_network_offline && !shutdown.network_offline_requested {
+            shutdown.network_offline_requested = true;
+            info!("taking the network offline before shutting down");
+            let response_parser: ResponseParser = Box::new(|frame| {
+                if let Err(e) = frame {
+                    warn!("failed to take the network offline: {}", e);
+                }
+                None
+            });
+            self.queue.enqueue_command(
+                ChangeNetworkState::new(NetworkState::NetOffline).into_boxed_request(),
+                InFlightCommand::External { response_parser },
+                None,
+                CommandToken::next(),
+                None,
+            );
+            return false;
+        }
+
+        true
+    }
+
+    /// Reopens the transport with exponential backoff, as configured by the reconnect policy.
+    /// Commands keep being accepted (and enqueued) in the meantime. Once out of attempts, or if
+    /// the transport can't be reopened at all, the error that lost the connection is returned.
+    /// Returns `None` if the task was asked to shut down instead.
+    async fn reconnect(
+        &mut self,
+        error: TaskError,
+    ) -> Result<Option<DeconzStream<BoxedTransportIo>>, TaskError> {
+        let policy = match &self.config.reconnect {
+            Some(policy) => policy.clone(),
+            None => return Err(error),
+        };
+
+        let mut attempt = 1;
+        while policy.allows_attempt(attempt) {
+            let delay = policy.delay(attempt);
+            self.set_connection_state(ConnectionState::Reconnecting { attempt, delay });
+
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                // There's no device to drain commands to, so shutting down can't wait.
+                if self.shutdown.is_some() {
+                    return Ok(None);
+                }
+
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    task_message = self.task_rx.recv(), if !self.handles_dropped => {
+                        self.handle_task_rx(task_message).await?;
+                    }
+                }
+            }
+
+            match self.config.connect().await {
+                Ok(transport_io) => {
+                    info!("reconnected to the device after {} attempt(s)", attempt);
+                    return Ok(Some(self.open_stream(transport_io)));
+                }
+                Err(TaskError::TransportConsumed) => break,
+                Err(e) => info!("reconnect attempt {} failed: {}", attempt, e),
+            }
+            attempt += 1;
+        }
+
+        Err(error)
+    }
+
+    /// Once the last handle is gone, nothing can be sent anymore, so the task shuts down.
+    async fn handle_task_rx(&mut self, task_message: Option<TaskMessage>) -> Result<(), TaskError> {
+        match task_message {
+            Some(task_message) => self.handle_task_message(task_message).await,
+            None => {
+                self.handles_dropped = true;
+                self.request_shutdown(Default::default(), None);
+                Ok(())
+            }
+        }
+    }
+
+    fn request_shutdown(&mut self, options: ShutdownOptions, done: Option<oneshot::Sender<()>>) {
+        let shutdown = self.shutdown.get_or_insert_with(|| {
+            info!("shutting down the client task with {:?}", options);
+            PendingShutdown {
+                deadline: Instant::now() + options.timeout,
+                options,
+                network_offline_requested: false,
+                done: Vec::new(),
+            }
+        });
+        shutdown.done.extend(done);
+
+        if !shutdown.options.drain {
+            self.queue.fail_enqueued_commands(|| HandleError::Shutdown);
+        }
     }
 
     async fn handle_deconz_frame(&mut self, incoming_frame: DeconzFrame<Bytes>) {
@@ -127,14 +456,58 @@ impl DeconzTask {
     async fn handle_task_message(&mut self, task_message: TaskMessage) -> Result<(), TaskError> {
         // info!("incoming task message {:?}", task_message);
         match task_message {
+            TaskMessage::CommandRequest {
+                response_parser, ..
+            } if self.shutdown.is_some() => {
+                (response_parser)(Err(HandleError::Shutdown));
+            }
+
             TaskMessage::CommandRequest {
                 command_request,
                 response_parser,
+                timeout,
+                command_token,
+                aps_confirm,
             } => self.queue.enqueue_command(
                 command_request,
                 InFlightCommand::External { response_parser },
+                timeout,
+                command_token,
+                aps_confirm,
             ),
 
+            TaskMessage::CancelCommand(command_token) => self.queue.cancel_command(command_token),
+
+            TaskMessage::Shutdown { options, done } => self.request_shutdown(options, Some(done)),
+
+            TaskMessage::SubscribeRequest(SubscribeRequest::Aps {
+                filter,
+                options,
+                sender,
+            }) => {
+                sender
+                    .send(self.queue.aps_subscriptions.subscribe(filter, options))
+                    .ok();
+            }
+
+            TaskMessage::SubscribeRequest(SubscribeRequest::Frame(sender)) => {
+                sender
+                    .send(self.queue.broadcast_channels.subscribe_frames())
+                    .ok();
+            }
+
+            TaskMessage::SubscribeRequest(SubscribeRequest::Event(sender)) => {
+                sender
+                    .send(self.queue.broadcast_channels.subscribe_events())
+                    .ok();
+            }
+
+            TaskMessage::SubscribeRequest(SubscribeRequest::WatchdogEvent(sender)) => {
+                sender
+                    .send(self.queue.broadcast_channels.watchdog.subscribe())
+                    .ok();
+            }
+
             TaskMessage::SubscribeRequest(SubscribeRequest::ApsDataIndication(sender)) => {
                 sender
                     .send(
@@ -149,3 +522,10 @@ impl DeconzTask {
         Ok(())
     }
 }
+
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    convert::Infallible,
+    fmt::Debug,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_serial::{FlowControl, SerialStream};
+use tracing::{debug, info};
+
+use super::{task::TaskError, DeconzClientConfig};
+use crate::{
+    protocol::{
+        device::{
+            FirmwareVersionPlatform, ReadFirmwareVersionRequest, ReadFirmwareVersionResponse,
+        },
+        CommandId, DeconzCommandRequest, DeconzCommandResponse,
+    },
+    DeconzStream,
+};
+
+/// Baud rates tried by [`BaudRate::auto`], fastest first.
+/// The ConBee II and RaspBee II run at 115200, the original ConBee and RaspBee at 38400.
+pub const DEFAULT_BAUD_RATE_CANDIDATES: [u32; 2] = [115200, 38400];
+
+/// How long to wait for a firmware version response before trying the next baud rate.
+const BAUD_RATE_PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Anything the deCONZ client can talk to a device through.
+pub trait TransportIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TransportIo for T {}
+
+/// A connected transport, as handed to the DeconzStream.
+pub type BoxedTransportIo = Box<dyn TransportIo>;
+
+/// How the deCONZ client reaches the device.
+#[derive(Clone, Debug)]
+pub enum DeconzTransport {
+    /// A local serial device, like /dev/ttyUSB0.
+    Serial(PathBuf),
+    /// A raw TCP socket forwarding to the device (e.g. ser2net), given as `host:port`.
+    Tcp(String),
+    /// A user-supplied stream. Since it can't be reopened, it can only be connected once.
+    Stream(TransportStream),
+}
+
+impl DeconzTransport {
+    /// Wraps an already connected stream, like one half of a tokio::io::duplex.
+    pub fn from_stream(stream: impl TransportIo + 'static) -> Self {
+        Self::Stream(TransportStream(Arc::new(Mutex::new(Some(Box::new(
+            stream,
+        ))))))
+    }
+}
+
+impl DeconzClientConfig {
+    /// Opens the configured transport, detecting the baud rate first if asked to.
+    pub(crate) async fn connect(&self) -> Result<BoxedTransportIo, TaskError> {
+        Ok(match &self.transport {
+            DeconzTransport::Serial(device_path) => match &self.baud_rate {
+                BaudRate::Fixed(baud_rate) => Box::new(open_serial(
+                    device_path,
+                    *baud_rate,
+                    self.flow_control,
+                    self.timeout,
+                )?),
+                BaudRate::Auto(candidates) => {
+                    let (serial_stream, detected) =
+                        probe_baud_rates(device_path, candidates, self.flow_control, self.timeout)
+                            .await?;
+                    info!(
+                        "detected {:?} device at {} baud",
+                        detected.platform, detected.baud_rate
+                    );
+                    Box::new(serial_stream)
+                }
+            },
+            DeconzTransport::Tcp(address) => {
+                let stream = TcpStream::connect(address).await?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            DeconzTransport::Stream(stream) => stream.take()?,
+        })
+    }
+}
+
+/// The serial baud rate used to talk to the device.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BaudRate {
+    Fixed(u32),
+    /// Try each rate in order, until the device answers a firmware version request.
+    Auto(Vec<u32>),
+}
+
+impl BaudRate {
+    /// Auto-detection using [`DEFAULT_BAUD_RATE_CANDIDATES`].
+    pub fn auto() -> Self {
+        Self::Auto(DEFAULT_BAUD_RATE_CANDIDATES.to_vec())
+    }
+}
+
+impl Default for BaudRate {
+    fn default() -> Self {
+        Self::Fixed(38400)
+    }
+}
+
+/// Parses either `auto` or a fixed numeric baud rate.
+impl FromStr for BaudRate {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::auto()),
+            s => Ok(Self::Fixed(s.parse()?)),
+        }
+    }
+}
+
+/// The outcome of a successful baud rate detection.
+#[derive(Clone, Copy, Debug)]
+pub struct DetectedSerial {
+    pub baud_rate: u32,
+    pub platform: FirmwareVersionPlatform,
+}
+
+/// Probes a serial device with a firmware version request at each candidate baud rate,
+/// returning the first rate the device answered at.
+pub async fn detect_baud_rate(
+    device_path: &Path,
+    candidates: &[u32],
+    flow_control: FlowControl,
+) -> Result<DetectedSerial, TaskError> {
+    let (_, detected) = probe_baud_rates(
+        device_path,
+        candidates,
+        flow_control,
+        BAUD_RATE_PROBE_TIMEOUT,
+    )
+    .await?;
+    Ok(detected)
+}
+
+fn open_serial(
+    device_path: &Path,
+    baud_rate: u32,
+    flow_control: FlowControl,
+    timeout: Duration,
+) -> Result<SerialStream, TaskError> {
+    Ok(SerialStream::open(
+        &tokio_serial::new(device_path.to_string_lossy(), baud_rate)
+            .flow_control(flow_control)
+            .timeout(timeout),
+    )?)
+}
+
+async fn probe_baud_rates(
+    device_path: &Path,
+    candidates: &[u32],
+    flow_control: FlowControl,
+    timeout: Duration,
+) -> Result<(SerialStream, DetectedSerial), TaskError> {
+    for &baud_rate in candidates {
+        let serial_stream = open_serial(device_path, baud_rate, flow_control, timeout)?;
+        let (serial_stream, response) = probe_firmware_version(serial_stream).await;
+        match response {
+            Some(response) => {
+                let detected = DetectedSerial {
+                    baud_rate,
+                    platform: response.platform,
+                };
+                return Ok((serial_stream, detected));
+            }
+            None => debug!("no firmware version response at {} baud", baud_rate),
+        }
+    }
+
+    Err(TaskError::BaudRateNotDetected)
+}
+
+/// Sends a firmware version request and waits for the answer. At the wrong baud rate, whatever
+/// the device sends back fails the CRC check and is ignored until the probe times out.
+pub(crate) async fn probe_firmware_version<S: TransportIo>(
+    stream: S,
+) -> (S, Option<ReadFirmwareVersionResponse>) {
+    let mut deconz_stream = DeconzStream::new(stream);
+    let probe = async {
+        deconz_stream
+            .write_frame(ReadFirmwareVersionRequest.as_frame(0))
+            .await
+            .ok()?;
+        loop {
+            match deconz_stream.next_frame().await? {
+                Ok(frame) if frame.command_id() == CommandId::Version => {
+                    return ReadFirmwareVersionResponse::from_frame(frame)
+                        .ok()
+                        .map(|(response, _)| response);
+                }
+                _ => continue,
+            }
+        }
+    };
+    let response = tokio::time::timeout(BAUD_RATE_PROBE_TIMEOUT, probe)
+        .await
+        .ok()
+        .flatten();
+
+    (deconz_stream.into_inner(), response)
+}
+
+/// Parses `tcp://host:port` into [`DeconzTransport::Tcp`], anything else is treated as a serial device path.
+impl FromStr for DeconzTransport {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("tcp://") {
+            Some(address) => Self::Tcp(address.to_string()),
+            None => Self::Serial(PathBuf::from(s)),
+        })
+    }
+}
+
+impl From<PathBuf> for DeconzTransport {
+    fn from(device_path: PathBuf) -> Self {
+        Self::Serial(device_path)
+    }
+}
+
+/// A user-supplied stream, shared so that the config stays cloneable.
+#[derive(Clone)]
+pub struct TransportStream(Arc<Mutex<Option<BoxedTransportIo>>>);
+
+impl TransportStream {
+    fn take(&self) -> Result<BoxedTransportIo, TaskError> {
+        self.0
+            .lock()
+            .expect("transport stream lock poisoned")
+            .take()
+            .ok_or(TaskError::TransportConsumed)
+    }
+}
+
+impl Debug for TransportStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TransportStream(..)")
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use bytes::{BufMut, BytesMut};
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::DeconzFrame;
+
+    #[tokio::test]
+    pub async fn test_probe_firmware_version() {
+        let (device_io, host_io) = tokio::io::duplex(256);
+        tokio::spawn(async move {
+            let mut device = DeconzStream::new(device_io);
+            let request = device.next_frame().await.unwrap().unwrap();
+            let mut payload = BytesMut::new();
+            payload.put_u32_le(0x26720700);
+            device
+                .write_frame(DeconzFrame::new(
+                    CommandId::Version,
+                    request.sequence_id(),
+                    Some(payload),
+                ))
+                .await
+                .unwrap();
+        });
+
+        let (_, response) = probe_firmware_version(host_io).await;
+        assert_eq!(response.unwrap().platform, FirmwareVersionPlatform::ArmR21);
+    }
+
+    #[tokio::test(start_paused = true)]
+    pub async fn test_probe_ignores_garbage() {
+        let (mut device_io, host_io) = tokio::io::duplex(256);
+        device_io
+            .write_all(&[0xC0, 0x13, 0x37, 0x42, 0xC0])
+            .await
+            .unwrap();
+
+        let (_, response) = probe_firmware_version(host_io).await;
+        assert!(response.is_none());
+    }
+}
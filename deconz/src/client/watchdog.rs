@@ -0,0 +1,132 @@
+use std::{sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use super::{handle::HandleError, queue::ResponseParser};
+use crate::protocol::StatusCode;
+
+/// Keeps the watchdog of the ConBee II and RaspBee II firmware from resetting the radio, by
+/// rewriting the `WatchdogTtl` parameter before it runs out. Other platforms don't have the
+/// watchdog, so nothing is sent to them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchdogKeepAlive {
+    ttl: Duration,
+    interval: Duration,
+}
+
+/// Returned when building a [`WatchdogKeepAlive`] that couldn't keep the watchdog alive.
+#[derive(Error, Debug)]
+pub enum WatchdogTtlError {
+    #[error("the watchdog TTL must be at least {min:?}, got {ttl:?}")]
+    TooShort { ttl: Duration, min: Duration },
+    #[error("the watchdog refresh interval ({interval:?}) must be shorter than its TTL ({ttl:?})")]
+    IntervalTooLong { interval: Duration, ttl: Duration },
+}
+
+impl WatchdogKeepAlive {
+    /// The shortest TTL to keep alive. The device counts the TTL in whole seconds, so shorter
+    /// ones leave no room to refresh it in time.
+    pub const MIN_TTL: Duration = Duration::from_secs(2);
+
+    /// Refreshes the given TTL once three quarters of it have passed.
+    pub fn new(ttl: Duration) -> Result<Self, WatchdogTtlError> {
+        Self::with_interval(ttl, ttl * 3 / 4)
+    }
+
+    /// Refreshes the given TTL at the given interval, which has to be shorter than the TTL.
+    pub fn with_interval(ttl: Duration, interval: Duration) -> Result<Self, WatchdogTtlError> {
+        if ttl < Self::MIN_TTL {
+            return Err(WatchdogTtlError::TooShort {
+                ttl,
+                min: Self::MIN_TTL,
+            });
+        }
+        if interval >= ttl {
+            return Err(WatchdogTtlError::IntervalTooLong { interval, ttl });
+        }
+
+        Ok(Self { ttl, interval })
+    }
+
+    /// The TTL written to the device. Only whole seconds are kept.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// How often the TTL is rewritten.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+impl Default for WatchdogKeepAlive {
+    fn default() -> Self {
+        let ttl = Duration::from_secs(600);
+        Self {
+            ttl,
+            interval: ttl * 3 / 4,
+        }
+    }
+}
+
+/// What happened to a watchdog refresh, see [`DeconzClientHandle::subscribe_watchdog_events`].
+///
+/// [`DeconzClientHandle::subscribe_watchdog_events`]: crate::DeconzClientHandle::subscribe_watchdog_events
+#[derive(Debug, Clone)]
+pub enum WatchdogEvent {
+    /// The TTL was rewritten.
+    Refreshed { ttl: Duration },
+    /// The TTL couldn't be rewritten. Unless a later refresh succeeds, the device will reset
+    /// its radio once the current TTL runs out.
+    RefreshFailed(Arc<HandleError>),
+}
+
+/// Handles the response to a watchdog TTL write, reporting the outcome to subscribers.
+pub(crate) fn refresh_response_parser(
+    ttl: Duration,
+    events: broadcast::Sender<WatchdogEvent>,
+) -> ResponseParser {
+    Box::new(move |frame| {
+        let event = match frame.map(|frame| frame.status()) {
+            Ok(StatusCode::Success) => WatchdogEvent::Refreshed { ttl },
+            Ok(status) => WatchdogEvent::RefreshFailed(Arc::new(HandleError::Status(status))),
+            Err(e) => WatchdogEvent::RefreshFailed(Arc::new(e)),
+        };
+        if let WatchdogEvent::RefreshFailed(e) = &event {
+            warn!("failed to refresh the watchdog: {}", e);
+        }
+        events.send(event).ok();
+        None
+    })
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_watchdog_min_ttl() {
+        assert!(WatchdogKeepAlive::new(Duration::from_secs(0)).is_err());
+        assert!(WatchdogKeepAlive::new(Duration::from_secs(1)).is_err());
+        let watchdog = WatchdogKeepAlive::new(Duration::from_secs(2)).unwrap();
+        assert_eq!(watchdog.ttl(), Duration::from_secs(2));
+        assert_eq!(watchdog.interval(), Duration::from_millis(1500));
+    }
+
+    #[test]
+    pub fn test_watchdog_interval() {
+        let ttl = Duration::from_secs(60);
+        assert!(matches!(
+            WatchdogKeepAlive::with_interval(ttl, ttl),
+            Err(WatchdogTtlError::IntervalTooLong { .. })
+        ));
+        assert!(matches!(
+            WatchdogKeepAlive::with_interval(Duration::ZERO, Duration::ZERO),
+            Err(WatchdogTtlError::TooShort { .. })
+        ));
+        let watchdog = WatchdogKeepAlive::with_interval(ttl, Duration::from_secs(30)).unwrap();
+        assert_eq!(watchdog.interval(), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,894 @@
+//! An in-process emulation of a deCONZ device (ConBee/RaspBee firmware), speaking the device side
+//! of the serial protocol over any AsyncRead + AsyncWrite.
+//!
+//! This is meant for testing applications without a physical stick:
+//!
+//! ```ignore
+//! let emulator = DeconzEmulator::new();
+//! let (_task, handle) = DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+//! ```
+//!
+//! Like a ConBee II, the emulated device resets into its bootloader once a written `WatchdogTtl`
+//! runs out, dropping the connection. The next connection then talks to the bootloader, which
+//! accepts a firmware update (see [`crate::firmware`]) and goes back to the application.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Notify,
+    time::Instant,
+};
+use tracing::warn;
+
+use crate::{
+    firmware::{
+        self,
+        bootloader::{BootloaderMessage, BootloaderStream, STATUS_SUCCESS},
+    },
+    frame::{OutgoingPacket, ProtocolError, TryBuf},
+    protocol::{
+        aps::{
+            ConfirmStatus, DestinationAddress, ReadConfirmDataResponse, ReadReceivedDataResponse,
+            ReceivedSendData, SendDataResponse,
+        },
+        device::{
+            ChangeNetworkStateResponse, DeviceState, FirmwareVersion, ReadDeviceStateResponse,
+            ReadFirmwareVersionResponse,
+        },
+        mac::{MACBeaconIndication, MACPollIndication},
+        network_parameters::{parameters, IndexedParameter, Parameter},
+        CommandId, DeconzCommandResponseEncoder, NetworkState, StatusCode,
+    },
+    DeconzFrame, DeconzStream, DeconzStreamError, DeconzTransport,
+};
+
+/// The firmware version reported by default, a ConBee II.
+pub const DEFAULT_FIRMWARE_VERSION: u32 = 0x26720700;
+
+/// The version reported by the emulated bootloader.
+const BOOTLOADER_VERSION: u32 = 0x00000300;
+/// How many bytes of the image the emulated bootloader asks for at once.
+const BOOTLOADER_CHUNK_SIZE: usize = 256;
+/// The update result for an image that doesn't match its CRC32.
+const BOOTLOADER_STATUS_CRC_MISMATCH: u8 = 0x01;
+
+/// An APS data request the emulated device accepted from the host.
+#[derive(Debug, Clone)]
+pub struct EmulatedApsDataRequest {
+    pub request_id: u8,
+    pub destination_address: DestinationAddress,
+    pub destination_endpoint: Option<u8>,
+    pub profile_id: u16,
+    pub cluster_id: u16,
+    pub source_endpoint: u8,
+    pub payload: Vec<u8>,
+    pub tx_options: u8,
+    pub radius: u8,
+}
+
+struct EmulatorState {
+    firmware_version: u32,
+    network_state: NetworkState,
+    parameters: HashMap<u8, Bytes>,
+    /// Slots of indexed parameters, by parameter id and encoded index.
+    indexed_parameters: HashMap<(u8, Bytes), Bytes>,
+    read_only_parameters: HashSet<u8>,
+    aps_data_request_slots: usize,
+    aps_confirm_status: ConfirmStatus,
+    aps_data_requests: Vec<EmulatedApsDataRequest>,
+    pending_confirms: VecDeque<ReadConfirmDataResponse>,
+    pending_indications: VecDeque<ReadReceivedDataResponse>,
+    outbox: VecDeque<DeconzFrame<OutgoingPacket>>,
+    next_unsolicited_sequence_id: u8,
+    ignored_requests: usize,
+    /// Keeps the device joining when asked to go online.
+    stall_joins: bool,
+    in_bootloader: bool,
+    watchdog_reset: Option<Instant>,
+    flashed_image: Option<Vec<u8>>,
+}
+
+/// A firmware update the emulated bootloader is receiving.
+struct PendingUpdate {
+    file_size: usize,
+    crc32: u32,
+    data: Vec<u8>,
+}
+
+impl PendingUpdate {
+    fn next_data_request(&self) -> BootloaderMessage {
+        BootloaderMessage::DataRequest {
+            offset: self.data.len() as u32,
+            length: (self.file_size - self.data.len()).min(BOOTLOADER_CHUNK_SIZE) as u16,
+        }
+    }
+}
+
+impl EmulatorState {
+    fn device_state(&self) -> DeviceState {
+        DeviceState {
+            network_state: self.network_state,
+            apsde_data_confirm: !self.pending_confirms.is_empty(),
+            apsde_data_indication: !self.pending_indications.is_empty(),
+            configuration_changed: false,
+            apsde_data_request_free_slots: self.pending_confirms.len()
+                < self.aps_data_request_slots,
+        }
+    }
+
+    fn set_parameter<T: Parameter>(&mut self, value: T) {
+        let mut payload = BytesMut::new();
+        value.write_frame(&mut payload);
+        self.parameters.insert(T::PARAMETER_ID, payload.freeze());
+    }
+
+    /// Settles on the lowest channel the channel mask allows, like the firmware does when it
+    /// finds all of them equally quiet.
+    fn form_network(&mut self) {
+        let channel_mask = self
+            .parameters
+            .get(&parameters::ChannelMask::PARAMETER_ID)
+            .and_then(|value| value.clone().try_get_u32_le().ok())
+            .map(parameters::ChannelMask::from);
+        if let Some(channel) = channel_mask.and_then(|mask| mask.channels().next()) {
+            self.set_parameter(parameters::CurrentChannel::from(channel));
+        }
+    }
+
+    fn queue_unsolicited(&mut self, response: &impl DeconzCommandResponseEncoder) {
+        let sequence_id = self.next_unsolicited_sequence_id;
+        self.next_unsolicited_sequence_id = sequence_id.wrapping_add(1);
+        self.outbox
+            .push_back(response.as_frame(sequence_id, StatusCode::Success));
+    }
+
+    fn queue_device_state_changed(&mut self) {
+        self.queue_unsolicited(&DeviceStateChanged(self.device_state()));
+    }
+
+    /// Resets into the bootloader if the watchdog ran out. Returns true if it did.
+    fn check_watchdog(&mut self) -> bool {
+        match self.watchdog_reset {
+            Some(deadline) if deadline <= Instant::now() => {
+                self.watchdog_reset = None;
+                self.in_bootloader = true;
+                self.network_state = NetworkState::NetOffline;
+                self.outbox.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<Option<DeconzFrame<OutgoingPacket>>, ProtocolError> {
+        if self.ignored_requests > 0 {
+            self.ignored_requests -= 1;
+            return Ok(None);
+        }
+
+        let sequence_id = frame.sequence_id();
+        let response = match frame.command_id() {
+            CommandId::DeviceState => ReadDeviceStateResponse {
+                device_state: self.device_state(),
+            }
+            .as_frame(sequence_id, StatusCode::Success),
+            CommandId::ChangeNetworkState => {
+                let requested_state: NetworkState = frame.try_get_u8()?.try_into()?;
+                self.network_state = match requested_state {
+                    NetworkState::NetJoining | NetworkState::NetConnected if self.stall_joins => {
+                        NetworkState::NetJoining
+                    }
+                    NetworkState::NetJoining | NetworkState::NetConnected => {
+                        NetworkState::NetConnected
+                    }
+                    NetworkState::NetLeaving | NetworkState::NetOffline => NetworkState::NetOffline,
+                };
+                if self.network_state == NetworkState::NetConnected {
+                    self.form_network();
+                }
+                self.queue_device_state_changed();
+                ChangeNetworkStateResponse {
+                    network_state: self.network_state,
+                }
+                .as_frame(sequence_id, StatusCode::Success)
+            }
+            CommandId::Version => {
+                ReadFirmwareVersionResponse::from(FirmwareVersion(self.firmware_version))
+                    .as_frame(sequence_id, StatusCode::Success)
+            }
+            CommandId::ReadParameter => {
+                let _payload_length = frame.try_get_u16_le()?;
+                let parameter_id = frame.try_get_u8()?;
+                let mut payload = BytesMut::new();
+                payload.put_u8(parameter_id);
+                let value = match index_len(parameter_id) {
+                    Some(index_len) => {
+                        frame.ensure_remaining(index_len)?;
+                        let index = frame.copy_to_bytes(index_len);
+                        self.indexed_parameters.get(&(parameter_id, index))
+                    }
+                    None => self.parameters.get(&parameter_id),
+                };
+                let status = match value {
+                    Some(value) => {
+                        payload.put_slice(value);
+                        StatusCode::Success
+                    }
+                    None => StatusCode::Unsupported,
+                };
+                raw_frame(CommandId::ReadParameter, sequence_id, status, payload)
+            }
+            CommandId::WriteParameter => {
+                let _payload_length = frame.try_get_u16_le()?;
+                let parameter_id = frame.try_get_u8()?;
+                let value_length = frame.remaining();
+                let value = frame.copy_to_bytes(value_length);
+                let status = if let Some(index_len) = index_len(parameter_id) {
+                    // The slot index leads the value.
+                    value.ensure_remaining(index_len)?;
+                    let index = value.slice(..index_len);
+                    self.indexed_parameters.insert((parameter_id, index), value);
+                    StatusCode::Success
+                } else if !self.parameters.contains_key(&parameter_id)
+                    || self.read_only_parameters.contains(&parameter_id)
+                {
+                    StatusCode::Unsupported
+                } else {
+                    if parameter_id == parameters::WatchdogTtl::PARAMETER_ID {
+                        let ttl = value.clone().try_get_u32_le()?;
+                        self.watchdog_reset =
+                            (ttl > 0).then(|| Instant::now() + Duration::from_secs(ttl.into()));
+                    }
+                    self.parameters.insert(parameter_id, value);
+                    StatusCode::Success
+                };
+                let mut payload = BytesMut::new();
+                payload.put_u8(parameter_id);
+                raw_frame(CommandId::WriteParameter, sequence_id, status, payload)
+            }
+            CommandId::ApsDataRequest => self.handle_aps_data_request(frame)?,
+            CommandId::ApsDataConfirm => match self.pending_confirms.pop_front() {
+                Some(mut confirm) => {
+                    confirm.device_state = self.device_state();
+                    confirm.as_frame(sequence_id, StatusCode::Success)
+                }
+                None => self.empty_aps_response(CommandId::ApsDataConfirm, sequence_id),
+            },
+            CommandId::ApsDataIndication => match self.pending_indications.pop_front() {
+                Some(mut indication) => {
+                    indication.device_state = self.device_state();
+                    indication.as_frame(sequence_id, StatusCode::Success)
+                }
+                None => self.empty_aps_response(CommandId::ApsDataIndication, sequence_id),
+            },
+            command_id => raw_frame(
+                command_id,
+                sequence_id,
+                StatusCode::Unsupported,
+                BytesMut::new(),
+            ),
+        };
+
+        Ok(Some(response))
+    }
+
+    fn handle_aps_data_request(
+        &mut self,
+        frame: DeconzFrame<Bytes>,
+    ) -> Result<DeconzFrame<OutgoingPacket>, ProtocolError> {
+        let sequence_id = frame.sequence_id();
+        let ReceivedSendData {
+            request_id,
+            destination_address,
+            destination_endpoint,
+            profile_id,
+            cluster_id,
+            source_endpoint,
+            payload,
+            tx_options,
+            radius,
+        } = ReceivedSendData::from_frame(frame)?;
+
+        if self.network_state != NetworkState::NetConnected
+            || self.pending_confirms.len() >= self.aps_data_request_slots
+        {
+            let status = match self.network_state {
+                NetworkState::NetConnected => StatusCode::Busy,
+                _ => StatusCode::NoNetwork,
+            };
+            return Ok(SendDataResponse {
+                device_state: self.device_state(),
+                request_id,
+            }
+            .as_frame(sequence_id, status));
+        }
+
+        self.aps_data_requests.push(EmulatedApsDataRequest {
+            request_id,
+            destination_address,
+            destination_endpoint,
+            profile_id,
+            cluster_id,
+            source_endpoint,
+            payload,
+            tx_options,
+            radius,
+        });
+        self.pending_confirms.push_back(ReadConfirmDataResponse {
+            device_state: self.device_state(),
+            request_id,
+            destination_address,
+            destination_endpoint,
+            source_endpoint,
+            confirm_status: self.aps_confirm_status,
+        });
+        self.queue_device_state_changed();
+
+        Ok(SendDataResponse {
+            device_state: self.device_state(),
+            request_id,
+        }
+        .as_frame(sequence_id, StatusCode::Success))
+    }
+
+    /// The firmware answers reads of an empty APS queue with an error status and just the device state.
+    fn empty_aps_response(
+        &self,
+        command_id: CommandId,
+        sequence_id: u8,
+    ) -> DeconzFrame<OutgoingPacket> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(self.device_state().into());
+        raw_frame(command_id, sequence_id, StatusCode::Error, payload)
+    }
+}
+
+/// The length of the index leading the value of an indexed parameter.
+fn index_len(parameter_id: u8) -> Option<usize> {
+    match parameter_id {
+        parameters::Endpoint::PARAMETER_ID => Some(1),
+        parameters::LinkKey::PARAMETER_ID => Some(8),
+        _ => None,
+    }
+}
+
+fn raw_frame(
+    command_id: CommandId,
+    sequence_id: u8,
+    status: StatusCode,
+    payload: BytesMut,
+) -> DeconzFrame<OutgoingPacket> {
+    DeconzFrame::new(command_id, sequence_id, Some(payload)).with_status(status)
+}
+
+/// The unsolicited DEVICE_STATE_CHANGED notification. It goes without a payload length, and ends in
+/// a reserved byte.
+struct DeviceStateChanged(DeviceState);
+
+impl DeconzCommandResponseEncoder for DeviceStateChanged {
+    fn command_id(&self) -> CommandId {
+        CommandId::DeviceStateChanged
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(self.0.into());
+        payload.put_u8(0); // Reserved
+        Some(payload)
+    }
+}
+
+struct Shared {
+    state: Mutex<EmulatorState>,
+    outbox_ready: Notify,
+}
+
+/// An emulated deCONZ device. Clones share the same device state, so one clone can be served to
+/// the client while another is kept around to inspect and drive the device.
+#[derive(Clone)]
+pub struct DeconzEmulator {
+    shared: Arc<Shared>,
+}
+
+impl DeconzEmulator {
+    /// Creates an offline coordinator with a default parameter table and a ConBee II firmware version.
+    pub fn new() -> Self {
+        let mut state = EmulatorState {
+            firmware_version: DEFAULT_FIRMWARE_VERSION,
+            network_state: NetworkState::NetOffline,
+            parameters: Default::default(),
+            indexed_parameters: Default::default(),
+            read_only_parameters: Default::default(),
+            aps_data_request_slots: 4,
+            aps_confirm_status: ConfirmStatus::Success,
+            stall_joins: false,
+            aps_data_requests: Default::default(),
+            pending_confirms: Default::default(),
+            pending_indications: Default::default(),
+            outbox: Default::default(),
+            next_unsolicited_sequence_id: 0,
+            ignored_requests: 0,
+            in_bootloader: false,
+            watchdog_reset: None,
+            flashed_image: None,
+        };
+
+        state.set_parameter(parameters::MacAddress::from(0x00212EFFFF000001));
+        state.set_parameter(parameters::NetworkPanId::from(0x1A62));
+        state.set_parameter(parameters::NetworkAddress::from(0x0000));
+        state.set_parameter(parameters::NetworkExtendedPanId::from(0x00212EFFFF000001));
+        state.set_parameter(parameters::APSDesignatedCoordinator::Coordinator);
+        state.set_parameter(parameters::ChannelMask::from(1 << 11));
+        state.set_parameter(parameters::APSExtendedPanId::from(0));
+        state.set_parameter(parameters::TrustCenterAddress::from(0x00212EFFFF000001));
+        state.set_parameter(parameters::SecurityMode::NoMasterButTrustCenterLinkKey);
+        state.set_parameter(parameters::PredefinedNetworkPanId::NotPredefined);
+        state.set_parameter(parameters::NetworkKey::Set([0; 16]));
+        state.set_parameter(parameters::CurrentChannel::from(11));
+        state.set_parameter(parameters::ProtocolVersion::from(0x010B));
+        state.set_parameter(parameters::PermitJoin::from(0));
+        state.set_parameter(parameters::NetworkUpdateId::from(0));
+        state.set_parameter(parameters::WatchdogTtl::from(Duration::from_secs(0)));
+        state.set_parameter(parameters::NetworkFrameCounter::from(0));
+
+        state.read_only_parameters.extend([
+            parameters::NetworkExtendedPanId::PARAMETER_ID,
+            parameters::CurrentChannel::PARAMETER_ID,
+            parameters::ProtocolVersion::PARAMETER_ID,
+        ]);
+
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                outbox_ready: Notify::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, EmulatorState> {
+        self.shared
+            .state
+            .lock()
+            .expect("emulator state lock poisoned")
+    }
+
+    /// Serves the device side of the protocol over the given stream, until the stream ends or
+    /// the device resets.
+    pub async fn run<S: AsyncRead + AsyncWrite + Unpin>(
+        self,
+        stream: S,
+    ) -> Result<(), DeconzStreamError> {
+        let in_bootloader = {
+            let mut state = self.state();
+            state.check_watchdog();
+            state.in_bootloader
+        };
+        if in_bootloader {
+            return self.run_bootloader(stream).await;
+        }
+
+        let mut deconz_stream = DeconzStream::new(stream);
+
+        loop {
+            loop {
+                let frame = match self.state().outbox.pop_front() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                deconz_stream.write_frame(frame).await?;
+            }
+
+            tokio::select! {
+                frame = deconz_stream.next_frame() => match frame {
+                    Some(Ok(frame)) => {
+                        let response = self.state().handle_request(frame);
+                        match response {
+                            Ok(Some(response)) => deconz_stream.write_frame(response).await?,
+                            Ok(None) => {}
+                            Err(e) => warn!("emulator dropping malformed request: {}", e),
+                        }
+                    }
+                    Some(Err(e)) => warn!("emulator dropping invalid frame: {}", e),
+                    None => return Ok(()),
+                },
+                _ = self.shared.outbox_ready.notified() => {}
+                _ = sleep_until_watchdog_reset(self.state().watchdog_reset) => {
+                    if self.state().check_watchdog() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves the bootloader side of a firmware update. Once an update succeeds, the device
+    /// resets into the application and the stream is dropped.
+    async fn run_bootloader<S: AsyncRead + AsyncWrite + Unpin>(
+        self,
+        stream: S,
+    ) -> Result<(), DeconzStreamError> {
+        let mut bootloader_stream = BootloaderStream::new(stream);
+        let mut update: Option<PendingUpdate> = None;
+
+        loop {
+            let message = match bootloader_stream.next_message().await {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    warn!("bootloader dropping invalid message: {}", e);
+                    continue;
+                }
+                None => return Ok(()),
+            };
+
+            match (message, &mut update) {
+                (BootloaderMessage::IdRequest, _) => {
+                    bootloader_stream
+                        .write_message(&BootloaderMessage::IdResponse {
+                            bootloader_version: BOOTLOADER_VERSION,
+                            app_crc: 0,
+                        })
+                        .await?
+                }
+                (
+                    BootloaderMessage::UpdateRequest {
+                        file_size, crc32, ..
+                    },
+                    _,
+                ) => {
+                    let pending_update = PendingUpdate {
+                        file_size: file_size as usize,
+                        crc32,
+                        data: Vec::with_capacity(file_size as usize),
+                    };
+                    bootloader_stream
+                        .write_message(&BootloaderMessage::UpdateResponse {
+                            status: STATUS_SUCCESS,
+                        })
+                        .await?;
+                    bootloader_stream
+                        .write_message(&pending_update.next_data_request())
+                        .await?;
+                    update = Some(pending_update);
+                }
+                (BootloaderMessage::DataResponse { offset, data, .. }, Some(pending_update))
+                    if offset as usize == pending_update.data.len() =>
+                {
+                    pending_update.data.extend_from_slice(&data);
+                    if pending_update.data.len() < pending_update.file_size {
+                        bootloader_stream
+                            .write_message(&pending_update.next_data_request())
+                            .await?;
+                        continue;
+                    }
+
+                    let status = if firmware::crc32(&pending_update.data) == pending_update.crc32 {
+                        STATUS_SUCCESS
+                    } else {
+                        BOOTLOADER_STATUS_CRC_MISMATCH
+                    };
+                    bootloader_stream
+                        .write_message(&BootloaderMessage::UpdateResult { status })
+                        .await?;
+                    if status == STATUS_SUCCESS {
+                        let mut state = self.state();
+                        state.flashed_image = update.take().map(|update| update.data);
+                        state.in_bootloader = false;
+                        return Ok(());
+                    }
+                    update = None;
+                }
+                (message, _) => warn!("bootloader ignoring unexpected message: {:?}", message),
+            }
+        }
+    }
+
+    /// Spawns the emulator on one end of an in-memory duplex stream, and returns the other end
+    /// as a transport for the client.
+    pub fn transport(&self) -> DeconzTransport {
+        let (device_io, host_io) = tokio::io::duplex(4096);
+        tokio::spawn(self.clone().run(device_io));
+        DeconzTransport::from_stream(host_io)
+    }
+
+    /// Returns true while the device is in its bootloader.
+    pub fn in_bootloader(&self) -> bool {
+        self.state().in_bootloader
+    }
+
+    /// Returns the image of the last successful firmware update, without its GCF header.
+    pub fn flashed_image(&self) -> Option<Vec<u8>> {
+        self.state().flashed_image.clone()
+    }
+
+    /// Sets the raw 32-bit firmware version, e.g. 0x26720700.
+    pub fn set_firmware_version(&self, firmware_version: u32) {
+        self.state().firmware_version = firmware_version;
+    }
+
+    pub fn network_state(&self) -> NetworkState {
+        self.state().network_state
+    }
+
+    /// Changes the network state as if the firmware did so on its own, e.g. after losing the network.
+    pub fn set_network_state(&self, network_state: NetworkState) {
+        let mut state = self.state();
+        state.network_state = network_state;
+        state.queue_device_state_changed();
+        drop(state);
+        self.shared.outbox_ready.notify_one();
+    }
+
+    /// Leaves the device joining when it's asked to go online, until the join is settled
+    /// through [`Self::set_network_state`].
+    pub fn stall_joins(&self, stall: bool) {
+        self.state().stall_joins = stall;
+    }
+
+    /// Sets a parameter in the device's parameter table.
+    pub fn set_parameter<T: Parameter>(&self, value: T) {
+        self.state().set_parameter(value);
+    }
+
+    /// Reads a parameter from the device's parameter table.
+    pub fn parameter<T: Parameter>(&self) -> Option<T> {
+        let value = self.state().parameters.get(&T::PARAMETER_ID)?.clone();
+        let frame = DeconzFrame::incoming(CommandId::ReadParameter, StatusCode::Success, &value);
+        T::from_frame(frame).ok()
+    }
+
+    /// Reads the slot of an indexed parameter, like an endpoint. Slots are empty until written.
+    pub fn indexed_parameter<T: IndexedParameter>(&self, index: T::Index) -> Option<T> {
+        let mut encoded_index = BytesMut::new();
+        T::write_index(index, &mut encoded_index);
+        let value = self
+            .state()
+            .indexed_parameters
+            .get(&(T::PARAMETER_ID, encoded_index.freeze()))?
+            .clone();
+        let frame = DeconzFrame::incoming(CommandId::ReadParameter, StatusCode::Success, &value);
+        T::from_frame(frame).ok()
+    }
+
+    /// Makes writes to the parameter fail with [`StatusCode::Unsupported`].
+    pub fn set_read_only<T: Parameter>(&self) {
+        self.state().read_only_parameters.insert(T::PARAMETER_ID);
+    }
+
+    /// Silently drops the next `count` requests, as if they were lost on the wire.
+    pub fn ignore_requests(&self, count: usize) {
+        self.state().ignored_requests = count;
+    }
+
+    /// Sets how many APS data requests may await their confirm at once.
+    pub fn set_aps_data_request_slots(&self, slots: usize) {
+        let mut state = self.state();
+        state.aps_data_request_slots = slots;
+        state.queue_device_state_changed();
+        drop(state);
+        self.shared.outbox_ready.notify_one();
+    }
+
+    /// Sets the status reported in the confirms of future APS data requests.
+    pub fn set_aps_confirm_status(&self, confirm_status: ConfirmStatus) {
+        self.state().aps_confirm_status = confirm_status;
+    }
+
+    /// Returns every APS data request the device has accepted so far.
+    pub fn aps_data_requests(&self) -> Vec<EmulatedApsDataRequest> {
+        self.state().aps_data_requests.clone()
+    }
+
+    /// Queues an APS data indication, as if it was received over the air, and raises the
+    /// apsde_data_indication flag.
+    pub fn inject_aps_data_indication(&self, indication: ReadReceivedDataResponse) {
+        let mut state = self.state();
+        state.pending_indications.push_back(indication);
+        state.queue_device_state_changed();
+        drop(state);
+        self.shared.outbox_ready.notify_one();
+    }
+
+    /// Sends an unsolicited MAC poll indication.
+    pub fn inject_mac_poll_indication(&self, indication: MACPollIndication) {
+        self.state().queue_unsolicited(&indication);
+        self.shared.outbox_ready.notify_one();
+    }
+
+    /// Sends an unsolicited MAC beacon indication.
+    pub fn inject_mac_beacon_indication(&self, indication: MACBeaconIndication) {
+        self.state().queue_unsolicited(&indication);
+        self.shared.outbox_ready.notify_one();
+    }
+}
+
+async fn sleep_until_watchdog_reset(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+impl Default for DeconzEmulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::num::NonZeroU8;
+
+    use super::*;
+    use crate::{
+        frame::DeconzCrc,
+        protocol::{
+            aps::{APSFramePayload, SendData, SendDataOptions, SourceAddress},
+            device::{
+                ChangeNetworkState, ChangeNetworkStateResponse, FirmwareVersionPlatform,
+                ReadFirmwareVersion,
+            },
+            network_parameters::{
+                ReadEndpoint, ReadLinkKey, ReadNetworkPanId, WriteEndpoint, WriteLinkKey,
+                WriteNetworkPanId,
+            },
+            DeconzCommandResponse,
+        },
+        DeconzClient, DeconzClientConfig, HandleError,
+    };
+
+    /// Encodes a frame as it goes over the wire, leaving out the CRC.
+    fn wire_bytes(frame: DeconzFrame<OutgoingPacket>) -> Vec<u8> {
+        let packet = frame.encode();
+        packet[..packet.len() - 2].to_vec()
+    }
+
+    #[test]
+    pub fn test_fixed_size_frames() {
+        // Laid out as in the serial protocol document: neither goes with a payload length.
+        let device_state = DeviceState::from(0x22);
+        assert_eq!(
+            wire_bytes(DeviceStateChanged(device_state).as_frame(0x2F, StatusCode::Success)),
+            [0x0E, 0x2F, 0x00, 0x07, 0x00, 0x22, 0x00]
+        );
+
+        let response = [0x08, 0x05, 0x00, 0x06, 0x00, 0x02];
+        let frame = DeconzCrc::generate(response)
+            .verify_frame(Bytes::copy_from_slice(&response))
+            .unwrap();
+        let (parsed, _) = ChangeNetworkStateResponse::from_frame(frame).unwrap();
+        assert_eq!(parsed.network_state, NetworkState::NetConnected);
+        assert_eq!(
+            wire_bytes(parsed.as_frame(0x05, StatusCode::Success)),
+            response
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_emulator_parameters() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+
+        let version = handle
+            .send_command(ReadFirmwareVersion::new())
+            .await
+            .unwrap();
+        assert_eq!(version.platform, FirmwareVersionPlatform::ArmR21);
+
+        handle
+            .send_command(WriteNetworkPanId::new(0x4321))
+            .await
+            .unwrap();
+        let pan_id = handle.send_command(ReadNetworkPanId::new()).await.unwrap();
+        assert_eq!(*pan_id.value, 0x4321);
+        assert_eq!(
+            *emulator.parameter::<parameters::NetworkPanId>().unwrap(),
+            0x4321
+        );
+
+        let endpoint = parameters::Endpoint {
+            index: 0,
+            descriptor: parameters::SimpleDescriptor {
+                endpoint: 0x01,
+                profile_id: 0x0104,
+                device_id: 0x0005,
+                device_version: 1,
+                in_clusters: vec![0x0000, 0x0006],
+                out_clusters: vec![0x0019],
+            },
+        };
+        handle
+            .send_command(WriteEndpoint::new(endpoint.clone()))
+            .await
+            .unwrap();
+        let read = handle
+            .send_command(ReadEndpoint::at_index(0))
+            .await
+            .unwrap();
+        assert_eq!(read.value, endpoint);
+        assert_eq!(
+            emulator.indexed_parameter::<parameters::Endpoint>(0),
+            Some(endpoint)
+        );
+        assert!(matches!(
+            handle.send_command(ReadEndpoint::at_index(1)).await,
+            Err(HandleError::Status(StatusCode::Unsupported))
+        ));
+
+        let link_key = parameters::LinkKey {
+            ieee_address: 0x00124B0001020304,
+            key: [0xAB; 16],
+        };
+        handle
+            .send_command(WriteLinkKey::new(link_key.clone()))
+            .await
+            .unwrap();
+        let read = handle
+            .send_command(ReadLinkKey::at_index(0x00124B0001020304))
+            .await
+            .unwrap();
+        assert_eq!(read.value, link_key);
+    }
+
+    #[tokio::test]
+    pub async fn test_emulator_aps() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        let mut indications = handle.subscribe_aps_data_indication().await.unwrap();
+
+        handle
+            .send_command(ChangeNetworkState::new(NetworkState::NetConnected))
+            .await
+            .unwrap();
+        assert_eq!(emulator.network_state(), NetworkState::NetConnected);
+
+        handle
+            .send_command(SendData {
+                destination_address: DestinationAddress::NetworkAddress(0x1234),
+                destination_endpoint: 1,
+                profile_id: 0x0104,
+                cluster_id: 0x0006,
+                source_endpoint: 1,
+                payload: APSFramePayload::from_vec(vec![0x01, 0x00, 0x02]).unwrap(),
+                options: SendDataOptions::default(),
+                radius: NonZeroU8::new(5),
+            })
+            .await
+            .unwrap();
+        let requests = emulator.aps_data_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].cluster_id, 0x0006);
+        assert_eq!(requests[0].payload, vec![0x01, 0x00, 0x02]);
+
+        emulator.inject_aps_data_indication(ReadReceivedDataResponse {
+            device_state: 0.into(),
+            destination_address: DestinationAddress::NetworkAddress(0x0000),
+            destination_endpoint: 1,
+            source_address: SourceAddress::NetworkAddress(0x1234),
+            source_endpoint: 1,
+            profile_id: 0x0104,
+            cluster_id: 0x0006,
+            application_specific_data_unit: vec![0x18, 0x01, 0x0B],
+            link_quality_indication: 255,
+            received_signal_strength_indication: -40,
+        });
+        let indication = indications.recv().await.unwrap();
+        assert_eq!(indication.cluster_id, 0x0006);
+        assert_eq!(
+            indication.application_specific_data_unit,
+            vec![0x18, 0x01, 0x0B]
+        );
+        assert_eq!(indication.received_signal_strength_indication, -40);
+    }
+}
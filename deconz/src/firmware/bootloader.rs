@@ -0,0 +1,216 @@
+//! The serial protocol spoken by the ConBee II / RaspBee II (V3) bootloader.
+//!
+//! Messages are SLIP framed and end in the same 2-byte checksum as deCONZ frames. Each message
+//! starts with [`BOOTLOADER_MAGIC`] and a message type, with responses setting the high bit of
+//! the type of the request they answer.
+//!
+//! The message set and layouts follow dresden elektronik's own flasher, [GCFFlasher], which
+//! defines them as the `BTL_*` constants in `gcf.c`. That is the reference to check them against
+//! when a real device disagrees.
+//!
+//! [GCFFlasher]: https://github.com/dresden-elektronik/gcfflasher
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::{
+    frame::{DeconzCrc, ProtocolError, TryBuf},
+    stream::{ResyncingSlipCodec, SlipPacket},
+    DeconzStreamError,
+};
+
+pub(crate) const BOOTLOADER_MAGIC: u8 = 0x81;
+
+const ID_REQUEST: u8 = 0x02;
+const ID_RESPONSE: u8 = 0x82;
+const UPDATE_REQUEST: u8 = 0x03;
+const UPDATE_RESPONSE: u8 = 0x83;
+const DATA_REQUEST: u8 = 0x04;
+const DATA_RESPONSE: u8 = 0x84;
+const UPDATE_RESULT: u8 = 0x05;
+
+/// The status the bootloader reports for accepted requests and successful updates.
+pub(crate) const STATUS_SUCCESS: u8 = 0x00;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BootloaderMessage {
+    /// Sent by the host until the bootloader answers, to keep it from starting the application.
+    IdRequest,
+    IdResponse {
+        bootloader_version: u32,
+        app_crc: u32,
+    },
+    /// Announces the image to the bootloader. The bootloader then pulls the data in chunks.
+    UpdateRequest {
+        file_size: u32,
+        target_address: u32,
+        file_type: u8,
+        crc32: u32,
+    },
+    UpdateResponse {
+        status: u8,
+    },
+    /// Sent by the bootloader, asking for `length` bytes of the image starting at `offset`.
+    DataRequest {
+        offset: u32,
+        length: u16,
+    },
+    DataResponse {
+        status: u8,
+        offset: u32,
+        data: Bytes,
+    },
+    /// Sent by the bootloader once the whole image was received and checked against its CRC.
+    UpdateResult {
+        status: u8,
+    },
+}
+
+impl BootloaderMessage {
+    pub(crate) fn encode(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(BOOTLOADER_MAGIC);
+        match self {
+            Self::IdRequest => buf.put_u8(ID_REQUEST),
+            Self::IdResponse {
+                bootloader_version,
+                app_crc,
+            } => {
+                buf.put_u8(ID_RESPONSE);
+                buf.put_u32_le(*bootloader_version);
+                buf.put_u32_le(*app_crc);
+            }
+            Self::UpdateRequest {
+                file_size,
+                target_address,
+                file_type,
+                crc32,
+            } => {
+                buf.put_u8(UPDATE_REQUEST);
+                buf.put_u32_le(*file_size);
+                buf.put_u32_le(*target_address);
+                buf.put_u8(*file_type);
+                buf.put_u32_le(*crc32);
+            }
+            Self::UpdateResponse { status } => {
+                buf.put_u8(UPDATE_RESPONSE);
+                buf.put_u8(*status);
+            }
+            Self::DataRequest { offset, length } => {
+                buf.put_u8(DATA_REQUEST);
+                buf.put_u32_le(*offset);
+                buf.put_u16_le(*length);
+            }
+            Self::DataResponse {
+                status,
+                offset,
+                data,
+            } => {
+                buf.put_u8(DATA_RESPONSE);
+                buf.put_u8(*status);
+                buf.put_u32_le(*offset);
+                buf.put_u16_le(data.len() as u16);
+                buf.put_slice(data);
+            }
+            Self::UpdateResult { status } => {
+                buf.put_u8(UPDATE_RESULT);
+                buf.put_u8(*status);
+            }
+        }
+        buf
+    }
+
+    pub(crate) fn decode(mut buf: Bytes) -> Result<Self, ProtocolError> {
+        let magic = buf.try_get_u8()?;
+        if magic != BOOTLOADER_MAGIC {
+            return Err(ProtocolError::BadBootloaderMagic(magic));
+        }
+
+        Ok(match buf.try_get_u8()? {
+            ID_REQUEST => Self::IdRequest,
+            ID_RESPONSE => Self::IdResponse {
+                bootloader_version: buf.try_get_u32_le()?,
+                app_crc: buf.try_get_u32_le()?,
+            },
+            UPDATE_REQUEST => Self::UpdateRequest {
+                file_size: buf.try_get_u32_le()?,
+                target_address: buf.try_get_u32_le()?,
+                file_type: buf.try_get_u8()?,
+                crc32: buf.try_get_u32_le()?,
+            },
+            UPDATE_RESPONSE => Self::UpdateResponse {
+                status: buf.try_get_u8()?,
+            },
+            DATA_REQUEST => Self::DataRequest {
+                offset: buf.try_get_u32_le()?,
+                length: buf.try_get_u16_le()?,
+            },
+            DATA_RESPONSE => {
+                let status = buf.try_get_u8()?;
+                let offset = buf.try_get_u32_le()?;
+                let length = buf.try_get_u16_le()? as usize;
+                buf.ensure_remaining(length)?;
+                Self::DataResponse {
+                    status,
+                    offset,
+                    data: buf.split_to(length),
+                }
+            }
+            UPDATE_RESULT => Self::UpdateResult {
+                status: buf.try_get_u8()?,
+            },
+            message_type => return Err(ProtocolError::UnknownBootloaderMessage(message_type)),
+        })
+    }
+}
+
+/// Reads and writes bootloader messages, like [`DeconzStream`](crate::DeconzStream) does for
+/// deCONZ frames.
+pub(crate) struct BootloaderStream<S: AsyncRead + AsyncWrite> {
+    slip_stream: Framed<S, ResyncingSlipCodec>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> BootloaderStream<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self {
+            slip_stream: Framed::new(stream, ResyncingSlipCodec::new()),
+        }
+    }
+
+    /// Returns None if the underlying stream has ended. Invalid messages are yielded as errors.
+    pub(crate) async fn next_message(
+        &mut self,
+    ) -> Option<Result<BootloaderMessage, DeconzStreamError>> {
+        let mut bytes = match self.slip_stream.next().await? {
+            Ok(SlipPacket::Packet(bytes)) => bytes,
+            Ok(SlipPacket::Discarded(e)) | Err(e) => {
+                return Some(Err(DeconzStreamError::SlipCodec(e)))
+            }
+        };
+        if bytes.len() < 2 {
+            return Some(Err(DeconzStreamError::Read(bytes.freeze())));
+        }
+
+        let crc_bytes = bytes.split_off(bytes.len() - 2);
+        let result = DeconzCrc::from_values(&crc_bytes)
+            .map_err(ProtocolError::from)
+            .and_then(|crc| crc.verify(&bytes))
+            .and_then(|_| BootloaderMessage::decode(bytes.freeze()));
+        Some(result.map_err(DeconzStreamError::from))
+    }
+
+    pub(crate) async fn write_message(
+        &mut self,
+        message: &BootloaderMessage,
+    ) -> Result<(), DeconzStreamError> {
+        let mut bytes = message.encode();
+        let crc = DeconzCrc::generate(&bytes);
+        bytes.put_slice(&crc.as_slice());
+        self.slip_stream
+            .send(bytes.freeze())
+            .await
+            .map_err(DeconzStreamError::SlipCodec)
+    }
+}
@@ -0,0 +1,511 @@
+//! Firmware updates for the ConBee II and RaspBee II, from `.gcf` images as published by
+//! dresden elektronik.
+//!
+//! The application firmware is left through its watchdog: [`flash`] writes a short `WatchdogTtl`,
+//! and once it runs out the device resets into its bootloader. The bootloader is then kept from
+//! starting the application by ID requests, and pulls the image from the host chunk by chunk.
+//! The original ConBee and RaspBee (AVR) are reset through their FTDI/GPIO lines instead, which
+//! is not supported.
+
+use std::{future::Future, time::Duration};
+
+use bytes::{Buf, Bytes};
+use thiserror::Error;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, warn};
+
+use crate::{
+    client::{
+        transport::{probe_firmware_version, BoxedTransportIo},
+        TaskError,
+    },
+    protocol::{
+        device::{FirmwareVersionPlatform, ReadFirmwareVersionResponse},
+        network_parameters::WriteWatchdogTtl,
+        CommandId, DeconzCommand, DeconzCommandRequest,
+    },
+    BaudRate, DeconzClientConfig, DeconzStream, DeconzStreamError,
+};
+
+use self::bootloader::{BootloaderMessage, BootloaderStream, STATUS_SUCCESS};
+
+pub(crate) mod bootloader;
+
+/// The magic number every GCF file starts with.
+const GCF_MAGIC: u32 = 0xCAFEFEED;
+/// magic (4) + file type (1) + target address (4) + file size (4) + header checksum (1)
+const GCF_HEADER_LEN: usize = 14;
+/// GCF file types from here on are images for the ARM/R21 platform.
+const GCF_FILE_TYPE_ARM_R21: u8 = 30;
+
+/// The TTL written to make the application firmware reset into the bootloader.
+const BOOTLOADER_RESET_TTL: Duration = Duration::from_secs(2);
+/// The baud rate the bootloader talks at, regardless of the application's.
+const BOOTLOADER_BAUD_RATE: u32 = 115200;
+/// How long the device gets to come back up in bootloader mode.
+const BOOTLOADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the bootloader to answer an ID request, before sending another.
+const ID_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+/// How long the bootloader may go quiet during an update.
+const UPDATE_MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum GcfError {
+    #[error("file too small for a gcf header (len={0})")]
+    TooSmall(usize),
+    #[error("not a gcf file (magic={0:#010x})")]
+    BadMagic(u32),
+    #[error("image size doesn't match the header (expected={expected}, actual={actual})")]
+    SizeMismatch { expected: usize, actual: usize },
+    #[error("gcf header checksum mismatch (expected={expected:#04x}, actual={actual:#04x})")]
+    ChecksumMismatch { expected: u8, actual: u8 },
+}
+
+/// A parsed `.gcf` firmware image.
+#[derive(Debug, Clone)]
+pub struct GcfFile {
+    file_type: u8,
+    target_address: u32,
+    data: Bytes,
+}
+
+impl GcfFile {
+    /// Parses the header of a GCF file, verifies its checksum, and checks it against the size of
+    /// the image that follows.
+    pub fn parse(file: impl Into<Bytes>) -> Result<Self, GcfError> {
+        let mut file = file.into();
+        if file.len() < GCF_HEADER_LEN {
+            return Err(GcfError::TooSmall(file.len()));
+        }
+        let actual_checksum = header_checksum(&file[..GCF_HEADER_LEN - 1]);
+
+        let magic = file.get_u32_le();
+        if magic != GCF_MAGIC {
+            return Err(GcfError::BadMagic(magic));
+        }
+        let file_type = file.get_u8();
+        let target_address = file.get_u32_le();
+        let file_size = file.get_u32_le() as usize;
+        let header_checksum = file.get_u8();
+        if header_checksum != actual_checksum {
+            return Err(GcfError::ChecksumMismatch {
+                expected: header_checksum,
+                actual: actual_checksum,
+            });
+        }
+
+        if file.len() != file_size {
+            return Err(GcfError::SizeMismatch {
+                expected: file_size,
+                actual: file.len(),
+            });
+        }
+
+        Ok(Self {
+            file_type,
+            target_address,
+            data: file,
+        })
+    }
+
+    pub fn file_type(&self) -> u8 {
+        self.file_type
+    }
+
+    /// Where the bootloader writes the image to.
+    pub fn target_address(&self) -> u32 {
+        self.target_address
+    }
+
+    /// The image itself, without the GCF header.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The platform the image was built for, as reported by
+    /// [`ReadFirmwareVersion`](crate::protocol::device::ReadFirmwareVersion).
+    pub fn platform(&self) -> FirmwareVersionPlatform {
+        if self.file_type >= GCF_FILE_TYPE_ARM_R21 {
+            FirmwareVersionPlatform::ArmR21
+        } else {
+            FirmwareVersionPlatform::Avr
+        }
+    }
+
+    /// The CRC32 (IEEE) of the image, which the bootloader verifies it against.
+    pub fn crc32(&self) -> u32 {
+        crc32(&self.data)
+    }
+
+    /// Checks that the image can be flashed onto a device running the given firmware.
+    pub fn check_compatible(
+        &self,
+        firmware_version: &ReadFirmwareVersionResponse,
+    ) -> Result<(), FlashError> {
+        if firmware_version.platform != self.platform() {
+            return Err(FlashError::PlatformMismatch {
+                image: self.platform(),
+                device: firmware_version.platform,
+            });
+        }
+        if self.platform() != FirmwareVersionPlatform::ArmR21 {
+            return Err(FlashError::UnsupportedPlatform(self.platform()));
+        }
+        Ok(())
+    }
+}
+
+/// The checksum in the last byte of a GCF header: the sum of the bytes before it.
+fn header_checksum(header: &[u8]) -> u8 {
+    header.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// The stages of a firmware update, as reported to the progress callback of [`flash`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashProgress {
+    Connecting,
+    /// The application firmware was asked to reset into the bootloader.
+    EnteringBootloader,
+    WaitingForBootloader,
+    /// The bootloader has received `written` of `total` bytes.
+    Uploading {
+        written: usize,
+        total: usize,
+    },
+    /// The bootloader verified the image, and the device is starting it.
+    Done,
+}
+
+#[derive(Error, Debug)]
+pub enum FlashError {
+    #[error(transparent)]
+    Gcf(#[from] GcfError),
+    #[error(transparent)]
+    Task(#[from] TaskError),
+    #[error(transparent)]
+    Stream(#[from] DeconzStreamError),
+    #[error("the device didn't report its firmware version")]
+    NoFirmwareVersion,
+    #[error("the image is for {image:?}, but the device is {device:?}")]
+    PlatformMismatch {
+        image: FirmwareVersionPlatform,
+        device: FirmwareVersionPlatform,
+    },
+    #[error("flashing {0:?} devices is not supported")]
+    UnsupportedPlatform(FirmwareVersionPlatform),
+    #[error("the device didn't come back up in bootloader mode")]
+    BootloaderNotFound,
+    #[error("timed out waiting for the bootloader")]
+    Timeout,
+    #[error("the connection to the bootloader was lost")]
+    ConnectionLost,
+    #[error("the bootloader rejected the update (status={0:#04x})")]
+    BootloaderRejected(u8),
+    #[error("the bootloader asked for data outside the image (offset={offset}, len={length})")]
+    InvalidDataRequest { offset: u32, length: u16 },
+    #[error("unexpected message from the bootloader")]
+    UnexpectedMessage,
+}
+
+/// Flashes a firmware image onto the device, reporting progress along the way.
+///
+/// The device must be running its application firmware, which is checked for compatibility
+/// with the image first. The transport is reopened a number of times while the device resets,
+/// so a [`DeconzTransport::Stream`](crate::DeconzTransport::Stream) can't be used.
+pub async fn flash(
+    config: &DeconzClientConfig,
+    image: &GcfFile,
+    progress: impl FnMut(FlashProgress),
+) -> Result<(), FlashError> {
+    let bootloader_config = DeconzClientConfig {
+        baud_rate: BaudRate::Fixed(BOOTLOADER_BAUD_RATE),
+        ..config.clone()
+    };
+    let bootloader_config = &bootloader_config;
+    let mut connected = false;
+    flash_with(
+        move || {
+            // The first connection goes to the application firmware, at its own baud rate.
+            let config = if connected { bootloader_config } else { config };
+            connected = true;
+            config.connect()
+        },
+        image,
+        progress,
+    )
+    .await
+}
+
+/// Does the work of [`flash`], (re)connecting to the device with `connect`.
+pub(crate) async fn flash_with<C, F>(
+    mut connect: C,
+    image: &GcfFile,
+    mut progress: impl FnMut(FlashProgress),
+) -> Result<(), FlashError>
+where
+    C: FnMut() -> F,
+    F: Future<Output = Result<BoxedTransportIo, TaskError>>,
+{
+    progress(FlashProgress::Connecting);
+    let (transport, firmware_version) = probe_firmware_version(connect().await?).await;
+    let firmware_version = firmware_version.ok_or(FlashError::NoFirmwareVersion)?;
+    image.check_compatible(&firmware_version)?;
+
+    progress(FlashProgress::EnteringBootloader);
+    enter_bootloader(transport).await?;
+
+    progress(FlashProgress::WaitingForBootloader);
+    let mut bootloader = find_bootloader(&mut connect).await?;
+
+    upload(&mut bootloader, image, &mut progress).await?;
+    progress(FlashProgress::Done);
+    Ok(())
+}
+
+/// Writes a short watchdog TTL, after which the application firmware resets into the bootloader.
+async fn enter_bootloader(transport: BoxedTransportIo) -> Result<(), FlashError> {
+    let mut deconz_stream = DeconzStream::new(transport);
+    deconz_stream
+        .write_frame(
+            WriteWatchdogTtl::new(BOOTLOADER_RESET_TTL)
+                .into_request()
+                .as_frame(0),
+        )
+        .await?;
+    let response = async {
+        loop {
+            match deconz_stream.next_frame().await {
+                Some(Ok(frame)) if frame.command_id() == CommandId::WriteParameter => return Ok(()),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) if e.is_io() => return Err(FlashError::Stream(e)),
+                Some(Err(e)) => debug!("ignoring invalid frame: {}", e),
+                None => return Err(FlashError::ConnectionLost),
+            }
+        }
+    };
+    timeout(UPDATE_MESSAGE_TIMEOUT, response)
+        .await
+        .map_err(|_| FlashError::Timeout)?
+}
+
+/// Reconnects until the bootloader answers an ID request.
+async fn find_bootloader<C, F>(
+    connect: &mut C,
+) -> Result<BootloaderStream<BoxedTransportIo>, FlashError>
+where
+    C: FnMut() -> F,
+    F: Future<Output = Result<BoxedTransportIo, TaskError>>,
+{
+    let deadline = Instant::now() + BOOTLOADER_TIMEOUT;
+    while Instant::now() < deadline {
+        let transport = match connect().await {
+            Ok(transport) => transport,
+            Err(e) => {
+                // The device is gone from the bus for a moment while it resets.
+                debug!("waiting for the bootloader: {}", e);
+                sleep(ID_REQUEST_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let mut bootloader = BootloaderStream::new(transport);
+        loop {
+            bootloader
+                .write_message(&BootloaderMessage::IdRequest)
+                .await?;
+            match timeout(ID_REQUEST_INTERVAL, bootloader.next_message()).await {
+                Ok(Some(Ok(BootloaderMessage::IdResponse {
+                    bootloader_version, ..
+                }))) => {
+                    debug!("found bootloader version {:#010x}", bootloader_version);
+                    return Ok(bootloader);
+                }
+                // Still talking to the application firmware, or the reset cut the connection.
+                Ok(None) => break,
+                Ok(Some(_)) | Err(_) if Instant::now() < deadline => continue,
+                Ok(Some(_)) | Err(_) => break,
+            }
+        }
+    }
+
+    Err(FlashError::BootloaderNotFound)
+}
+
+/// Announces the image to the bootloader, and serves its data requests until it reports the result.
+async fn upload(
+    bootloader: &mut BootloaderStream<BoxedTransportIo>,
+    image: &GcfFile,
+    progress: &mut impl FnMut(FlashProgress),
+) -> Result<(), FlashError> {
+    let total = image.data.len();
+    bootloader
+        .write_message(&BootloaderMessage::UpdateRequest {
+            file_size: total as u32,
+            target_address: image.target_address,
+            file_type: image.file_type,
+            crc32: image.crc32(),
+        })
+        .await?;
+
+    let mut written = 0;
+    loop {
+        let message = timeout(UPDATE_MESSAGE_TIMEOUT, bootloader.next_message())
+            .await
+            .map_err(|_| FlashError::Timeout)?;
+        match message {
+            Some(Ok(BootloaderMessage::UpdateResponse { status })) if status == STATUS_SUCCESS => {
+                progress(FlashProgress::Uploading { written, total })
+            }
+            Some(Ok(BootloaderMessage::UpdateResponse { status })) => {
+                return Err(FlashError::BootloaderRejected(status))
+            }
+            Some(Ok(BootloaderMessage::DataRequest { offset, length })) => {
+                let start = offset as usize;
+                let end = start + length as usize;
+                if end > total {
+                    return Err(FlashError::InvalidDataRequest { offset, length });
+                }
+                bootloader
+                    .write_message(&BootloaderMessage::DataResponse {
+                        status: STATUS_SUCCESS,
+                        offset,
+                        data: image.data.slice(start..end),
+                    })
+                    .await?;
+                written = written.max(end);
+                progress(FlashProgress::Uploading { written, total });
+            }
+            Some(Ok(BootloaderMessage::UpdateResult { status })) if status == STATUS_SUCCESS => {
+                return Ok(())
+            }
+            Some(Ok(BootloaderMessage::UpdateResult { status })) => {
+                return Err(FlashError::BootloaderRejected(status))
+            }
+            Some(Ok(BootloaderMessage::IdResponse { .. })) => continue,
+            Some(Ok(message)) => {
+                debug!("unexpected bootloader message: {:?}", message);
+                return Err(FlashError::UnexpectedMessage);
+            }
+            Some(Err(e)) if e.is_io() => return Err(FlashError::Stream(e)),
+            Some(Err(e)) => warn!("ignoring invalid bootloader message: {}", e),
+            None => return Err(FlashError::ConnectionLost),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use bytes::{BufMut, BytesMut};
+
+    use super::bootloader::BOOTLOADER_MAGIC;
+    use super::*;
+    use crate::{emulator::DeconzEmulator, ProtocolError};
+
+    pub fn gcf_file(file_type: u8, data: &[u8]) -> Bytes {
+        let mut file = BytesMut::new();
+        file.put_u32_le(GCF_MAGIC);
+        file.put_u8(file_type);
+        file.put_u32_le(0x5000);
+        file.put_u32_le(data.len() as u32);
+        file.put_u8(header_checksum(&file));
+        file.put_slice(data);
+        file.freeze()
+    }
+
+    #[test]
+    pub fn test_parse_gcf() {
+        let image = GcfFile::parse(gcf_file(GCF_FILE_TYPE_ARM_R21, b"123456789")).unwrap();
+        assert_eq!(image.platform(), FirmwareVersionPlatform::ArmR21);
+        assert_eq!(image.target_address(), 0x5000);
+        assert_eq!(image.data(), b"123456789");
+        assert_eq!(image.crc32(), 0xCBF43926);
+
+        let mut truncated = BytesMut::from(&gcf_file(GCF_FILE_TYPE_ARM_R21, b"1234")[..]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(matches!(
+            GcfFile::parse(truncated),
+            Err(GcfError::SizeMismatch {
+                expected: 4,
+                actual: 3
+            })
+        ));
+        assert!(matches!(
+            GcfFile::parse(&b"not a gcf file"[..]),
+            Err(GcfError::BadMagic(_))
+        ));
+
+        let mut corrupted = BytesMut::from(&gcf_file(GCF_FILE_TYPE_ARM_R21, b"1234")[..]);
+        corrupted[5] ^= 0x01;
+        assert!(matches!(
+            GcfFile::parse(corrupted),
+            Err(GcfError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_decode_bootloader_message() {
+        let message = BootloaderMessage::DataRequest {
+            offset: 0x100,
+            length: 64,
+        };
+        assert_eq!(
+            BootloaderMessage::decode(message.encode().freeze()).unwrap(),
+            message
+        );
+
+        assert!(matches!(
+            BootloaderMessage::decode(Bytes::from_static(&[0x0D, 0x02])),
+            Err(ProtocolError::BadBootloaderMagic(0x0D))
+        ));
+        assert!(matches!(
+            BootloaderMessage::decode(Bytes::from_static(&[BOOTLOADER_MAGIC, 0x7F])),
+            Err(ProtocolError::UnknownBootloaderMessage(0x7F))
+        ));
+    }
+
+    /// Runs the update against the emulator's bootloader, which speaks the same [`bootloader`]
+    /// messages as the client. The layout of those comes from GCFFlasher (see the module), not
+    /// from this test, so it's only as right as that reference.
+    #[tokio::test(start_paused = true)]
+    pub async fn test_flash() {
+        let emulator = DeconzEmulator::new();
+        let connect = || {
+            let (device_io, host_io) = tokio::io::duplex(4096);
+            tokio::spawn(emulator.clone().run(device_io));
+            async { Ok(Box::new(host_io) as BoxedTransportIo) }
+        };
+
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let image = GcfFile::parse(gcf_file(GCF_FILE_TYPE_ARM_R21, &data)).unwrap();
+        let mut stages = Vec::new();
+        flash_with(connect, &image, |progress| stages.push(progress))
+            .await
+            .unwrap();
+
+        assert_eq!(emulator.flashed_image().unwrap(), data);
+        assert_eq!(stages.first(), Some(&FlashProgress::Connecting));
+        assert!(stages.contains(&FlashProgress::Uploading {
+            written: 1000,
+            total: 1000
+        }));
+        assert_eq!(stages.last(), Some(&FlashProgress::Done));
+
+        let avr_image = GcfFile::parse(gcf_file(0x01, &data)).unwrap();
+        let result = flash_with(connect, &avr_image, |_| {}).await;
+        assert!(matches!(
+            result,
+            Err(FlashError::PlatformMismatch {
+                image: FirmwareVersionPlatform::Avr,
+                device: FirmwareVersionPlatform::ArmR21,
+            })
+        ));
+    }
+}
@@ -1,7 +1,6 @@
 use std::{
     convert::TryInto,
     ops::{Deref, DerefMut},
-    u16,
 };
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
@@ -21,10 +20,71 @@ pub enum ProtocolError {
     SmallFrame(usize),
     #[error("frame too large (len={0})")]
     LargeFrame(usize),
+    #[error("frame truncated (needed={needed}, remaining={remaining})")]
+    TruncatedFrame { needed: usize, remaining: usize },
+    #[error("unknown address mode (mode={0})")]
+    UnknownAddressMode(u8),
+    #[error("unexpected parameter (expected={expected}, actual={actual})")]
+    UnexpectedParameter { expected: u8, actual: u8 },
+    #[error("unknown value for parameter (id={parameter_id}, value={value})")]
+    UnknownParameterValue { parameter_id: u8, value: u8 },
+    #[error("crc mismatch (expected={expected:#06x}, actual={actual:#06x})")]
+    CrcMismatch { expected: u16, actual: u16 },
     #[error(transparent)]
     CrcError(#[from] CrcError),
+    #[error("not a bootloader message (magic={0:#04x})")]
+    BadBootloaderMagic(u8),
+    #[error("unknown bootloader message (type={0:#04x})")]
+    UnknownBootloaderMessage(u8),
 }
 
+/// Checked versions of the [`Buf`] getters used by the frame parsers, returning
+/// [`ProtocolError::TruncatedFrame`] instead of panicking when a frame is too short.
+pub(crate) trait TryBuf: Buf {
+    fn ensure_remaining(&self, needed: usize) -> Result<(), ProtocolError> {
+        if self.remaining() < needed {
+            return Err(ProtocolError::TruncatedFrame {
+                needed,
+                remaining: self.remaining(),
+            });
+        }
+        Ok(())
+    }
+
+    fn try_get_u8(&mut self) -> Result<u8, ProtocolError> {
+        self.ensure_remaining(1)?;
+        Ok(self.get_u8())
+    }
+
+    fn try_get_i8(&mut self) -> Result<i8, ProtocolError> {
+        self.ensure_remaining(1)?;
+        Ok(self.get_i8())
+    }
+
+    fn try_get_u16_le(&mut self) -> Result<u16, ProtocolError> {
+        self.ensure_remaining(2)?;
+        Ok(self.get_u16_le())
+    }
+
+    fn try_get_u32_le(&mut self) -> Result<u32, ProtocolError> {
+        self.ensure_remaining(4)?;
+        Ok(self.get_u32_le())
+    }
+
+    fn try_get_u64_le(&mut self) -> Result<u64, ProtocolError> {
+        self.ensure_remaining(8)?;
+        Ok(self.get_u64_le())
+    }
+
+    fn try_copy_to_slice(&mut self, dst: &mut [u8]) -> Result<(), ProtocolError> {
+        self.ensure_remaining(dst.len())?;
+        self.copy_to_slice(dst);
+        Ok(())
+    }
+}
+
+impl<B: Buf> TryBuf for B {}
+
 /// A raw DeconzFrame, just a Bytes container with some header information.
 /// The state of the frame is encoded in the types, Bytes is immutable where BytesMut is a mutable payload.
 /// Likewise, a DeconzFrame<OutgoingPacket> is a container ready to send to the hardware device.
@@ -43,7 +103,7 @@ impl DeconzFrame<Bytes> {
             return Err(ProtocolError::SmallFrame(frame.remaining()));
         }
 
-        let command_id = frame.get_u8().try_into()?;
+        let command_id = frame.get_u8().into();
         let sequence_number = frame.get_u8();
         let status = Some(frame.get_u8().try_into()?);
         let reported_frame_length = frame.get_u16_le();
@@ -60,6 +120,17 @@ impl DeconzFrame<Bytes> {
         })
     }
 
+    /// Builds an incoming frame directly from its parts, bypassing the wire format.
+    #[cfg(any(test, feature = "emulator"))]
+    pub(crate) fn incoming(command_id: CommandId, status: StatusCode, payload: &[u8]) -> Self {
+        Self {
+            command_id,
+            sequence_number: 0,
+            status: Some(status),
+            inner: Bytes::copy_from_slice(payload),
+        }
+    }
+
     /// Returns this frame's command type ID
     pub fn command_id(&self) -> CommandId {
         self.command_id
@@ -110,6 +181,24 @@ impl DeconzFrame<OutgoingPacket> {
         }
     }
 
+    /// Sets the status field, as the device does for its responses.
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Returns the frame as the receiving end parses it, with the payload length (where there is
+    /// one) at the start of the payload.
+    pub(crate) fn to_parsed(&self) -> DeconzFrame<Bytes> {
+        DeconzFrame {
+            command_id: self.command_id,
+            sequence_number: self.sequence_number,
+            // The status byte of requests is reserved, and zero.
+            status: Some(self.status.unwrap_or(StatusCode::Success)),
+            inner: self.packet_bytes().split_off(5).freeze(),
+        }
+    }
+
     fn header_bytes(&self) -> BytesMut {
         // the payload size field demands the header length + command payload length
         let mut frame_len = 5;
@@ -121,9 +210,10 @@ impl DeconzFrame<OutgoingPacket> {
         }
 
         let mut buf = BytesMut::with_capacity(frame_len + 2); // + 2 bytes for the CRC value
-        buf.put_u8(self.command_id as u8);
+        buf.put_u8(self.command_id.into());
         buf.put_u8(self.sequence_number);
-        buf.put_u8(0); // status field is 0 (reserved) for outgoing requests
+        // status field is 0 (reserved) for outgoing requests
+        buf.put_u8(self.status.map_or(0, |status| status as u8));
         buf.put_u16_le(frame_len as u16);
         buf
     }
@@ -179,26 +269,47 @@ impl DeconzCrc {
         Ok(Self(buf[0], buf[1]))
     }
 
+    /// Validates a payload against the CRC value.
+    pub(crate) fn verify(self, payload: &[u8]) -> Result<(), ProtocolError> {
+        let expected = Self::generate(payload);
+        if expected != self {
+            return Err(ProtocolError::CrcMismatch {
+                expected: expected.value(),
+                actual: self.value(),
+            });
+        }
+        Ok(())
+    }
+
     /// Validates a payload against the CRC value and returns a DeconzFrame if succesful.
     pub(crate) fn verify_frame<T: Into<Bytes>>(
         self,
         payload: T,
     ) -> Result<DeconzFrame<Bytes>, ProtocolError> {
-        /*
-            todo: validate incoming CRC :)
-        */
-        DeconzFrame::parse_incoming(payload.into())
+        let payload = payload.into();
+        self.verify(&payload)?;
+        DeconzFrame::parse_incoming(payload)
     }
 
     /// Returns a 2-byte tuple containing the CRC value
     pub(crate) fn as_slice(&self) -> [u8; 2] {
         [self.0, self.1]
     }
+
+    /// Returns the CRC as the little-endian u16 it is transmitted as.
+    pub(crate) fn value(&self) -> u16 {
+        u16::from_le_bytes(self.as_slice())
+    }
 }
 
 #[cfg(test)]
 pub mod test {
-    use crate::protocol::{device::ReadFirmwareVersionRequest, DeconzCommandRequest};
+    use crate::protocol::{
+        device::{
+            FirmwareVersionPlatform, ReadFirmwareVersionRequest, ReadFirmwareVersionResponse,
+        },
+        DeconzCommandRequest, DeconzCommandResponse,
+    };
 
     use super::*;
 
@@ -208,4 +319,48 @@ pub mod test {
         let crc = DeconzCrc::generate(packet_bytes);
         assert_eq!(crc, DeconzCrc(234, 255))
     }
+
+    #[test]
+    pub fn test_verify_frame() {
+        let mut packet_bytes = ReadFirmwareVersionRequest.as_frame(0).packet_bytes();
+        let crc = DeconzCrc::generate(&packet_bytes);
+        assert!(crc
+            .clone()
+            .verify_frame(packet_bytes.clone().freeze())
+            .is_ok());
+
+        packet_bytes[1] ^= 0x01;
+        match crc.verify_frame(packet_bytes.freeze()) {
+            Err(ProtocolError::CrcMismatch { expected, actual }) => {
+                assert_eq!(actual, 0xffea);
+                assert_eq!(expected, 0xffe9);
+            }
+            other => panic!("expected crc mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_version_framing() {
+        // A reserved u32 and no payload length, as laid out in the serial protocol document.
+        let packet_bytes = ReadFirmwareVersionRequest.as_frame(1).packet_bytes();
+        assert_eq!(
+            &packet_bytes[..],
+            &[0x0D, 0x01, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+
+        // The response of a ConBee II on firmware 0x26720700.
+        let response = [0x0D, 0x01, 0x00, 0x09, 0x00, 0x00, 0x07, 0x72, 0x26];
+        let frame = DeconzCrc::generate(response)
+            .verify_frame(Bytes::copy_from_slice(&response))
+            .unwrap();
+        let (response, _) = ReadFirmwareVersionResponse::from_frame(frame).unwrap();
+        assert_eq!(
+            response,
+            ReadFirmwareVersionResponse {
+                major_version: 0x26,
+                minor_version: 0x72,
+                platform: FirmwareVersionPlatform::ArmR21,
+            }
+        );
+    }
 }
@@ -0,0 +1,135 @@
+//! Zigbee 3.0 install codes, which devices are commissioned with instead of the well-known
+//! default trust center link key.
+//!
+//! The link key derived from an install code is provisioned on the coordinator through the
+//! [`LinkKey`](crate::protocol::network_parameters::parameters::LinkKey) parameter, keyed by the
+//! IEEE address of the device that is about to join.
+
+use std::convert::TryInto;
+
+use aes::{
+    cipher::{BlockEncrypt, NewBlockCipher},
+    Aes128,
+};
+use thiserror::Error;
+
+use crate::protocol::{aps::IEEEAddress, network_parameters::parameters::LinkKey};
+
+/// The lengths install codes come in, including their 2-byte CRC.
+const INSTALL_CODE_LENGTHS: [usize; 4] = [8, 10, 14, 18];
+
+const AES_BLOCK_SIZE: usize = 16;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InstallCodeError {
+    #[error("invalid install code length (len={0}), expected 8, 10, 14 or 18 bytes")]
+    InvalidLength(usize),
+    #[error("install code crc mismatch (expected={expected:#06x}, actual={actual:#06x})")]
+    CrcMismatch { expected: u16, actual: u16 },
+}
+
+/// An install code whose CRC has been validated. The CRC is kept as part of the code, as it is
+/// also part of what the link key is derived from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallCode(Vec<u8>);
+
+impl InstallCode {
+    /// Validates an install code as printed on the device, including its trailing CRC16 (which
+    /// is transmitted little-endian).
+    pub fn new(code: &[u8]) -> Result<Self, InstallCodeError> {
+        if !INSTALL_CODE_LENGTHS.contains(&code.len()) {
+            return Err(InstallCodeError::InvalidLength(code.len()));
+        }
+
+        let (data, crc) = code.split_at(code.len() - 2);
+        let expected = crc16_x25(data);
+        let actual = u16::from_le_bytes([crc[0], crc[1]]);
+        if expected != actual {
+            return Err(InstallCodeError::CrcMismatch { expected, actual });
+        }
+
+        Ok(Self(code.to_vec()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Derives the trust center link key, the AES-MMO hash of the install code.
+    pub fn link_key(&self) -> [u8; 16] {
+        aes_mmo_hash(&self.0)
+    }
+
+    /// The link key parameter to provision for the device with the given IEEE address.
+    pub fn link_key_for(&self, ieee_address: IEEEAddress) -> LinkKey {
+        LinkKey {
+            ieee_address,
+            key: self.link_key(),
+        }
+    }
+}
+
+/// CRC-16/X-25, the CRC install codes are protected with.
+fn crc16_x25(data: &[u8]) -> u16 {
+    !data.iter().fold(0xFFFFu16, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u16, |crc, _| {
+            (crc >> 1) ^ (0x8408 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// The Matyas-Meyer-Oseas hash built on AES-128, as specified by Zigbee for messages shorter
+/// than 2^16 bits. The message is padded with a single 1 bit, zeros, and its length in bits.
+fn aes_mmo_hash(message: &[u8]) -> [u8; 16] {
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % AES_BLOCK_SIZE != AES_BLOCK_SIZE - 2 {
+        padded.push(0x00);
+    }
+    padded.extend_from_slice(&((message.len() * 8) as u16).to_be_bytes());
+
+    let mut hash = [0u8; 16];
+    for block in padded.chunks(AES_BLOCK_SIZE) {
+        let block: [u8; AES_BLOCK_SIZE] = block.try_into().expect("padded to whole blocks");
+        let cipher = Aes128::new(&hash.into());
+        let mut encrypted = block.into();
+        cipher.encrypt_block(&mut encrypted);
+        for ((hash, encrypted), block) in hash.iter_mut().zip(encrypted).zip(&block) {
+            *hash = encrypted ^ block;
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_install_code_link_key() {
+        // The example from the Zigbee Base Device Behavior specification.
+        let code = [
+            0x83, 0xFE, 0xD3, 0x40, 0x7A, 0x93, 0x97, 0x23, 0xA5, 0xC6, 0x39, 0xB2, 0x69, 0x16,
+            0xD5, 0x05, 0xC3, 0xB5,
+        ];
+        let install_code = InstallCode::new(&code).unwrap();
+        assert_eq!(
+            install_code.link_key(),
+            [
+                0x66, 0xB6, 0x90, 0x09, 0x81, 0xE1, 0xEE, 0x3C, 0xA4, 0x20, 0x6B, 0x6B, 0x86, 0x1C,
+                0x02, 0xBB
+            ]
+        );
+
+        let mut corrupted = code;
+        corrupted[0] ^= 0x01;
+        assert!(matches!(
+            InstallCode::new(&corrupted),
+            Err(InstallCodeError::CrcMismatch { actual: 0xB5C3, .. })
+        ));
+        assert_eq!(
+            InstallCode::new(&code[..5]),
+            Err(InstallCodeError::InvalidLength(5))
+        );
+    }
+}
@@ -1,10 +1,24 @@
+pub mod backup;
+pub mod capture;
 mod client;
+#[cfg(any(test, feature = "emulator"))]
+pub mod emulator;
+pub mod firmware;
 mod frame;
+pub mod install_code;
 pub mod protocol;
 mod stream;
 
+pub use client::handle::{CancelHandle, DeconzClientHandle, HandleError};
 pub use client::DeconzClient;
 pub use client::DeconzClientConfig;
-pub use client::handle::DeconzClientHandle;
-pub use frame::DeconzFrame;
-pub use stream::DeconzStream;
+pub use client::{
+    detect_baud_rate, ApsFilter, ApsSource, ApsSubscription, ApsSubscriptionOptions, BaudRate,
+    BroadcastLimit, ConnectionState, DeconzEvent, DeconzTransport, DetectedSerial,
+    DeviceCapabilities, EnsureOnlineError, FlowControl, FormNetworkError, FormNetworkOptions,
+    FormedNetwork, FrameDirection, OverflowPolicy, ReconnectPolicy, ShutdownOptions, TappedFrame,
+    TaskError, WatchdogEvent, WatchdogKeepAlive, WatchdogTtlError, DEFAULT_BAUD_RATE_CANDIDATES,
+    LEAVE_NETWORK_TIMEOUT,
+};
+pub use frame::{DeconzFrame, ProtocolError};
+pub use stream::{DeconzStream, DeconzStreamError, DeconzStreamStats};
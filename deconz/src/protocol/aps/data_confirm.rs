@@ -1,13 +1,17 @@
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
-use crate::DeconzFrame;
+use crate::{
+    frame::{ProtocolError, TryBuf},
+    DeconzFrame,
+};
 
 // todo: remove super imports, use crate level relatives.
 use super::super::{
     device::DeviceState, CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse,
+    DeconzCommandResponseEncoder,
 };
 
-use super::DestinationAddress;
+use super::{ConfirmStatus, DestinationAddress};
 
 #[derive(Debug)]
 pub struct ReadConfirmData;
@@ -15,14 +19,14 @@ pub struct ReadConfirmData;
 #[derive(Debug)]
 pub struct ReadConfirmDataRequest;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReadConfirmDataResponse {
     pub device_state: DeviceState,
     pub request_id: u8,
     pub destination_address: DestinationAddress,
     pub destination_endpoint: Option<u8>,
     pub source_endpoint: u8,
-    pub confirm_status: u8,
+    pub confirm_status: ConfirmStatus,
 }
 
 impl ReadConfirmData {
@@ -59,25 +63,23 @@ impl DeconzCommandRequest for ReadConfirmDataRequest {
 }
 
 impl DeconzCommandResponse for ReadConfirmDataResponse {
-    fn from_frame(mut frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        let _payload_length = frame.get_u16_le();
-        let device_state = frame.get_u8().into();
-        let request_id = frame.get_u8();
-        let destination_address_mode = frame.get_u8();
-        let destination_address = match destination_address_mode {
-            0x01 => DestinationAddress::GroupAddress(frame.get_u16_le()),
-            0x02 => DestinationAddress::NetworkAddress(frame.get_u16_le()),
-            0x03 => DestinationAddress::IEEEAddress(frame.get_u64_le()),
-            other => panic!("Unexpected destination address mode: {:?}", other),
-        };
-        let destination_endpoint = match destination_address_mode {
-            0x02 | 0x03 => Some(frame.get_u8()),
-            _ => None,
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        let device_state = frame.try_get_u8()?.into();
+        let request_id = frame.try_get_u8()?;
+        let destination_address = DestinationAddress::from_frame(&mut frame)?;
+        let destination_endpoint = match destination_address {
+            DestinationAddress::NetworkAddress(_) | DestinationAddress::IEEEAddress(_) => {
+                Some(frame.try_get_u8()?)
+            }
+            DestinationAddress::GroupAddress(_) => None,
         };
-        let source_endpoint = frame.get_u8();
-        let confirm_status = frame.get_u8();
+        let source_endpoint = frame.try_get_u8()?;
+        let confirm_status = frame.try_get_u8()?.into();
 
-        (
+        Ok((
             Self {
                 device_state,
                 destination_address,
@@ -87,6 +89,25 @@ impl DeconzCommandResponse for ReadConfirmDataResponse {
                 request_id,
             },
             Some(device_state),
-        )
+        ))
+    }
+}
+
+impl DeconzCommandResponseEncoder for ReadConfirmDataResponse {
+    fn command_id(&self) -> CommandId {
+        CommandId::ApsDataConfirm
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(self.device_state.into());
+        payload.put_u8(self.request_id);
+        self.destination_address.write_frame(&mut payload);
+        if let Some(destination_endpoint) = self.destination_endpoint {
+            payload.put_u8(destination_endpoint);
+        }
+        payload.put_u8(self.source_endpoint);
+        payload.put_u8(self.confirm_status.into());
+        Some(payload)
     }
 }
@@ -1,10 +1,14 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
-use crate::DeconzFrame;
+use crate::{
+    frame::{ProtocolError, TryBuf},
+    DeconzFrame,
+};
 
 use super::{
     super::{
         device::DeviceState, CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse,
+        DeconzCommandResponseEncoder,
     },
     DestinationAddress, SourceAddress,
 };
@@ -69,45 +73,41 @@ impl DeconzCommandRequest for ReadReceivedDataRequest {
 }
 
 impl DeconzCommandResponse for ReadReceivedDataResponse {
-    fn from_frame(mut frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        let _payload_length = frame.get_u16_le();
-        let flags = frame.get_u8();
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        let flags = frame.try_get_u8()?;
         let device_state = flags.into();
 
-        let destination_address_mode = frame.get_u8();
-        let destination_address = match destination_address_mode {
-            0x01 => DestinationAddress::GroupAddress(frame.get_u16_le()),
-            0x02 => DestinationAddress::NetworkAddress(frame.get_u16_le()),
-            0x03 => DestinationAddress::IEEEAddress(frame.get_u64_le()),
-            other => panic!("Unexpected destination address mode: {:?}", other),
-        };
-        let destination_endpoint = frame.get_u8();
+        let destination_address = DestinationAddress::from_frame(&mut frame)?;
+        let destination_endpoint = frame.try_get_u8()?;
 
         // We are expecting 0x04 here, because of our request flags.
-        let source_address = SourceAddress::from_frame(&mut frame);
-        let source_endpoint = frame.get_u8();
+        let source_address = SourceAddress::from_frame(&mut frame)?;
+        let source_endpoint = frame.try_get_u8()?;
 
-        let profile_id = frame.get_u16_le();
-        let cluster_id = frame.get_u16_le();
+        let profile_id = frame.try_get_u16_le()?;
+        let cluster_id = frame.try_get_u16_le()?;
 
-        let application_specific_data_unit_length = frame.get_u16_le();
+        let application_specific_data_unit_length = frame.try_get_u16_le()?;
         let mut application_specific_data_unit =
             vec![0u8; application_specific_data_unit_length as usize];
-        frame.copy_to_slice(&mut application_specific_data_unit);
+        frame.try_copy_to_slice(&mut application_specific_data_unit)?;
 
-        frame.get_u8(); // Reserved
-        frame.get_u8(); // Reserved
+        frame.try_get_u8()?; // Reserved
+        frame.try_get_u8()?; // Reserved
 
-        let link_quality_indication = frame.get_u8();
+        let link_quality_indication = frame.try_get_u8()?;
 
-        frame.get_u8(); // Reserved
-        frame.get_u8(); // Reserved
-        frame.get_u8(); // Reserved
-        frame.get_u8(); // Reserved
+        frame.try_get_u8()?; // Reserved
+        frame.try_get_u8()?; // Reserved
+        frame.try_get_u8()?; // Reserved
+        frame.try_get_u8()?; // Reserved
 
-        let received_signal_strength_indication = frame.get_i8();
+        let received_signal_strength_indication = frame.try_get_i8()?;
 
-        (
+        Ok((
             Self {
                 device_state,
                 destination_address,
@@ -121,6 +121,30 @@ impl DeconzCommandResponse for ReadReceivedDataResponse {
                 received_signal_strength_indication,
             },
             Some(device_state),
-        )
+        ))
+    }
+}
+
+impl DeconzCommandResponseEncoder for ReadReceivedDataResponse {
+    fn command_id(&self) -> CommandId {
+        CommandId::ApsDataIndication
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(self.device_state.into());
+        self.destination_address.write_frame(&mut payload);
+        payload.put_u8(self.destination_endpoint);
+        self.source_address.write_frame(&mut payload);
+        payload.put_u8(self.source_endpoint);
+        payload.put_u16_le(self.profile_id);
+        payload.put_u16_le(self.cluster_id);
+        payload.put_u16_le(self.application_specific_data_unit.len() as u16);
+        payload.put_slice(&self.application_specific_data_unit);
+        payload.put_u16(0); // Reserved
+        payload.put_u8(self.link_quality_indication);
+        payload.put_u32(0); // Reserved
+        payload.put_i8(self.received_signal_strength_indication);
+        Some(payload)
     }
 }
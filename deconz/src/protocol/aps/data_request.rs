@@ -1,13 +1,17 @@
 use std::num::NonZeroU8;
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
-use crate::DeconzFrame;
+use crate::{
+    frame::{ProtocolError, TryBuf},
+    DeconzFrame,
+};
 
 // todo: remove super imports, use crate level relatives.
 use super::{
     super::{
         device::DeviceState, CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse,
+        DeconzCommandResponseEncoder,
     },
     DestinationAddress,
 };
@@ -58,6 +62,7 @@ pub struct SendDataRequest {
 
 #[derive(Debug)]
 pub struct SendDataResponse {
+    pub device_state: DeviceState,
     pub request_id: u8,
 }
 
@@ -68,7 +73,7 @@ impl DeconzCommand for SendData {
     fn into_request(self) -> Self::Request {
         Self::Request {
             inner: self,
-            // todo: plumb down how to generate the request id...
+            // Allocated by the client when the request is sent.
             request_id: 0,
         }
     }
@@ -120,15 +125,88 @@ impl DeconzCommandRequest for SendDataRequest {
 
         Some(payload)
     }
+
+    fn set_aps_request_id(&mut self, request_id: u8) {
+        self.request_id = request_id;
+    }
+
+    fn is_broadcast(&self) -> bool {
+        self.inner.destination_address.is_broadcast()
+    }
+}
+
+/// An APS data request as the device reads it, the inverse of [`SendDataRequest::payload_data`].
+#[derive(Debug, Clone)]
+pub(crate) struct ReceivedSendData {
+    pub request_id: u8,
+    pub destination_address: DestinationAddress,
+    /// Absent for [`DestinationAddress::GroupAddress`].
+    pub destination_endpoint: Option<u8>,
+    pub profile_id: u16,
+    pub cluster_id: u16,
+    pub source_endpoint: u8,
+    pub payload: Vec<u8>,
+    pub tx_options: u8,
+    pub radius: u8,
+}
+
+impl ReceivedSendData {
+    pub(crate) fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        let request_id = frame.try_get_u8()?;
+        let _flags = frame.try_get_u8()?;
+        let destination_address = DestinationAddress::from_frame(&mut frame)?;
+        let destination_endpoint = match destination_address {
+            DestinationAddress::GroupAddress(_) => None,
+            _ => Some(frame.try_get_u8()?),
+        };
+        let profile_id = frame.try_get_u16_le()?;
+        let cluster_id = frame.try_get_u16_le()?;
+        let source_endpoint = frame.try_get_u8()?;
+        let mut payload = vec![0u8; frame.try_get_u16_le()? as usize];
+        frame.try_copy_to_slice(&mut payload)?;
+        let tx_options = frame.try_get_u8()?;
+        let radius = frame.try_get_u8()?;
+
+        Ok(Self {
+            request_id,
+            destination_address,
+            destination_endpoint,
+            profile_id,
+            cluster_id,
+            source_endpoint,
+            payload,
+            tx_options,
+            radius,
+        })
+    }
 }
 
 impl DeconzCommandResponse for SendDataResponse {
-    fn from_frame(mut frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        let _payload_length = frame.get_u16_le();
-        let device_state = frame.get_u8().into();
-        let request_id = frame.get_u8();
-        let response = Self { request_id };
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        let device_state = frame.try_get_u8()?.into();
+        let request_id = frame.try_get_u8()?;
+        let response = Self {
+            device_state,
+            request_id,
+        };
+
+        Ok((response, Some(device_state)))
+    }
+}
+
+impl DeconzCommandResponseEncoder for SendDataResponse {
+    fn command_id(&self) -> CommandId {
+        CommandId::ApsDataRequest
+    }
 
-        (response, Some(device_state))
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(self.device_state.into());
+        payload.put_u8(self.request_id);
+        Some(payload)
     }
 }
@@ -1,26 +1,108 @@
 mod data_confirm;
 mod data_indication;
 mod data_request;
+mod status;
 
-use bytes::{Buf, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 pub use data_confirm::{ReadConfirmData, ReadConfirmDataRequest, ReadConfirmDataResponse};
 pub use data_indication::{ReadReceivedData, ReadReceivedDataRequest, ReadReceivedDataResponse};
+pub(crate) use data_request::ReceivedSendData;
 pub use data_request::{
     APSFramePayload, OverflowError, SendData, SendDataOptions, SendDataRequest, SendDataResponse,
 };
+pub use status::{ApsStatus, ConfirmStatus, MacStatus, NwkStatus};
 
-use crate::DeconzFrame;
+use crate::{
+    frame::{ProtocolError, TryBuf},
+    DeconzFrame,
+};
 
 pub type IEEEAddress = u64;
 pub type NetworkAddress = u16;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DestinationAddress {
     GroupAddress(u16),
+    /// Also carries the broadcast addresses, see [`Broadcast`].
     NetworkAddress(u16),
     IEEEAddress(u64),
 }
 
+/// The broadcast addresses of the network layer, see the Zigbee specification (3.6.5).
+/// Broadcasts are sent to a [`DestinationAddress::NetworkAddress`], which they convert into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Broadcast {
+    /// Every device in the network, including sleepy end devices.
+    AllDevices = 0xFFFF,
+    /// Every device that keeps its receiver on when idle, leaving out sleepy end devices.
+    RxOnWhenIdle = 0xFFFD,
+    /// The routers and the coordinator.
+    Routers = 0xFFFC,
+}
+
+impl Broadcast {
+    pub fn from_network_address(network_address: NetworkAddress) -> Option<Self> {
+        match network_address {
+            0xFFFF => Some(Broadcast::AllDevices),
+            0xFFFD => Some(Broadcast::RxOnWhenIdle),
+            0xFFFC => Some(Broadcast::Routers),
+            _ => None,
+        }
+    }
+}
+
+impl From<Broadcast> for DestinationAddress {
+    fn from(broadcast: Broadcast) -> Self {
+        DestinationAddress::NetworkAddress(broadcast as NetworkAddress)
+    }
+}
+
+impl DestinationAddress {
+    /// Returns the broadcast this address stands for, if it's one of the broadcast addresses.
+    pub fn as_broadcast(&self) -> Option<Broadcast> {
+        match self {
+            DestinationAddress::NetworkAddress(network_address) => {
+                Broadcast::from_network_address(*network_address)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether frames to this address are broadcast through the network. Group sends are too,
+    /// so they take up the same room in every router's broadcast transaction table.
+    pub fn is_broadcast(&self) -> bool {
+        matches!(self, DestinationAddress::GroupAddress(_)) || self.as_broadcast().is_some()
+    }
+
+    /// Reads an address mode byte followed by the address it describes.
+    pub(crate) fn from_frame(frame: &mut DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+        match frame.try_get_u8()? {
+            0x01 => Ok(DestinationAddress::GroupAddress(frame.try_get_u16_le()?)),
+            0x02 => Ok(DestinationAddress::NetworkAddress(frame.try_get_u16_le()?)),
+            0x03 => Ok(DestinationAddress::IEEEAddress(frame.try_get_u64_le()?)),
+            other => Err(ProtocolError::UnknownAddressMode(other)),
+        }
+    }
+
+    /// Writes the address mode byte followed by the address, the inverse of [`Self::from_frame`].
+    pub(crate) fn write_frame(&self, payload: &mut BytesMut) {
+        match self {
+            DestinationAddress::GroupAddress(group_address) => {
+                payload.put_u8(0x01);
+                payload.put_u16_le(*group_address);
+            }
+            DestinationAddress::NetworkAddress(network_address) => {
+                payload.put_u8(0x02);
+                payload.put_u16_le(*network_address);
+            }
+            DestinationAddress::IEEEAddress(ieee_address) => {
+                payload.put_u8(0x03);
+                payload.put_u64_le(*ieee_address);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum SourceAddress {
     NetworkAddress(u16),
@@ -32,16 +114,38 @@ pub enum SourceAddress {
 }
 
 impl SourceAddress {
-    pub(crate) fn from_frame(frame: &mut DeconzFrame<Bytes>) -> Self {
-        let source_address_mode = frame.get_u8();
-        match source_address_mode {
-            0x02 => SourceAddress::NetworkAddress(frame.get_u16_le()),
-            0x03 => SourceAddress::IEEEAddress(frame.get_u64_le()),
+    pub(crate) fn from_frame(frame: &mut DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+        let source_address_mode = frame.try_get_u8()?;
+        Ok(match source_address_mode {
+            0x02 => SourceAddress::NetworkAddress(frame.try_get_u16_le()?),
+            0x03 => SourceAddress::IEEEAddress(frame.try_get_u64_le()?),
             0x04 => SourceAddress::Both {
-                network_address: frame.get_u16_le(),
-                ieee_address: frame.get_u64_le(),
+                network_address: frame.try_get_u16_le()?,
+                ieee_address: frame.try_get_u64_le()?,
             },
-            other => panic!("Unexpected source address mode: {:?}", other),
+            other => return Err(ProtocolError::UnknownAddressMode(other)),
+        })
+    }
+
+    /// Writes the address mode byte followed by the address(es), the inverse of [`Self::from_frame`].
+    pub(crate) fn write_frame(&self, payload: &mut BytesMut) {
+        match self {
+            SourceAddress::NetworkAddress(network_address) => {
+                payload.put_u8(0x02);
+                payload.put_u16_le(*network_address);
+            }
+            SourceAddress::IEEEAddress(ieee_address) => {
+                payload.put_u8(0x03);
+                payload.put_u64_le(*ieee_address);
+            }
+            SourceAddress::Both {
+                network_address,
+                ieee_address,
+            } => {
+                payload.put_u8(0x04);
+                payload.put_u16_le(*network_address);
+                payload.put_u64_le(*ieee_address);
+            }
         }
     }
 
@@ -0,0 +1,165 @@
+/// Declares a status enum along with its conversion from and to the raw status byte.
+macro_rules! status_codes {
+    ($(#[$meta:meta])* $name:ident { $($(#[$variant_meta:meta])* $variant:ident = $value:expr,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant = $value,)*
+        }
+
+        impl $name {
+            fn from_u8(value: u8) -> Option<Self> {
+                match value {
+                    $(x if x == Self::$variant as u8 => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    /// Failures reported by the APS layer, see the Zigbee specification (2.2.5).
+    ApsStatus {
+        AsduTooLong = 0xA0,
+        DefragDeferred = 0xA1,
+        DefragUnsupported = 0xA2,
+        IllegalRequest = 0xA3,
+        InvalidBinding = 0xA4,
+        InvalidGroup = 0xA5,
+        InvalidParameter = 0xA6,
+        /// The destination didn't acknowledge the frame, with APS acks requested.
+        NoAck = 0xA7,
+        NoBoundDevice = 0xA8,
+        NoShortAddress = 0xA9,
+        NotSupported = 0xAA,
+        SecuredLinkKey = 0xAB,
+        SecuredNwkKey = 0xAC,
+        SecurityFail = 0xAD,
+        TableFull = 0xAE,
+        Unsecured = 0xAF,
+        UnsupportedAttribute = 0xB0,
+    }
+}
+
+status_codes! {
+    /// Failures reported by the network layer, see the Zigbee specification (3.7).
+    NwkStatus {
+        InvalidParameter = 0xC1,
+        InvalidRequest = 0xC2,
+        NotPermitted = 0xC3,
+        StartupFailure = 0xC4,
+        AlreadyPresent = 0xC5,
+        SyncFailure = 0xC6,
+        NeighborTableFull = 0xC7,
+        UnknownDevice = 0xC8,
+        UnsupportedAttribute = 0xC9,
+        NoNetworks = 0xCA,
+        MaxFrameCounter = 0xCC,
+        NoKey = 0xCD,
+        BadCcmOutput = 0xCE,
+        /// No route to the destination could be found.
+        RouteDiscoveryFailed = 0xD0,
+        RouteError = 0xD1,
+        BroadcastTableFull = 0xD2,
+        FrameNotBuffered = 0xD3,
+    }
+}
+
+status_codes! {
+    /// Failures reported by the IEEE 802.15.4 MAC layer.
+    MacStatus {
+        BeaconLoss = 0xE0,
+        ChannelAccessFailure = 0xE1,
+        Denied = 0xE2,
+        DisableTrxFailure = 0xE3,
+        FailedSecurityCheck = 0xE4,
+        FrameTooLong = 0xE5,
+        InvalidGts = 0xE6,
+        InvalidHandle = 0xE7,
+        InvalidParameter = 0xE8,
+        /// The next hop didn't acknowledge the frame.
+        NoAck = 0xE9,
+        NoBeacon = 0xEA,
+        NoData = 0xEB,
+        NoShortAddress = 0xEC,
+        OutOfCap = 0xED,
+        PanIdConflict = 0xEE,
+        Realignment = 0xEF,
+        /// The frame was held for a sleeping end device that didn't poll for it in time.
+        TransactionExpired = 0xF0,
+        TransactionOverflow = 0xF1,
+        TxActive = 0xF2,
+        UnavailableKey = 0xF3,
+        UnsupportedAttribute = 0xF4,
+    }
+}
+
+/// The outcome of an APS data request, as reported by its confirm. The status codes of the
+/// APS, network and MAC layers share one byte, so whichever layer gave up reports the failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmStatus {
+    Success,
+    Aps(ApsStatus),
+    Nwk(NwkStatus),
+    Mac(MacStatus),
+    /// A status none of the layers define.
+    Unknown(u8),
+}
+
+impl ConfirmStatus {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Success)
+    }
+}
+
+impl From<u8> for ConfirmStatus {
+    fn from(value: u8) -> Self {
+        if value == 0x00 {
+            return Self::Success;
+        }
+        ApsStatus::from_u8(value)
+            .map(Self::Aps)
+            .or_else(|| NwkStatus::from_u8(value).map(Self::Nwk))
+            .or_else(|| MacStatus::from_u8(value).map(Self::Mac))
+            .unwrap_or(Self::Unknown(value))
+    }
+}
+
+impl From<ConfirmStatus> for u8 {
+    fn from(status: ConfirmStatus) -> Self {
+        match status {
+            ConfirmStatus::Success => 0x00,
+            ConfirmStatus::Aps(status) => status as u8,
+            ConfirmStatus::Nwk(status) => status as u8,
+            ConfirmStatus::Mac(status) => status as u8,
+            ConfirmStatus::Unknown(value) => value,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_confirm_status() {
+        assert_eq!(ConfirmStatus::from(0x00), ConfirmStatus::Success);
+        assert_eq!(
+            ConfirmStatus::from(0xA7),
+            ConfirmStatus::Aps(ApsStatus::NoAck)
+        );
+        assert_eq!(
+            ConfirmStatus::from(0xD0),
+            ConfirmStatus::Nwk(NwkStatus::RouteDiscoveryFailed)
+        );
+        assert_eq!(
+            ConfirmStatus::from(0xE9),
+            ConfirmStatus::Mac(MacStatus::NoAck)
+        );
+        assert_eq!(ConfirmStatus::from(0xCB), ConfirmStatus::Unknown(0xCB));
+        for value in 0..=u8::MAX {
+            assert_eq!(u8::from(ConfirmStatus::from(value)), value);
+        }
+    }
+}
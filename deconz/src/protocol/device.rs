@@ -1,16 +1,20 @@
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    fmt::{Debug, Display},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::frame::{ProtocolError, TryBuf};
 
 use super::{
-    CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse, DeconzFrame,
-    NetworkState,
+    CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse,
+    DeconzCommandResponseEncoder, DeconzFrame, NetworkState,
 };
 
 // Read Firmware Version
 
-#[derive(Debug)]
-#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FirmwareVersionPlatform {
     /// ConBee and RaspBee (AVR)
     Avr,
@@ -29,6 +33,82 @@ impl From<u8> for FirmwareVersionPlatform {
     }
 }
 
+impl From<FirmwareVersionPlatform> for u8 {
+    fn from(platform: FirmwareVersionPlatform) -> Self {
+        match platform {
+            FirmwareVersionPlatform::Avr => 0x05,
+            FirmwareVersionPlatform::ArmR21 => 0x07,
+            FirmwareVersionPlatform::Unknown(i) => i,
+        }
+    }
+}
+
+/// The raw 32-bit firmware version, in the form deCONZ displays it (e.g. `0x26720700` for a
+/// ConBee II). From the most significant byte down: major version, minor version, platform, and a
+/// reserved byte.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion(pub u32);
+
+impl FirmwareVersion {
+    pub fn major_version(&self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    pub fn minor_version(&self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    pub fn platform(&self) -> FirmwareVersionPlatform {
+        ((self.0 >> 8) as u8).into()
+    }
+
+    /// Returns `true` if this is the given version or a newer one. Only the major and minor
+    /// version count, the platform byte is ignored.
+    pub fn is_at_least(&self, other: FirmwareVersion) -> bool {
+        (self.major_version(), self.minor_version())
+            >= (other.major_version(), other.minor_version())
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#010x}", self.0)
+    }
+}
+
+impl Debug for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FirmwareVersion({})", self)
+    }
+}
+
+impl From<u32> for FirmwareVersion {
+    fn from(v: u32) -> Self {
+        Self(v)
+    }
+}
+
+impl From<ReadFirmwareVersionResponse> for FirmwareVersion {
+    fn from(response: ReadFirmwareVersionResponse) -> Self {
+        Self(u32::from_be_bytes([
+            response.major_version,
+            response.minor_version,
+            response.platform.into(),
+            0,
+        ]))
+    }
+}
+
+impl From<FirmwareVersion> for ReadFirmwareVersionResponse {
+    fn from(firmware_version: FirmwareVersion) -> Self {
+        Self {
+            major_version: firmware_version.major_version(),
+            minor_version: firmware_version.minor_version(),
+            platform: firmware_version.platform(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ReadFirmwareVersionRequest;
 
@@ -37,9 +117,15 @@ impl DeconzCommandRequest for ReadFirmwareVersionRequest {
         CommandId::Version
     }
 
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
     fn payload_data(&self) -> Option<BytesMut> {
+        // The serial protocol document has VERSION requests carry four reserved bytes, without a
+        // payload length in front: 9 bytes in all, as the firmware answers with.
         let mut payload = BytesMut::new();
-        payload.put_u16_le(0); // Reserved
+        payload.put_u32_le(0); // Reserved
         Some(payload)
     }
 }
@@ -67,24 +153,47 @@ impl DeconzCommand for ReadFirmwareVersion {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ReadFirmwareVersionResponse {
     pub major_version: u8,
     pub minor_version: u8,
     pub platform: FirmwareVersionPlatform,
 }
 
+impl ReadFirmwareVersionResponse {
+    pub fn firmware_version(&self) -> FirmwareVersion {
+        (*self).into()
+    }
+}
+
 impl DeconzCommandResponse for ReadFirmwareVersionResponse {
-    fn from_frame(mut frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        let _reserved = frame.get_u8();
-        (
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _reserved = frame.try_get_u8()?;
+        Ok((
             Self {
-                platform: frame.get_u8().into(),
-                minor_version: frame.get_u8(),
-                major_version: frame.get_u8(),
+                platform: frame.try_get_u8()?.into(),
+                minor_version: frame.try_get_u8()?,
+                major_version: frame.try_get_u8()?,
             },
             None,
-        )
+        ))
+    }
+}
+
+impl DeconzCommandResponseEncoder for ReadFirmwareVersionResponse {
+    fn command_id(&self) -> CommandId {
+        CommandId::Version
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(0); // Reserved
+        payload.put_u8(self.platform.into());
+        payload.put_u8(self.minor_version);
+        payload.put_u8(self.major_version);
+        Some(payload)
     }
 }
 
@@ -124,6 +233,10 @@ impl DeconzCommandRequest for ReadDeviceStateRequest {
         CommandId::DeviceState
     }
 
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
     fn payload_data(&self) -> Option<BytesMut> {
         let mut payload = BytesMut::new();
         payload.put_u8(0); // Reserved
@@ -134,14 +247,28 @@ impl DeconzCommandRequest for ReadDeviceStateRequest {
 }
 
 impl DeconzCommandResponse for ReadDeviceStateResponse {
-    fn from_frame(mut frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        let flags = frame.get_u8();
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let flags = frame.try_get_u8()?;
         let device_state = flags.into();
-        (Self { device_state }, Some(device_state))
+        Ok((Self { device_state }, Some(device_state)))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl DeconzCommandResponseEncoder for ReadDeviceStateResponse {
+    fn command_id(&self) -> CommandId {
+        CommandId::DeviceState
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(self.device_state.into());
+        Some(payload)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct DeviceState {
     pub network_state: NetworkState,
     pub apsde_data_confirm: bool,
@@ -164,6 +291,17 @@ impl From<u8> for DeviceState {
     }
 }
 
+impl From<DeviceState> for u8 {
+    fn from(device_state: DeviceState) -> Self {
+        let flag = move |set: bool, flag: u8| if set { flag } else { 0 };
+        device_state.network_state as u8
+            | flag(device_state.apsde_data_confirm, 0x04)
+            | flag(device_state.apsde_data_indication, 0x08)
+            | flag(device_state.configuration_changed, 0x10)
+            | flag(device_state.apsde_data_request_free_slots, 0x20)
+    }
+}
+
 pub struct ChangeNetworkState(NetworkState);
 
 impl ChangeNetworkState {
@@ -177,7 +315,10 @@ pub struct ChangeNetworkStateRequest {
     pub state: NetworkState,
 }
 
-pub struct ChangeNetworkStateResponse;
+#[derive(Debug)]
+pub struct ChangeNetworkStateResponse {
+    pub network_state: NetworkState,
+}
 
 impl DeconzCommand for ChangeNetworkState {
     type Request = ChangeNetworkStateRequest;
@@ -201,7 +342,23 @@ impl DeconzCommandRequest for ChangeNetworkStateRequest {
 }
 
 impl DeconzCommandResponse for ChangeNetworkStateResponse {
-    fn from_frame(mut _frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        (Self {}, None)
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        // The firmware echoes the network state it is changing to, right after the header.
+        let network_state = frame.try_get_u8()?.try_into()?;
+        Ok((Self { network_state }, None))
+    }
+}
+
+impl DeconzCommandResponseEncoder for ChangeNetworkStateResponse {
+    fn command_id(&self) -> CommandId {
+        CommandId::ChangeNetworkState
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(self.network_state as u8);
+        Some(payload)
     }
 }
@@ -1,13 +1,14 @@
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::{
-    protocol::{aps::SourceAddress, device::DeviceState},
+    frame::{ProtocolError, TryBuf},
+    protocol::{aps::SourceAddress, device::DeviceState, CommandId},
     DeconzFrame,
 };
 
-use super::super::DeconzCommandResponse;
+use super::super::{DeconzCommandResponse, DeconzCommandResponseEncoder};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MACBeaconIndication {
     /// "16-bit short address of a router or coordinator (0x0000)"
     /// I have no idea what that means, but thats from the deconz serial
@@ -26,20 +27,22 @@ pub struct MACBeaconIndication {
 }
 
 impl DeconzCommandResponse for MACBeaconIndication {
-    fn from_frame(mut frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        let _payload_length = frame.get_u16_le();
-        let source_address = SourceAddress::NetworkAddress(frame.get_u16_le());
-        let network_pan_id = frame.get_u16_le();
-        let channel = frame.get_u8();
-        let flags = frame.get_u8();
-        let update_id = frame.get_u8();
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        let source_address = SourceAddress::NetworkAddress(frame.try_get_u16_le()?);
+        let network_pan_id = frame.try_get_u16_le()?;
+        let channel = frame.try_get_u8()?;
+        let flags = frame.try_get_u8()?;
+        let update_id = frame.try_get_u8()?;
 
         let data = match frame.has_remaining() {
             true => Some(frame.to_vec()),
             false => None,
         };
 
-        (
+        Ok((
             Self {
                 source_address,
                 network_pan_id,
@@ -49,6 +52,34 @@ impl DeconzCommandResponse for MACBeaconIndication {
                 update_id,
             },
             None,
-        )
+        ))
+    }
+}
+
+impl DeconzCommandResponseEncoder for MACBeaconIndication {
+    fn command_id(&self) -> CommandId {
+        CommandId::MacBeaconIndication
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let source_address = match self.source_address {
+            SourceAddress::NetworkAddress(network_address)
+            | SourceAddress::Both {
+                network_address, ..
+            } => network_address,
+            // Beacons only ever carry a short address.
+            SourceAddress::IEEEAddress(_) => 0xFFFF,
+        };
+
+        let mut payload = BytesMut::new();
+        payload.put_u16_le(source_address);
+        payload.put_u16_le(self.network_pan_id);
+        payload.put_u8(self.channel);
+        payload.put_u8(self.flags);
+        payload.put_u8(self.update_id);
+        if let Some(data) = &self.data {
+            payload.put_slice(data);
+        }
+        Some(payload)
     }
 }
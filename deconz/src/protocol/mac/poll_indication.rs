@@ -1,13 +1,14 @@
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::{
-    protocol::{aps::SourceAddress, device::DeviceState},
+    frame::{ProtocolError, TryBuf},
+    protocol::{aps::SourceAddress, device::DeviceState, CommandId},
     DeconzFrame,
 };
 
-use super::super::DeconzCommandResponse;
+use super::super::{DeconzCommandResponse, DeconzCommandResponseEncoder};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MACPollIndication {
     pub source_address: SourceAddress,
     /// The received LQI value 0–255
@@ -17,28 +18,30 @@ pub struct MACPollIndication {
     pub neighbor_table_state: Option<NeighborTableState>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NeighborTableState {
     pub life_time: u32,
     pub device_timeout: u32,
 }
 
 impl DeconzCommandResponse for MACPollIndication {
-    fn from_frame(mut frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>) {
-        let _payload_length = frame.get_u16_le();
-        let source_address = SourceAddress::from_frame(&mut frame);
-        let link_quality_indicator = frame.get_u8();
-        let received_signal_strength_indication = frame.get_i8();
+    fn from_frame(
+        mut frame: DeconzFrame<Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        let source_address = SourceAddress::from_frame(&mut frame)?;
+        let link_quality_indicator = frame.try_get_u8()?;
+        let received_signal_strength_indication = frame.try_get_i8()?;
 
         let neighbor_table_state = match frame.has_remaining() {
             true => Some(NeighborTableState {
-                life_time: frame.get_u32_le(),
-                device_timeout: frame.get_u32_le(),
+                life_time: frame.try_get_u32_le()?,
+                device_timeout: frame.try_get_u32_le()?,
             }),
             false => None,
         };
 
-        (
+        Ok((
             Self {
                 source_address,
                 received_signal_strength_indication,
@@ -46,6 +49,24 @@ impl DeconzCommandResponse for MACPollIndication {
                 link_quality_indicator,
             },
             None,
-        )
+        ))
+    }
+}
+
+impl DeconzCommandResponseEncoder for MACPollIndication {
+    fn command_id(&self) -> CommandId {
+        CommandId::MacPollIndication
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        self.source_address.write_frame(&mut payload);
+        payload.put_u8(self.link_quality_indicator);
+        payload.put_i8(self.received_signal_strength_indication);
+        if let Some(neighbor_table_state) = &self.neighbor_table_state {
+            payload.put_u32_le(neighbor_table_state.life_time);
+            payload.put_u32_le(neighbor_table_state.device_timeout);
+        }
+        Some(payload)
     }
 }
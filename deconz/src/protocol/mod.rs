@@ -2,13 +2,14 @@ pub mod aps;
 pub mod device;
 pub mod mac;
 pub mod network_parameters;
+pub mod raw;
 
 use std::convert::TryFrom;
 use std::fmt::Debug;
 
 use bytes::{Bytes, BytesMut};
 
-use self::device::DeviceState;
+use self::device::{DeviceState, FirmwareVersion};
 
 use super::{
     frame::{OutgoingPacket, ProtocolError},
@@ -22,6 +23,37 @@ pub trait DeconzCommandRequest: Debug + Send + 'static {
     /// Returns the payload to proceed the header
     fn payload_data(&self) -> Option<BytesMut>;
 
+    /// Returns `true` if sending the request more than once has the same effect as sending it
+    /// once, which makes it safe to retry after a timeout.
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+
+    /// The oldest protocol version (see [`parameters::ProtocolVersion`]) that understands the
+    /// request, if older ones don't. The client refuses to send it to those devices.
+    ///
+    /// [`parameters::ProtocolVersion`]: network_parameters::parameters::ProtocolVersion
+    fn min_protocol_version(&self) -> Option<u16> {
+        None
+    }
+
+    /// The oldest firmware version that understands the request, if older ones don't. Compared by
+    /// major and minor version only, see [`FirmwareVersion::is_at_least`]. The client refuses to
+    /// send it to devices running older firmware.
+    fn min_firmware_version(&self) -> Option<FirmwareVersion> {
+        None
+    }
+
+    /// Called by the client with the request id it allocated, for APS data requests. The id is
+    /// what the device's confirm for the request is matched up by.
+    fn set_aps_request_id(&mut self, _request_id: u8) {}
+
+    /// Whether this is an APS data request that gets broadcast through the network, which the
+    /// client paces according to [`BroadcastLimit`](crate::BroadcastLimit).
+    fn is_broadcast(&self) -> bool {
+        false
+    }
+
     /// Concatenates the packet payload onto a common header
     fn as_frame(&self, sequence_number: u8) -> DeconzFrame<OutgoingPacket> {
         DeconzFrame::new(self.command_id(), sequence_number, self.payload_data())
@@ -41,11 +73,23 @@ pub trait DeconzCommand: Sized {
 
 pub trait DeconzCommandResponse: Sized + Send + 'static {
     /// Parses an incoming payload frame into the right type
-    fn from_frame(frame: DeconzFrame<Bytes>) -> (Self, Option<DeviceState>);
+    fn from_frame(frame: DeconzFrame<Bytes>) -> Result<(Self, Option<DeviceState>), ProtocolError>;
 }
 
-#[derive(Debug)]
-struct UnsupportedValueError<T>(T);
+/// The device side of [`DeconzCommandResponse`], encoding a response the way the firmware sends it.
+/// Used to emulate a device, e.g. in tests.
+pub trait DeconzCommandResponseEncoder {
+    fn command_id(&self) -> CommandId;
+
+    /// Returns the payload to proceed the header, the inverse of [`DeconzCommandResponse::from_frame`]
+    fn payload_data(&self) -> Option<BytesMut>;
+
+    /// Concatenates the packet payload onto a common header
+    fn as_frame(&self, sequence_number: u8, status: StatusCode) -> DeconzFrame<OutgoingPacket> {
+        DeconzFrame::new(self.command_id(), sequence_number, self.payload_data())
+            .with_status(status)
+    }
+}
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
@@ -73,7 +117,7 @@ impl TryFrom<u8> for StatusCode {
             x if x == Self::Error as u8 => Ok(Self::Error),
             x if x == Self::NoNetwork as u8 => Ok(Self::NoNetwork),
             x if x == Self::InvalidValue as u8 => Ok(Self::InvalidValue),
-            x => Err(ProtocolError::UnknownCommandId(x)),
+            x => Err(ProtocolError::UnknownStatusCode(x)),
         }
     }
 }
@@ -108,9 +152,38 @@ impl TryFrom<u8> for NetworkState {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-#[repr(u8)]
-pub enum CommandId {
+/// Declares the known command ids along with their conversion from and to the raw id byte.
+macro_rules! command_ids {
+    ($($variant:ident = $value:expr,)*) => {
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        pub enum CommandId {
+            $($variant,)*
+            /// An id this crate doesn't know about, like those of commands added by newer
+            /// firmware. Never holds the id of one of the known commands. See [`RawCommand`](raw::RawCommand).
+            Unknown(u8),
+        }
+
+        impl From<u8> for CommandId {
+            fn from(value: u8) -> Self {
+                match value {
+                    $($value => Self::$variant,)*
+                    x => Self::Unknown(x),
+                }
+            }
+        }
+
+        impl From<CommandId> for u8 {
+            fn from(command_id: CommandId) -> Self {
+                match command_id {
+                    $(CommandId::$variant => $value,)*
+                    CommandId::Unknown(x) => x,
+                }
+            }
+        }
+    };
+}
+
+command_ids! {
     DeviceState = 0x07,
     ChangeNetworkState = 0x08,
     ReadParameter = 0x0A,
@@ -126,29 +199,13 @@ pub enum CommandId {
 }
 
 impl CommandId {
+    /// Whether the payload of the command starts with its length. The serial protocol document
+    /// lays out the device state, network state, firmware version and device state changed frames
+    /// at a fixed size instead.
     pub fn includes_payload_len(&self) -> bool {
-        !matches!(self, Self::DeviceState | Self::ChangeNetworkState)
-    }
-}
-
-impl TryFrom<u8> for CommandId {
-    type Error = ProtocolError;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            x if x == Self::DeviceState as u8 => Ok(Self::DeviceState),
-            x if x == Self::ChangeNetworkState as u8 => Ok(Self::ChangeNetworkState),
-            x if x == Self::ReadParameter as u8 => Ok(Self::ReadParameter),
-            x if x == Self::WriteParameter as u8 => Ok(Self::WriteParameter),
-            x if x == Self::DeviceStateChanged as u8 => Ok(Self::DeviceStateChanged),
-            x if x == Self::Version as u8 => Ok(Self::Version),
-            x if x == Self::ApsDataRequest as u8 => Ok(Self::ApsDataRequest),
-            x if x == Self::ApsDataConfirm as u8 => Ok(Self::ApsDataConfirm),
-            x if x == Self::ApsDataIndication as u8 => Ok(Self::ApsDataIndication),
-            x if x == Self::MacPollIndication as u8 => Ok(Self::MacPollIndication),
-            x if x == Self::MacBeaconIndication as u8 => Ok(Self::MacBeaconIndication),
-            x if x == Self::UpdateBootloader as u8 => Ok(Self::UpdateBootloader),
-            x => Err(ProtocolError::UnknownCommandId(x)),
-        }
+        !matches!(
+            self,
+            Self::DeviceState | Self::ChangeNetworkState | Self::Version | Self::DeviceStateChanged
+        )
     }
 }
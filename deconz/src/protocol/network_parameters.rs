@@ -1,12 +1,16 @@
 use std::fmt::Debug;
 use std::{any::type_name, marker::PhantomData};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
-use crate::DeconzFrame;
+use crate::{
+    frame::{ProtocolError, TryBuf},
+    DeconzFrame,
+};
 
 use super::{
     device::DeviceState, CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse,
+    DeconzCommandResponseEncoder,
 };
 
 mod sealed {
@@ -15,30 +19,41 @@ mod sealed {
 
 use sealed::Sealed;
 
-pub trait Parameter: Debug + Sealed + Send + 'static {
+pub trait Parameter: Debug + Sealed + Send + Sized + 'static {
     // todo: should we enumify this?
     const PARAMETER_ID: u8;
+    /// The oldest protocol version that knows the parameter, if not all of them do.
+    const MIN_PROTOCOL_VERSION: Option<u16> = None;
 
-    fn from_frame(frame: DeconzFrame<Bytes>) -> Self;
+    fn from_frame(frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError>;
 
     fn write_frame(&self, payload: &mut BytesMut);
 }
 
+/// A parameter with several slots, where reads name the slot by its index, see
+/// [`ReadParameter::at_index`]. The index is also part of the value.
+pub trait IndexedParameter: Parameter {
+    type Index;
+
+    fn write_index(index: Self::Index, payload: &mut BytesMut);
+}
+
 pub mod parameters {
     use std::{fmt::Display, ops::Deref, str::FromStr, time::Duration};
 
     use bytes::{Buf, BufMut};
 
-    use super::{Bytes, DeconzFrame, Parameter, Sealed};
+    use super::{Bytes, DeconzFrame, IndexedParameter, Parameter, ProtocolError, Sealed, TryBuf};
+    use crate::protocol::aps::IEEEAddress;
 
     #[derive(Debug)]
     pub struct ParseParameterError<S = &'static str>(S)
     where
         S: AsRef<str>;
 
-    impl<S: AsRef<str>> ToString for ParseParameterError<S> {
-        fn to_string(&self) -> String {
-            format!("ParseParameterError: {}", self.0.as_ref())
+    impl<S: AsRef<str>> Display for ParseParameterError<S> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "ParseParameterError: {}", self.0.as_ref())
         }
     }
 
@@ -49,8 +64,8 @@ pub mod parameters {
     impl Parameter for MacAddress {
         const PARAMETER_ID: u8 = 0x01;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u64_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u64_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -90,8 +105,8 @@ pub mod parameters {
     impl Parameter for NetworkPanId {
         const PARAMETER_ID: u8 = 0x05;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u16_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u16_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -126,8 +141,8 @@ pub mod parameters {
     impl Parameter for NetworkAddress {
         const PARAMETER_ID: u8 = 0x07;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u16_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u16_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -162,8 +177,8 @@ pub mod parameters {
     impl Parameter for NetworkExtendedPanId {
         const PARAMETER_ID: u8 = 0x08;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u64_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u64_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -179,6 +194,12 @@ pub mod parameters {
         }
     }
 
+    impl From<u64> for NetworkExtendedPanId {
+        fn from(v: u64) -> Self {
+            Self(v)
+        }
+    }
+
     impl Display for NetworkExtendedPanId {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             write!(f, "0x{:016x}", self.0)
@@ -195,11 +216,14 @@ pub mod parameters {
     impl Parameter for APSDesignatedCoordinator {
         const PARAMETER_ID: u8 = 0x09;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            match frame.get_u8() {
-                x if x == Self::Coordinator as u8 => Self::Coordinator,
-                x if x == Self::Router as u8 => Self::Router,
-                x => panic!("Unexpected coordinator type: {:?}", x),
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            match frame.try_get_u8()? {
+                x if x == Self::Coordinator as u8 => Ok(Self::Coordinator),
+                x if x == Self::Router as u8 => Ok(Self::Router),
+                value => Err(ProtocolError::UnknownParameterValue {
+                    parameter_id: Self::PARAMETER_ID,
+                    value,
+                }),
             }
         }
 
@@ -230,15 +254,35 @@ pub mod parameters {
         }
     }
 
-    #[derive(Debug)]
+    /// The channels Zigbee uses in the 2.4 GHz band.
+    pub const ZIGBEE_CHANNELS: std::ops::RangeInclusive<u8> = 11..=26;
+
+    /// The channels the device may form or join a network on, one bit per channel.
+    #[derive(Debug, Clone, Copy, PartialEq)]
     pub struct ChannelMask(u32);
 
+    impl ChannelMask {
+        pub fn from_channels(channels: impl IntoIterator<Item = u8>) -> Self {
+            Self(
+                channels
+                    .into_iter()
+                    .filter(|channel| ZIGBEE_CHANNELS.contains(channel))
+                    .fold(0, |mask, channel| mask | 1 << channel),
+            )
+        }
+
+        /// The Zigbee channels set in the mask, lowest first.
+        pub fn channels(self) -> impl Iterator<Item = u8> {
+            ZIGBEE_CHANNELS.filter(move |channel| self.0 & 1 << channel != 0)
+        }
+    }
+
     impl Sealed for ChannelMask {}
     impl Parameter for ChannelMask {
         const PARAMETER_ID: u8 = 0x0A;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u32_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u32_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -267,8 +311,8 @@ pub mod parameters {
     impl Parameter for APSExtendedPanId {
         const PARAMETER_ID: u8 = 0x0B;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u64_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u64_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -303,8 +347,8 @@ pub mod parameters {
     impl Parameter for TrustCenterAddress {
         const PARAMETER_ID: u8 = 0x0E;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u64_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u64_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -344,15 +388,20 @@ pub mod parameters {
     impl Parameter for SecurityMode {
         const PARAMETER_ID: u8 = 0x10;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            match frame.get_u8() {
-                x if x == Self::NoSecurity as u8 => Self::NoSecurity,
-                x if x == Self::PreconfiguredNetworkKey as u8 => Self::PreconfiguredNetworkKey,
-                x if x == Self::NetworkKeyFromTrustCenter as u8 => Self::NetworkKeyFromTrustCenter,
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            match frame.try_get_u8()? {
+                x if x == Self::NoSecurity as u8 => Ok(Self::NoSecurity),
+                x if x == Self::PreconfiguredNetworkKey as u8 => Ok(Self::PreconfiguredNetworkKey),
+                x if x == Self::NetworkKeyFromTrustCenter as u8 => {
+                    Ok(Self::NetworkKeyFromTrustCenter)
+                }
                 x if x == Self::NoMasterButTrustCenterLinkKey as u8 => {
-                    Self::NoMasterButTrustCenterLinkKey
+                    Ok(Self::NoMasterButTrustCenterLinkKey)
                 }
-                x => panic!("Unexpected security mode: {:?}", x),
+                value => Err(ProtocolError::UnknownParameterValue {
+                    parameter_id: Self::PARAMETER_ID,
+                    value,
+                }),
             }
         }
 
@@ -375,6 +424,107 @@ pub mod parameters {
         }
     }
 
+    /// One of the coordinator's own application endpoints, as it answers Simple_Desc and
+    /// Match_Desc requests with.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct SimpleDescriptor {
+        pub endpoint: u8,
+        pub profile_id: u16,
+        pub device_id: u16,
+        pub device_version: u8,
+        pub in_clusters: Vec<u16>,
+        pub out_clusters: Vec<u16>,
+    }
+
+    impl SimpleDescriptor {
+        /// How many clusters a descriptor can list in each direction, as the count is a single
+        /// byte. Longer lists are written wrapped around, so they have to be rejected up front.
+        pub const MAX_CLUSTERS: usize = u8::MAX as usize;
+
+        fn read_clusters(frame: &mut DeconzFrame<Bytes>) -> Result<Vec<u16>, ProtocolError> {
+            let count = frame.try_get_u8()?;
+            (0..count).map(|_| frame.try_get_u16_le()).collect()
+        }
+
+        fn write_clusters(clusters: &[u16], payload: &mut bytes::BytesMut) {
+            payload.put_u8(clusters.len() as u8);
+            for cluster in clusters {
+                payload.put_u16_le(*cluster);
+            }
+        }
+    }
+
+    impl Display for SimpleDescriptor {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let clusters = |clusters: &[u16]| {
+                clusters
+                    .iter()
+                    .map(|cluster| format!("0x{:04x}", cluster))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            write!(
+                f,
+                "endpoint={} profile=0x{:04x} device=0x{:04x} version={} in=[{}] out=[{}]",
+                self.endpoint,
+                self.profile_id,
+                self.device_id,
+                self.device_version,
+                clusters(&self.in_clusters),
+                clusters(&self.out_clusters)
+            )
+        }
+    }
+
+    /// The simple descriptor in one of the device's endpoint slots.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Endpoint {
+        pub index: u8,
+        pub descriptor: SimpleDescriptor,
+    }
+
+    impl Sealed for Endpoint {}
+    impl Parameter for Endpoint {
+        const PARAMETER_ID: u8 = 0x13;
+
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self {
+                index: frame.try_get_u8()?,
+                descriptor: SimpleDescriptor {
+                    endpoint: frame.try_get_u8()?,
+                    profile_id: frame.try_get_u16_le()?,
+                    device_id: frame.try_get_u16_le()?,
+                    device_version: frame.try_get_u8()?,
+                    in_clusters: SimpleDescriptor::read_clusters(&mut frame)?,
+                    out_clusters: SimpleDescriptor::read_clusters(&mut frame)?,
+                },
+            })
+        }
+
+        fn write_frame(&self, payload: &mut bytes::BytesMut) {
+            payload.put_u8(self.index);
+            payload.put_u8(self.descriptor.endpoint);
+            payload.put_u16_le(self.descriptor.profile_id);
+            payload.put_u16_le(self.descriptor.device_id);
+            payload.put_u8(self.descriptor.device_version);
+            SimpleDescriptor::write_clusters(&self.descriptor.in_clusters, payload);
+            SimpleDescriptor::write_clusters(&self.descriptor.out_clusters, payload);
+        }
+    }
+    impl IndexedParameter for Endpoint {
+        type Index = u8;
+
+        fn write_index(index: Self::Index, payload: &mut bytes::BytesMut) {
+            payload.put_u8(index);
+        }
+    }
+
+    impl Display for Endpoint {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "[{}] {}", self.index, self.descriptor)
+        }
+    }
+
     #[derive(Debug, PartialEq, Clone, Copy)]
     pub enum PredefinedNetworkPanId {
         /// The [`NetworkPanId`] will be selected or obtained dynamically.
@@ -387,11 +537,14 @@ pub mod parameters {
     impl Parameter for PredefinedNetworkPanId {
         const PARAMETER_ID: u8 = 0x15;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            match frame.get_u8() {
-                x if x == Self::NotPredefined as u8 => Self::NotPredefined,
-                x if x == Self::Predefined as u8 => Self::Predefined,
-                x => panic!("Unexpected PANID mode: {:?}", x),
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            match frame.try_get_u8()? {
+                x if x == Self::NotPredefined as u8 => Ok(Self::NotPredefined),
+                x if x == Self::Predefined as u8 => Ok(Self::Predefined),
+                value => Err(ProtocolError::UnknownParameterValue {
+                    parameter_id: Self::PARAMETER_ID,
+                    value,
+                }),
             }
         }
 
@@ -421,15 +574,15 @@ pub mod parameters {
     impl Parameter for NetworkKey {
         const PARAMETER_ID: u8 = 0x18;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
             // in testing, I found that if the frame is empty, then perhaps
             // there is no network key?
             if frame.has_remaining() {
                 let mut buf = [0u8; 16];
-                frame.copy_to_slice(&mut buf);
-                Self::Set(buf)
+                frame.try_copy_to_slice(&mut buf)?;
+                Ok(Self::Set(buf))
             } else {
-                Self::Unset
+                Ok(Self::Unset)
             }
         }
 
@@ -441,7 +594,47 @@ pub mod parameters {
         }
     }
 
-    // LinkKey - skipped because it doesnt look to be useful?
+    /// The trust center link key of a single device, keyed by its IEEE address. Used to let
+    /// devices join with a key derived from their install code, see [`crate::install_code`].
+    #[derive(Clone, PartialEq, Eq)]
+    pub struct LinkKey {
+        pub ieee_address: IEEEAddress,
+        pub key: [u8; 16],
+    }
+
+    impl Sealed for LinkKey {}
+    impl Parameter for LinkKey {
+        const PARAMETER_ID: u8 = 0x19;
+
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            let ieee_address = frame.try_get_u64_le()?;
+            let mut key = [0; 16];
+            frame.try_copy_to_slice(&mut key)?;
+            Ok(Self { ieee_address, key })
+        }
+
+        fn write_frame(&self, payload: &mut bytes::BytesMut) {
+            payload.put_u64_le(self.ieee_address);
+            payload.put_slice(&self.key);
+        }
+    }
+    impl IndexedParameter for LinkKey {
+        type Index = IEEEAddress;
+
+        fn write_index(index: Self::Index, payload: &mut bytes::BytesMut) {
+            payload.put_u64_le(index);
+        }
+    }
+
+    /// Keeps the key itself out of logs.
+    impl std::fmt::Debug for LinkKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("LinkKey")
+                .field("ieee_address", &format_args!("{:#018x}", self.ieee_address))
+                .field("key", &"..")
+                .finish()
+        }
+    }
 
     #[derive(Debug)]
     pub struct CurrentChannel(u8);
@@ -450,8 +643,8 @@ pub mod parameters {
     impl Parameter for CurrentChannel {
         const PARAMETER_ID: u8 = 0x1C;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u8())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u8()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -459,6 +652,12 @@ pub mod parameters {
         }
     }
 
+    impl From<u8> for CurrentChannel {
+        fn from(v: u8) -> Self {
+            Self(v)
+        }
+    }
+
     impl std::ops::Deref for CurrentChannel {
         type Target = u8;
 
@@ -474,8 +673,8 @@ pub mod parameters {
     impl Parameter for ProtocolVersion {
         const PARAMETER_ID: u8 = 0x22;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u16_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u16_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -483,6 +682,12 @@ pub mod parameters {
         }
     }
 
+    impl From<u16> for ProtocolVersion {
+        fn from(v: u16) -> Self {
+            Self(v)
+        }
+    }
+
     impl std::ops::Deref for ProtocolVersion {
         type Target = u16;
 
@@ -497,8 +702,8 @@ pub mod parameters {
     impl Parameter for NetworkUpdateId {
         const PARAMETER_ID: u8 = 0x24;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u8())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u8()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -525,13 +730,16 @@ pub mod parameters {
     impl Sealed for WatchdogTtl {}
     impl Parameter for WatchdogTtl {
         const PARAMETER_ID: u8 = 0x26;
+        // As zigpy-deconz has it (`PROTO_VER_WATCHDOG` in `zigpy_deconz/api.py`), which only
+        // refreshes the watchdog on devices reporting at least this protocol version.
+        const MIN_PROTOCOL_VERSION: Option<u16> = Some(0x0108);
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(Duration::from_secs(if frame.len() < 1 {
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(Duration::from_secs(if frame.is_empty() {
                 0
             } else {
-                frame.get_u32_le().into()
-            }))
+                frame.try_get_u32_le()?.into()
+            })))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -566,8 +774,8 @@ pub mod parameters {
     impl Parameter for NetworkFrameCounter {
         const PARAMETER_ID: u8 = 0x27;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u32_le())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u32_le()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -595,8 +803,8 @@ pub mod parameters {
     impl Parameter for PermitJoin {
         const PARAMETER_ID: u8 = 0x21;
 
-        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Self {
-            Self(frame.get_u8())
+        fn from_frame(mut frame: DeconzFrame<Bytes>) -> Result<Self, ProtocolError> {
+            Ok(Self(frame.try_get_u8()?))
         }
 
         fn write_frame(&self, payload: &mut bytes::BytesMut) {
@@ -620,8 +828,10 @@ pub type ReadChannelMask = ReadParameter<parameters::ChannelMask>;
 pub type ReadAPSExtendedPanId = ReadParameter<parameters::APSExtendedPanId>;
 pub type ReadTrustCenterAddress = ReadParameter<parameters::TrustCenterAddress>;
 pub type ReadSecurityMode = ReadParameter<parameters::SecurityMode>;
+pub type ReadEndpoint = ReadParameter<parameters::Endpoint>;
 pub type ReadPredefinedNetworkPanId = ReadParameter<parameters::PredefinedNetworkPanId>;
 pub type ReadNetworkKey = ReadParameter<parameters::NetworkKey>;
+pub type ReadLinkKey = ReadParameter<parameters::LinkKey>;
 pub type ReadCurrentChannel = ReadParameter<parameters::CurrentChannel>;
 pub type ReadProtocolVersion = ReadParameter<parameters::ProtocolVersion>;
 pub type ReadNetworkUpdateId = ReadParameter<parameters::NetworkUpdateId>;
@@ -629,6 +839,7 @@ pub type ReadWatchdogTtl = ReadParameter<parameters::WatchdogTtl>;
 pub type ReadNetworkFrameCounter = ReadParameter<parameters::NetworkFrameCounter>;
 pub type ReadPermitJoin = ReadParameter<parameters::PermitJoin>;
 
+pub type WriteMacAddress = WriteParameter<parameters::MacAddress>;
 pub type WriteNetworkAddress = WriteParameter<parameters::NetworkAddress>;
 pub type WriteNetworkPanId = WriteParameter<parameters::NetworkPanId>;
 pub type WriteAPSDesignatedCoordinator = WriteParameter<parameters::APSDesignatedCoordinator>;
@@ -636,21 +847,47 @@ pub type WriteChannelMask = WriteParameter<parameters::ChannelMask>;
 pub type WriteAPSExtendedPanId = WriteParameter<parameters::APSExtendedPanId>;
 pub type WriteTrustCenterAddress = WriteParameter<parameters::TrustCenterAddress>;
 pub type WriteSecurityMode = WriteParameter<parameters::SecurityMode>;
+pub type WriteEndpoint = WriteParameter<parameters::Endpoint>;
 pub type WritePredefinedNetworkPanId = WriteParameter<parameters::PredefinedNetworkPanId>;
 pub type WriteNetworkKey = WriteParameter<parameters::NetworkKey>;
-// pub type WriteLinkKey = WriteParameter<LinkKey>;
+pub type WriteLinkKey = WriteParameter<parameters::LinkKey>;
 pub type WriteNetworkUpdateId = WriteParameter<parameters::NetworkUpdateId>;
 pub type WriteWatchdogTtl = WriteParameter<parameters::WatchdogTtl>;
 pub type WriteNetworkFrameCounter = WriteParameter<parameters::NetworkFrameCounter>;
 pub type WritePermitJoin = WriteParameter<parameters::PermitJoin>;
 
+/// Checks that a response carries the parameter we asked the device for.
+fn expect_parameter_id<T: Parameter>(parameter_id: u8) -> Result<(), ProtocolError> {
+    if parameter_id != T::PARAMETER_ID {
+        return Err(ProtocolError::UnexpectedParameter {
+            expected: T::PARAMETER_ID,
+            actual: parameter_id,
+        });
+    }
+    Ok(())
+}
+
 pub struct ReadParameter<T: Parameter> {
+    index: Option<Bytes>,
     _phantom: PhantomData<T>,
 }
 
 impl<T: Parameter> ReadParameter<T> {
     pub fn new() -> Self {
         Self {
+            index: None,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: IndexedParameter> ReadParameter<T> {
+    /// Reads the slot at the given index.
+    pub fn at_index(index: T::Index) -> Self {
+        let mut payload = BytesMut::new();
+        T::write_index(index, &mut payload);
+        Self {
+            index: Some(payload.freeze()),
             _phantom: PhantomData,
         }
     }
@@ -663,6 +900,7 @@ impl<T: Parameter> Default for ReadParameter<T> {
 }
 
 pub struct ReadParameterRequest<T: Parameter> {
+    index: Option<Bytes>,
     _phantom: PhantomData<T>,
 }
 
@@ -675,6 +913,7 @@ impl<T: Parameter> Debug for ReadParameterRequest<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ReadParamaterRequest")
             .field("paramater", &type_name::<T>())
+            .field("index", &self.index)
             .finish()
     }
 }
@@ -685,6 +924,7 @@ impl<T: Parameter> DeconzCommand for ReadParameter<T> {
 
     fn into_request(self) -> Self::Request {
         Self::Request {
+            index: self.index,
             _phantom: PhantomData,
         }
     }
@@ -695,25 +935,49 @@ impl<T: Parameter> DeconzCommandRequest for ReadParameterRequest<T> {
         CommandId::ReadParameter
     }
 
+    fn is_idempotent(&self) -> bool {
+        true
+    }
+
+    fn min_protocol_version(&self) -> Option<u16> {
+        T::MIN_PROTOCOL_VERSION
+    }
+
     fn payload_data(&self) -> Option<BytesMut> {
         let mut payload = BytesMut::new();
         payload.put_u8(T::PARAMETER_ID);
+        if let Some(index) = &self.index {
+            payload.put_slice(index);
+        }
         Some(payload)
     }
 }
 
 impl<T: Parameter> DeconzCommandResponse for ReadParameterResponse<T> {
-    fn from_frame(mut frame: DeconzFrame<bytes::Bytes>) -> (Self, Option<DeviceState>) {
-        // todo: how do we handle this better? what if it doesn't match? should we crash?
-        let _payload_length = frame.get_u16_le();
-        let paramater_id = frame.get_u8();
-        assert_eq!(paramater_id, T::PARAMETER_ID);
-        (
+    fn from_frame(
+        mut frame: DeconzFrame<bytes::Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        expect_parameter_id::<T>(frame.try_get_u8()?)?;
+        Ok((
             Self {
-                value: T::from_frame(frame),
+                value: T::from_frame(frame)?,
             },
             None,
-        )
+        ))
+    }
+}
+
+impl<T: Parameter> DeconzCommandResponseEncoder for ReadParameterResponse<T> {
+    fn command_id(&self) -> CommandId {
+        CommandId::ReadParameter
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(T::PARAMETER_ID);
+        self.value.write_frame(&mut payload);
+        Some(payload)
     }
 }
 
@@ -766,6 +1030,10 @@ impl<T: Parameter> DeconzCommandRequest for WriteParameterRequest<T> {
         CommandId::WriteParameter
     }
 
+    fn min_protocol_version(&self) -> Option<u16> {
+        T::MIN_PROTOCOL_VERSION
+    }
+
     fn payload_data(&self) -> Option<BytesMut> {
         let mut payload = BytesMut::new();
         payload.put_u8(T::PARAMETER_ID);
@@ -775,16 +1043,37 @@ impl<T: Parameter> DeconzCommandRequest for WriteParameterRequest<T> {
 }
 
 impl<T: Parameter> DeconzCommandResponse for WriteParameterResponse<T> {
-    fn from_frame(mut frame: DeconzFrame<bytes::Bytes>) -> (Self, Option<DeviceState>) {
-        let _payload_length = frame.get_u16_le();
-        let paramater_id = frame.get_u8();
-        assert_eq!(paramater_id, T::PARAMETER_ID);
-        (
+    fn from_frame(
+        mut frame: DeconzFrame<bytes::Bytes>,
+    ) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        let _payload_length = frame.try_get_u16_le()?;
+        expect_parameter_id::<T>(frame.try_get_u8()?)?;
+        Ok((
             Self {
                 _phantom: Default::default(),
             },
             None,
-        )
+        ))
+    }
+}
+
+impl<T: Parameter> DeconzCommandResponseEncoder for WriteParameterResponse<T> {
+    fn command_id(&self) -> CommandId {
+        CommandId::WriteParameter
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        let mut payload = BytesMut::new();
+        payload.put_u8(T::PARAMETER_ID);
+        Some(payload)
+    }
+}
+
+impl<T: Parameter> Default for WriteParameterResponse<T> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
     }
 }
 
@@ -793,3 +1082,85 @@ impl<T: Parameter + Default> WriteParameterResponse<T> {
         T::default()
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{frame::ProtocolError, protocol::StatusCode};
+
+    fn read_response(payload: &[u8]) -> DeconzFrame<Bytes> {
+        DeconzFrame::incoming(CommandId::ReadParameter, StatusCode::Success, payload)
+    }
+
+    #[test]
+    pub fn test_read_parameter_response() {
+        let frame = read_response(&[0x03, 0x00, 0x05, 0x34, 0x12]);
+        let (response, _) = ReadParameterResponse::<parameters::NetworkPanId>::from_frame(frame)
+            .expect("valid response");
+        assert_eq!(*response.value, 0x1234);
+    }
+
+    #[test]
+    pub fn test_read_parameter_response_errors() {
+        let truncated = read_response(&[0x03, 0x00, 0x05, 0x34]);
+        assert!(matches!(
+            ReadParameterResponse::<parameters::NetworkPanId>::from_frame(truncated),
+            Err(ProtocolError::TruncatedFrame {
+                needed: 2,
+                remaining: 1
+            })
+        ));
+
+        let wrong_parameter = read_response(&[0x03, 0x00, 0x07, 0x34, 0x12]);
+        assert!(matches!(
+            ReadParameterResponse::<parameters::NetworkPanId>::from_frame(wrong_parameter),
+            Err(ProtocolError::UnexpectedParameter {
+                expected: 0x05,
+                actual: 0x07
+            })
+        ));
+
+        let unknown_value = read_response(&[0x02, 0x00, 0x10, 0x09]);
+        assert!(matches!(
+            ReadParameterResponse::<parameters::SecurityMode>::from_frame(unknown_value),
+            Err(ProtocolError::UnknownParameterValue {
+                parameter_id: 0x10,
+                value: 0x09
+            })
+        ));
+    }
+
+    #[test]
+    pub fn test_endpoint() {
+        let endpoint = parameters::Endpoint {
+            index: 1,
+            descriptor: parameters::SimpleDescriptor {
+                endpoint: 0x01,
+                profile_id: 0x0104,
+                device_id: 0x0005,
+                device_version: 1,
+                in_clusters: vec![0x0000, 0x0006],
+                out_clusters: vec![0x0019],
+            },
+        };
+        let request = WriteEndpoint::new(endpoint.clone()).into_request();
+        let payload = request.payload_data().unwrap();
+        assert_eq!(
+            &payload[..],
+            &[
+                0x13, 0x01, 0x01, 0x04, 0x01, 0x05, 0x00, 0x01, 0x02, 0x00, 0x00, 0x06, 0x00, 0x01,
+                0x19, 0x00
+            ]
+        );
+
+        let read_request = ReadEndpoint::at_index(1).into_request();
+        assert_eq!(&read_request.payload_data().unwrap()[..], &[0x13, 0x01]);
+
+        let mut response = vec![payload.len() as u8, 0x00];
+        response.extend_from_slice(&payload);
+        let (response, _) =
+            ReadParameterResponse::<parameters::Endpoint>::from_frame(read_response(&response))
+                .expect("valid response");
+        assert_eq!(response.value, endpoint);
+    }
+}
@@ -0,0 +1,106 @@
+//! An escape hatch for commands this crate doesn't implement (yet), like undocumented ones or
+//! those of newer firmware. Paired with
+//! [`DeconzClientHandle::subscribe_frames`](crate::DeconzClientHandle::subscribe_frames), this is
+//! enough to experiment with them.
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{frame::ProtocolError, DeconzFrame};
+
+use super::{
+    device::DeviceState, CommandId, DeconzCommand, DeconzCommandRequest, DeconzCommandResponse,
+};
+
+/// A command sent as-is, resolving with the response frame as-is.
+#[derive(Debug, Clone)]
+pub struct RawCommand {
+    pub command_id: u8,
+    /// Everything after the frame header. The payload length is prepended by the client, for
+    /// all commands but the few known to go without.
+    pub payload: Bytes,
+}
+
+impl RawCommand {
+    pub fn new(command_id: u8, payload: impl Into<Bytes>) -> Self {
+        Self {
+            command_id,
+            payload: payload.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RawCommandRequest(RawCommand);
+
+impl DeconzCommand for RawCommand {
+    type Request = RawCommandRequest;
+    /// The response is matched up by command id and sequence number, the payload is left to the
+    /// caller. It still starts with the payload length, where there is one.
+    type Response = DeconzFrame<Bytes>;
+
+    fn into_request(self) -> Self::Request {
+        RawCommandRequest(self)
+    }
+}
+
+impl DeconzCommandRequest for RawCommandRequest {
+    fn command_id(&self) -> CommandId {
+        self.0.command_id.into()
+    }
+
+    fn payload_data(&self) -> Option<BytesMut> {
+        Some(BytesMut::from(&self.0.payload[..]))
+    }
+}
+
+impl DeconzCommandResponse for DeconzFrame<Bytes> {
+    fn from_frame(frame: DeconzFrame<Bytes>) -> Result<(Self, Option<DeviceState>), ProtocolError> {
+        Ok((frame, None))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{
+        emulator::DeconzEmulator, protocol::StatusCode, DeconzClient, DeconzClientConfig,
+        FrameDirection, HandleError,
+    };
+
+    #[tokio::test]
+    pub async fn test_raw_command() {
+        let emulator = DeconzEmulator::new();
+        let (_task, mut handle) =
+            DeconzClient::new(DeconzClientConfig::new(emulator.transport())).start();
+        let mut frames = handle.subscribe_frames().await.unwrap();
+
+        // The device state request, which goes without a payload length.
+        let response = handle
+            .send_command(RawCommand::new(0x07, vec![0, 0, 0]))
+            .await
+            .unwrap();
+        assert_eq!(response.command_id(), CommandId::DeviceState);
+        assert_eq!(response.len(), 1);
+
+        // Ids this crate doesn't know make it to the device and back all the same.
+        let result = handle.send_command(RawCommand::new(0x30, vec![0x01])).await;
+        assert!(matches!(
+            result,
+            Err(HandleError::Status(StatusCode::Unsupported))
+        ));
+
+        let mut tapped = Vec::new();
+        while let Ok(frame) = frames.try_recv() {
+            if frame.frame.command_id() == CommandId::Unknown(0x30) {
+                tapped.push((frame.direction, frame.frame.to_vec()));
+            }
+        }
+        assert_eq!(
+            tapped,
+            [
+                (FrameDirection::Outgoing, vec![0x01, 0x00, 0x01]),
+                (FrameDirection::Incoming, vec![0x00, 0x00]),
+            ]
+        );
+    }
+}
@@ -1,11 +1,15 @@
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
-use slip_codec::{SlipError, tokio::SlipCodec};
+use slip_codec::{tokio::SlipCodec, SlipError};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
 use super::frame::{DeconzCrc, DeconzFrame, OutgoingPacket, ProtocolError};
+use crate::{capture::Capture, FrameDirection};
+
+/// The SLIP frame delimiter.
+const SLIP_END: u8 = 0xC0;
 
 #[derive(Error, Debug)]
 pub enum DeconzStreamError {
@@ -17,6 +21,22 @@ pub enum DeconzStreamError {
     Protocol(#[from] ProtocolError),
 }
 
+impl DeconzStreamError {
+    /// Returns `true` if the underlying stream failed, rather than a single frame being invalid.
+    pub fn is_io(&self) -> bool {
+        matches!(self, Self::SlipCodec(SlipError::ReadError(_)))
+    }
+}
+
+/// Counters describing incoming frames that never made it out of [`DeconzStream::next_frame`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeconzStreamStats {
+    /// Frames that were received in full, but failed CRC validation.
+    pub corrupted_frames: u64,
+    /// Frames that were discarded for any reason, including corrupted frames.
+    pub dropped_frames: u64,
+}
+
 /// Reads incoming bytes and returns a validated DeconzFrame
 fn read_frame(mut bytes: BytesMut) -> Result<DeconzFrame<Bytes>, DeconzStreamError> {
     let bytes_len = bytes.len();
@@ -31,28 +51,127 @@ fn read_frame(mut bytes: BytesMut) -> Result<DeconzFrame<Bytes>, DeconzStreamErr
     Ok(crc.verify_frame(bytes)?)
 }
 
+/// A decoded SLIP packet, or the reason the packet was thrown away.
+pub(crate) enum SlipPacket {
+    Packet(BytesMut),
+    Discarded(SlipError),
+}
+
+/// Wraps [`SlipCodec`] so that malformed SLIP data doesn't put the underlying [`Framed`] into its
+/// errored state (which yields a spurious end-of-stream).
+///
+/// On a framing error, the rest of the broken packet is skipped up to the next END byte and the
+/// inner codec is replaced, as it otherwise keeps the partially decoded bytes around.
+pub(crate) struct ResyncingSlipCodec {
+    inner: SlipCodec,
+    resyncing: bool,
+}
+
+impl ResyncingSlipCodec {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: SlipCodec::new(),
+            resyncing: false,
+        }
+    }
+}
+
+impl Decoder for ResyncingSlipCodec {
+    type Item = SlipPacket;
+    type Error = SlipError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.resyncing {
+            match src.iter().position(|b| *b == SLIP_END) {
+                Some(end) => {
+                    src.advance(end);
+                    self.resyncing = false;
+                }
+                None => {
+                    src.clear();
+                    return Ok(None);
+                }
+            }
+        }
+
+        match self.inner.decode(src) {
+            Ok(packet) => Ok(packet.map(SlipPacket::Packet)),
+            Err(SlipError::ReadError(e)) => Err(SlipError::ReadError(e)),
+            Err(e) => {
+                self.inner = SlipCodec::new();
+                self.resyncing = true;
+                Ok(Some(SlipPacket::Discarded(e)))
+            }
+        }
+    }
+}
+
+impl Encoder<Bytes> for ResyncingSlipCodec {
+    type Error = SlipError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.inner.encode(item, dst)
+    }
+}
+
 /// A wrapper for an AsyncRead + AsyncWrite that allows for reading and writing deCONZ protocol packets.
 /// SLIP encapsulation and CRC generation/validation is built-in, so just send your structured payloads.
 pub struct DeconzStream<S: AsyncRead + AsyncWrite> {
-    slip_stream: Framed<S, SlipCodec>,
+    slip_stream: Framed<S, ResyncingSlipCodec>,
+    stats: DeconzStreamStats,
+    capture: Option<Capture>,
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> DeconzStream<S> {
     /// Creates a new deCONZ stream from anything that implements AsyncRead + AsyncWrite. For example, a tokio::fs::File.
     pub fn new(stream: S) -> Self {
-        let slip_stream = tokio_util::codec::Framed::new(stream, slip_codec::tokio::SlipCodec::new());
-        Self { slip_stream }
+        let slip_stream = Framed::new(stream, ResyncingSlipCodec::new());
+        Self {
+            slip_stream,
+            stats: Default::default(),
+            capture: None,
+        }
+    }
+
+    /// Records every frame read from or written to the stream from now on, see [`Capture`].
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
     }
 
     /// Reads until the next frame is received, where it will validate and yield a new DeconzFrame.
     /// Returns None if the underlying stream has ended.
+    ///
+    /// Invalid frames are yielded as errors and counted in [`DeconzStream::stats`]; the stream stays
+    /// usable afterwards.
     pub async fn next_frame(&mut self) -> Option<Result<DeconzFrame<Bytes>, DeconzStreamError>> {
-        let a = self.slip_stream.next().await;
-        match a {
-            Some(Ok(bytes)) => Some(read_frame(bytes)),
-            Some(Err(e)) => Some(Err(DeconzStreamError::SlipCodec(e))),
-            None => None,
+        let result = match self.slip_stream.next().await? {
+            Ok(SlipPacket::Packet(bytes)) => match &self.capture {
+                Some(capture) => {
+                    let packet = bytes.clone().freeze();
+                    let result = read_frame(bytes);
+                    capture.record(
+                        FrameDirection::Incoming,
+                        packet,
+                        result.as_ref().ok().cloned(),
+                    );
+                    result
+                }
+                None => read_frame(bytes),
+            },
+            Ok(SlipPacket::Discarded(e)) | Err(e) => Err(DeconzStreamError::SlipCodec(e)),
+        };
+
+        if let Err(e) = &result {
+            if matches!(
+                e,
+                DeconzStreamError::Protocol(ProtocolError::CrcMismatch { .. })
+            ) {
+                self.stats.corrupted_frames += 1;
+            }
+            self.stats.dropped_frames += 1;
         }
+
+        Some(result)
     }
 
     /// Writes a frame to the stream, encoding it on the way out.
@@ -60,11 +179,82 @@ impl<S: AsyncRead + AsyncWrite + Unpin> DeconzStream<S> {
         &mut self,
         payload: DeconzFrame<OutgoingPacket>,
     ) -> Result<(), DeconzStreamError> {
+        let frame = self.capture.as_ref().map(|_| payload.to_parsed());
+        let packet = payload.encode();
+        if let Some(capture) = &self.capture {
+            capture.record(FrameDirection::Outgoing, packet.clone(), frame);
+        }
+
         self.slip_stream
-            .send(payload.encode())
+            .send(packet)
             .await
             .map_err(DeconzStreamError::SlipCodec)?;
 
         Ok(())
     }
+
+    /// Consumes the stream, returning the underlying AsyncRead + AsyncWrite.
+    /// Any buffered, not yet decoded bytes are lost.
+    pub fn into_inner(self) -> S {
+        self.slip_stream.into_inner()
+    }
+
+    /// Returns the counters for frames that were dropped on the way in.
+    pub fn stats(&self) -> DeconzStreamStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::protocol::CommandId;
+
+    fn encoded_device_state_changed() -> Vec<u8> {
+        let mut packet = vec![u8::from(CommandId::DeviceStateChanged), 1, 0, 6, 0, 0x02];
+        packet.extend_from_slice(&DeconzCrc::generate(&packet).as_slice());
+        packet
+    }
+
+    #[tokio::test]
+    pub async fn test_resync_after_bad_frames() {
+        let (mut device, host) = tokio::io::duplex(256);
+        let mut stream = DeconzStream::new(host);
+
+        let mut corrupted = encoded_device_state_changed();
+        corrupted[5] ^= 0xFF;
+
+        let mut wire = vec![SLIP_END, 0x01, 0xDB, 0x02, 0x03, SLIP_END];
+        wire.extend_from_slice(&corrupted);
+        wire.push(SLIP_END);
+        wire.extend_from_slice(&encoded_device_state_changed());
+        wire.push(SLIP_END);
+        device.write_all(&wire).await.unwrap();
+
+        assert!(matches!(
+            stream.next_frame().await,
+            Some(Err(DeconzStreamError::SlipCodec(SlipError::FramingError)))
+        ));
+        assert!(matches!(
+            stream.next_frame().await,
+            Some(Err(DeconzStreamError::Protocol(
+                ProtocolError::CrcMismatch { .. }
+            )))
+        ));
+        let frame = stream.next_frame().await.unwrap().unwrap();
+        assert_eq!(frame.command_id(), CommandId::DeviceStateChanged);
+        assert_eq!(&frame[..], &[0x02]);
+        assert_eq!(
+            stream.stats(),
+            DeconzStreamStats {
+                corrupted_frames: 1,
+                dropped_frames: 2,
+            }
+        );
+
+        drop(device);
+        assert!(stream.next_frame().await.is_none());
+    }
 }